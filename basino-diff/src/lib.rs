@@ -0,0 +1,488 @@
+//! Compare the basino assembly routines against the pure-Rust versions
+//!
+//! rust-basino has two implementations of the stack and queue
+//! routines: the assembly in libbasino, and the Rust versions in
+//! rust_basino::portable that host builds use.  This crate runs the
+//! same scripts of operations against both and reports the first step
+//! where they differ.  The assembly runs in the basino-emu emulator.
+//! The Rust versions are the reference.
+//!
+//! After every step the harness compares the return codes, the popped
+//! values, the pointer fields of both structs, what the getter
+//! routines return and the contents of both arrays.  Pointers are
+//! compared as offsets from the start of their array, since the
+//! arrays are at different addresses on the two sides.
+#![warn(missing_docs)]
+
+use std::{fmt, path::Path};
+
+use basino_emu::{Cpu, Program};
+use rust_basino::{portable::*, testing::Rng, Queue, QueueObj, Stack};
+
+/// The length of the stack and queue arrays
+pub const ARRAY_LEN: usize = 16;
+
+/// Where the assembly side puts the stack struct
+const STACK: u16 = 0x0100;
+/// Where the assembly side puts the queue struct
+const QUEUE: u16 = 0x0110;
+/// Where routines that return a status through a pointer write it
+const STATUS: u16 = 0x0120;
+/// Where the assembly side puts the stack array
+const STACK_ARRAY: u16 = 0x0200;
+/// Where the assembly side puts the queue array
+const QUEUE_ARRAY: u16 = 0x0300;
+
+/// The number of pointer fields in the stack struct
+const STACK_FIELDS: u16 = 4;
+/// The number of pointer fields in the queue struct
+const QUEUE_FIELDS: u16 = 6;
+
+/// The stack getter routines, in the order the outcome lists them
+const STACK_GETTERS: [&str; 3] = [
+    "basino_get_basino_stack_top_sentinel",
+    "basino_get_basino_stack_bottom",
+    "basino_get_basino_stack_top",
+];
+
+/// The queue info routines, in the order the outcome lists them
+const QUEUE_GETTERS: [&str; 5] = [
+    "basino_queue_get_queue_start",
+    "basino_queue_get_queue_end",
+    "basino_queue_get_head",
+    "basino_queue_get_last_head",
+    "basino_queue_get_tail",
+];
+
+/// An operation in a script
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Initialize the stack on the first len bytes of its array, the
+    /// way StackImpl::new does
+    StackInit(u16),
+    /// Push a value onto the stack
+    Push(u8),
+    /// Pop a value from the stack
+    Pop,
+    /// Initialize the queue on the first len bytes of its array, the
+    /// way Queue::init does.  len must be between one and ARRAY_LEN,
+    /// init doesn't check the end and the queue would run off the
+    /// array.
+    QueueInit(u16),
+    /// Put a value into the queue
+    Put(u8),
+    /// Get a value from the queue
+    Get,
+    /// Call every stack and queue routine that checks for null with a
+    /// null pointer
+    Null,
+}
+
+/// What a step returned and the state after it
+#[derive(Debug, PartialEq, Eq)]
+pub struct Outcome {
+    /// The codes the routines returned, or the status they wrote
+    /// through the result pointer, followed by the value pop or get
+    /// returned if they succeeded
+    pub results: Vec<u8>,
+    /// The stack fields then the queue fields, as offsets from their
+    /// array, None if the field is null
+    pub fields: Vec<Option<isize>>,
+    /// What the stack getters and the queue info routines return, and
+    /// the status the queue info routines write
+    pub getters: Vec<(Option<isize>, u8)>,
+    /// The stack array then the queue array
+    pub arrays: Vec<u8>,
+}
+
+/// Errors that can occur running a script
+#[derive(Debug)]
+pub enum Error {
+    /// The emulator failed running a step
+    Emulator {
+        /// The index of the step in the script
+        step: usize,
+        /// The operation
+        op: Op,
+        /// What went wrong
+        error: basino_emu::Error,
+    },
+    /// The assembly and the reference differ after a step
+    Mismatch {
+        /// The index of the step in the script
+        step: usize,
+        /// The operation
+        op: Op,
+        /// What the assembly did
+        asm: Box<Outcome>,
+        /// What the reference did
+        reference: Box<Outcome>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Emulator { step, op, error } => {
+                write!(f, "step {}, {:?}: the emulator failed: {}", step, op, error)
+            }
+            Error::Mismatch {
+                step,
+                op,
+                asm,
+                reference,
+            } => write!(
+                f,
+                "step {}, {:?}: the assembly differs from the reference\n\
+                 assembly:  {:?}\n\
+                 reference: {:?}",
+                step, op, asm, reference
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A stack and queue implementation the scripts run against
+pub trait Backend {
+    /// Run an operation and return the results
+    fn run(&mut self, op: Op) -> Result<Vec<u8>, basino_emu::Error>;
+
+    /// Get the state of the stack and queue after a step
+    fn outcome(&mut self, results: Vec<u8>) -> Result<Outcome, basino_emu::Error>;
+}
+
+/// Get the offset of a pointer from the start of its array
+fn offset(address: usize, array: usize) -> Option<isize> {
+    if address == 0 {
+        None
+    } else {
+        Some(address as isize - array as isize)
+    }
+}
+
+/// Get the results of pop or get from the value and status
+fn popped(value: u8, status: u8) -> Vec<u8> {
+    match status {
+        0 => vec![0, value],
+        _ => vec![status],
+    }
+}
+
+/// The assembly routines running in the emulator
+pub struct Asm {
+    program: Program,
+    cpu: Cpu,
+}
+
+impl Asm {
+    /// Load libbasino into a new emulated ATmega328p
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, basino_emu::Error> {
+        let program = Program::load_file(path)?;
+        let cpu = Cpu::with_program(&program)?;
+        Ok(Asm { program, cpu })
+    }
+
+    /// Call a routine
+    fn call(&mut self, name: &str, args: &[u16]) -> Result<u16, basino_emu::Error> {
+        let address = self.program.symbol(name)?;
+        self.cpu.call(address, args)
+    }
+
+    /// Call a routine that returns an 8-bit code
+    fn call_u8(&mut self, name: &str, args: &[u16]) -> Result<u8, basino_emu::Error> {
+        Ok(self.call(name, args)? as u8)
+    }
+
+    /// Call a routine that writes a status to STATUS
+    ///
+    /// Returns the value and the status.
+    fn call_with_status(
+        &mut self,
+        name: &str,
+        object: u16,
+    ) -> Result<(u16, u8), basino_emu::Error> {
+        self.cpu.write(STATUS, 0xFF)?;
+        let value = self.call(name, &[object, STATUS])?;
+        Ok((value, self.cpu.read(STATUS)?))
+    }
+
+    /// Get the results of pop or get
+    fn call_popped(&mut self, name: &str, object: u16) -> Result<Vec<u8>, basino_emu::Error> {
+        let (value, status) = self.call_with_status(name, object)?;
+        Ok(popped(value as u8, status))
+    }
+}
+
+impl Backend for Asm {
+    fn run(&mut self, op: Op) -> Result<Vec<u8>, basino_emu::Error> {
+        Ok(match op {
+            Op::StackInit(len) => {
+                let top = STACK_ARRAY.wrapping_add(len).wrapping_sub(1);
+                vec![self.call_u8("basino_stack_init", &[STACK, top, STACK_ARRAY])?]
+            }
+            Op::Push(value) => vec![self.call_u8("basino_stack_push", &[STACK, value as u16])?],
+            Op::Pop => self.call_popped("basino_stack_pop", STACK)?,
+            Op::QueueInit(len) => {
+                let end = QUEUE_ARRAY + len - 1;
+                vec![self.call_u8("basino_queue_init", &[QUEUE, QUEUE_ARRAY, end])?]
+            }
+            Op::Put(value) => vec![self.call_u8("basino_queue_put", &[QUEUE, value as u16])?],
+            Op::Get => self.call_popped("basino_queue_get", QUEUE)?,
+            Op::Null => {
+                let top = STACK_ARRAY + ARRAY_LEN as u16 - 1;
+                let end = QUEUE_ARRAY + ARRAY_LEN as u16 - 1;
+                let mut results = vec![
+                    self.call_u8("basino_stack_init", &[0, top, STACK_ARRAY])?,
+                    self.call_u8("basino_stack_push", &[0, 1])?,
+                    self.call_with_status("basino_stack_pop", 0)?.1,
+                    self.call_u8("basino_queue_init", &[0, QUEUE_ARRAY, end])?,
+                    self.call_u8("basino_queue_put", &[0, 1])?,
+                    self.call_with_status("basino_queue_get", 0)?.1,
+                ];
+                for name in QUEUE_GETTERS {
+                    let (value, status) = self.call_with_status(name, 0)?;
+                    results.push(status);
+                    results.push((value == 0) as u8);
+                }
+                results
+            }
+        })
+    }
+
+    fn outcome(&mut self, results: Vec<u8>) -> Result<Outcome, basino_emu::Error> {
+        let mut fields = Vec::new();
+        for i in 0..STACK_FIELDS {
+            let address = self.cpu.read_u16(STACK + 2 * i)?;
+            fields.push(offset(address as usize, STACK_ARRAY as usize));
+        }
+        for i in 0..QUEUE_FIELDS {
+            let address = self.cpu.read_u16(QUEUE + 2 * i)?;
+            fields.push(offset(address as usize, QUEUE_ARRAY as usize));
+        }
+
+        let mut getters = Vec::new();
+        for name in STACK_GETTERS {
+            let address = self.call(name, &[STACK])?;
+            getters.push((offset(address as usize, STACK_ARRAY as usize), 0));
+        }
+        for name in QUEUE_GETTERS {
+            let (address, status) = self.call_with_status(name, QUEUE)?;
+            getters.push((offset(address as usize, QUEUE_ARRAY as usize), status));
+        }
+
+        let mut arrays = self.cpu.read_bytes(STACK_ARRAY, ARRAY_LEN)?;
+        arrays.extend(self.cpu.read_bytes(QUEUE_ARRAY, ARRAY_LEN)?);
+
+        Ok(Outcome {
+            results,
+            fields,
+            getters,
+            arrays,
+        })
+    }
+}
+
+/// The Rust versions of the routines, the reference
+pub struct Portable {
+    stack: Stack<'static>,
+    queue: QueueObj<'static>,
+    // The arrays are boxed so the pointers in the structs stay valid
+    // when the backend moves
+    stack_array: Box<[u8; ARRAY_LEN]>,
+    queue_array: Box<[u8; ARRAY_LEN]>,
+}
+
+impl Portable {
+    /// Create a backend with an uninitialized stack and queue and
+    /// zeroed arrays
+    pub fn new() -> Self {
+        Portable {
+            // A zeroed stack has null pointers, like the uninitialized
+            // stack struct in the emulator's memory
+            stack: unsafe { core::mem::zeroed() },
+            queue: Queue::uninit().queue,
+            stack_array: Box::new([0; ARRAY_LEN]),
+            queue_array: Box::new([0; ARRAY_LEN]),
+        }
+    }
+}
+
+impl Default for Portable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for Portable {
+    fn run(&mut self, op: Op) -> Result<Vec<u8>, basino_emu::Error> {
+        let stack_bottom = self.stack_array.as_mut_ptr();
+        let queue_start = self.queue_array.as_mut_ptr();
+        let mut status = 0xFF;
+
+        // The pointers all point into the arrays, and the ops keep the
+        // queue end inside its array
+        Ok(unsafe {
+            match op {
+                Op::StackInit(len) => {
+                    let top = stack_bottom.wrapping_add(len as usize).wrapping_sub(1);
+                    vec![basino_stack_init(&mut self.stack, top, stack_bottom)]
+                }
+                Op::Push(value) => vec![basino_stack_push(&self.stack, value)],
+                Op::Pop => {
+                    let value = basino_stack_pop(&self.stack, &mut status);
+                    popped(value, status)
+                }
+                Op::QueueInit(len) => {
+                    let end = queue_start.add(len as usize - 1);
+                    vec![basino_queue_init(&mut self.queue, queue_start, end)]
+                }
+                Op::Put(value) => vec![basino_queue_put(&self.queue, value)],
+                Op::Get => {
+                    let value = basino_queue_get(&self.queue, &mut status);
+                    popped(value, status)
+                }
+                Op::Null => {
+                    let top = stack_bottom.add(ARRAY_LEN - 1);
+                    let end = queue_start.add(ARRAY_LEN - 1);
+                    let mut results = vec![
+                        basino_stack_init(core::ptr::null_mut(), top, stack_bottom),
+                        basino_stack_push(core::ptr::null(), 1),
+                    ];
+                    basino_stack_pop(core::ptr::null(), &mut status);
+                    results.push(status);
+                    results.push(basino_queue_init(core::ptr::null_mut(), queue_start, end));
+                    results.push(basino_queue_put(core::ptr::null(), 1));
+                    basino_queue_get(core::ptr::null(), &mut status);
+                    results.push(status);
+                    for getter in [
+                        basino_queue_get_queue_start,
+                        basino_queue_get_queue_end,
+                        basino_queue_get_head,
+                        basino_queue_get_last_head,
+                        basino_queue_get_tail,
+                    ] {
+                        let value = getter(core::ptr::null_mut(), &mut status);
+                        results.push(status);
+                        results.push(value.is_null() as u8);
+                    }
+                    results
+                }
+            }
+        })
+    }
+
+    fn outcome(&mut self, results: Vec<u8>) -> Result<Outcome, basino_emu::Error> {
+        let stack_array = self.stack_array.as_ptr() as usize;
+        let queue_array = self.queue_array.as_ptr() as usize;
+        let stack = &self.stack;
+        let queue = &self.queue;
+
+        let mut fields: Vec<Option<isize>> =
+            [stack.data, stack.top_sentinel, stack.bottom, stack.top]
+                .iter()
+                .map(|p| offset(*p as usize, stack_array))
+                .collect();
+        fields.extend(
+            [
+                queue.queue.cast_const(),
+                queue.start,
+                queue.end,
+                queue.head.cast_const(),
+                queue.last_head.cast_const(),
+                queue.tail.cast_const(),
+            ]
+            .iter()
+            .map(|p| offset(*p as usize, queue_array)),
+        );
+
+        let mut getters = Vec::new();
+        unsafe {
+            for getter in [
+                basino_get_basino_stack_top_sentinel,
+                basino_get_basino_stack_bottom,
+                basino_get_basino_stack_top,
+            ] {
+                getters.push((offset(getter(&self.stack) as usize, stack_array), 0));
+            }
+            for getter in [
+                basino_queue_get_queue_start,
+                basino_queue_get_queue_end,
+                basino_queue_get_head,
+                basino_queue_get_last_head,
+                basino_queue_get_tail,
+            ] {
+                let mut status = 0xFF;
+                let address = getter(&mut self.queue, &mut status);
+                getters.push((offset(address as usize, queue_array), status));
+            }
+        }
+
+        let mut arrays = self.stack_array.to_vec();
+        arrays.extend_from_slice(&self.queue_array[..]);
+
+        Ok(Outcome {
+            results,
+            fields,
+            getters,
+            arrays,
+        })
+    }
+}
+
+/// Run a script against the assembly and a fresh reference
+///
+/// asm should be freshly loaded, the reference starts out with
+/// uninitialized structs and zeroed arrays.  Returns the first step
+/// where they differ, or where the emulator failed.
+pub fn compare(asm: &mut Asm, script: &[Op]) -> Result<(), Error> {
+    let mut reference = Portable::new();
+    let emulator = |step: usize, op: Op| move |error| Error::Emulator { step, op, error };
+
+    for (step, op) in script.iter().copied().enumerate() {
+        let results = asm.run(op).map_err(emulator(step, op))?;
+        let found = asm.outcome(results).map_err(emulator(step, op))?;
+        // The reference doesn't use the emulator and never fails
+        let results = reference.run(op).map_err(emulator(step, op))?;
+        let expected = reference.outcome(results).map_err(emulator(step, op))?;
+
+        if found != expected {
+            return Err(Error::Mismatch {
+                step,
+                op,
+                asm: Box::new(found),
+                reference: Box::new(expected),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Make a random script of len operations
+///
+/// Both structs are initialized first.  Re-initializing is rare so
+/// the structs have time to fill up and wrap around.
+pub fn random_script(rng: &mut Rng, len: usize) -> Vec<Op> {
+    let mut script = vec![
+        Op::StackInit(rng.below(ARRAY_LEN as u32 + 1) as u16),
+        Op::QueueInit(rng.below(ARRAY_LEN as u32) as u16 + 1),
+    ];
+
+    while script.len() < len {
+        let value = rng.below(256) as u8;
+        let op = match rng.below(100) {
+            0 => Op::StackInit(rng.below(ARRAY_LEN as u32 + 1) as u16),
+            1 => Op::QueueInit(rng.below(ARRAY_LEN as u32) as u16 + 1),
+            2 => Op::Null,
+            3..=26 => Op::Push(value),
+            27..=50 => Op::Pop,
+            51..=75 => Op::Put(value),
+            _ => Op::Get,
+        };
+        script.push(op);
+    }
+
+    script
+}
@@ -0,0 +1,121 @@
+//! Run scripts against the assembly and the reference
+//!
+//! Each test is a script of stack or queue operations, either written
+//! out to hit an edge case like a full queue wrapping around, or
+//! generated from a seed.  check runs it on both backends and fails
+//! with the step where they first disagree.
+//!
+//! The assembly side needs libbasino.a, see basino_emu::library_path,
+//! so the tests are ignored by a plain cargo test.  make diff-test
+//! builds the library and runs them.
+
+use basino_diff::{compare, random_script, Asm, Op, ARRAY_LEN};
+use basino_emu::library_path;
+use rust_basino::testing::{Rng, RANDOM_SEED};
+
+/// Run a script and panic with the first difference
+fn check(name: &str, script: &[Op]) {
+    let path = library_path();
+    if !path.exists() {
+        panic!(
+            "{} doesn't exist, build it with make -C basino",
+            path.display()
+        );
+    }
+
+    let mut asm =
+        Asm::load(&path).unwrap_or_else(|e| panic!("couldn't load {}: {}", path.display(), e));
+    if let Err(e) = compare(&mut asm, script) {
+        panic!("{}: {}\nscript: {:?}", name, e, script);
+    }
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn stack_fill_and_drain() {
+    let mut script = vec![Op::StackInit(4)];
+    script.extend((1..=4).map(Op::Push));
+    script.extend([Op::Pop; 4]);
+    script.extend([Op::Push(5), Op::Pop, Op::Pop]);
+
+    check("stack_fill_and_drain", &script);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn stack_init_lengths() {
+    let mut script = Vec::new();
+    for len in 0..=ARRAY_LEN as u16 {
+        script.extend([Op::StackInit(len), Op::Push(len as u8), Op::Pop, Op::Pop]);
+    }
+
+    check("stack_init_lengths", &script);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn stack_uninitialized() {
+    check("stack_uninitialized", &[Op::Push(1), Op::Pop]);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn queue_fill_and_drain() {
+    let mut script = vec![Op::QueueInit(4)];
+    script.extend((1..=4).map(Op::Put));
+    script.extend([Op::Get; 4]);
+
+    check("queue_fill_and_drain", &script);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn queue_wraps_around() {
+    let mut script = vec![Op::QueueInit(3)];
+    for value in 0..20 {
+        script.extend([
+            Op::Put(value),
+            Op::Put(value + 100),
+            Op::Get,
+            Op::Get,
+            Op::Get,
+        ]);
+    }
+
+    check("queue_wraps_around", &script);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn queue_init_lengths() {
+    let mut script = Vec::new();
+    for len in 1..=ARRAY_LEN as u16 {
+        script.extend([Op::QueueInit(len), Op::Put(1), Op::Put(2), Op::Get]);
+    }
+
+    check("queue_init_lengths", &script);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn queue_uninitialized() {
+    check("queue_uninitialized", &[Op::Put(1), Op::Get]);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn null_pointers() {
+    check("null_pointers", &[Op::Null]);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make diff-test"]
+fn random_scripts() {
+    for i in 0..64 {
+        let seed = RANDOM_SEED.wrapping_add(i);
+        let mut rng = Rng::new(seed);
+        let script = random_script(&mut rng, 300);
+
+        check(&format!("random script with seed {:#x}", seed), &script);
+    }
+}
@@ -0,0 +1,1171 @@
+//! The AVR core
+//!
+//! The data memory uses the ATmega328p layout: the 32 registers at
+//! 0x00, the 64 I/O registers at 0x20, the extended I/O registers at
+//! 0x60 and 2K of SRAM at 0x100.  The registers, SREG and SP live in
+//! the data memory, so ld, st, in and out see them the same way the
+//! hardware does.
+//!
+//! Cycle counts are the ATmega328p datasheet counts.
+
+use crate::{object::Program, Error};
+
+/// The size of flash in bytes
+pub const FLASH_SIZE: usize = 32 * 1024;
+/// The size of the data address space in bytes
+pub const DATA_SIZE: usize = 0x900;
+/// The first SRAM address
+pub const SRAM_START: u16 = 0x100;
+/// The last SRAM address, the stack pointer starts here
+pub const RAMEND: u16 = 0x8FF;
+/// The default number of instructions a call can run
+pub const DEFAULT_STEP_LIMIT: u64 = 1_000_000;
+
+/// The carry flag bit in SREG
+pub const FLAG_C: u8 = 0;
+/// The zero flag bit in SREG
+pub const FLAG_Z: u8 = 1;
+/// The negative flag bit in SREG
+pub const FLAG_N: u8 = 2;
+/// The two's complement overflow flag bit in SREG
+pub const FLAG_V: u8 = 3;
+/// The sign flag bit in SREG
+pub const FLAG_S: u8 = 4;
+/// The half carry flag bit in SREG
+pub const FLAG_H: u8 = 5;
+/// The bit copy storage bit in SREG
+pub const FLAG_T: u8 = 6;
+/// The global interrupt enable bit in SREG
+pub const FLAG_I: u8 = 7;
+
+/// The data address of SREG
+const SREG: usize = 0x5F;
+/// The data address of SPL, SPH follows it
+const SPL: usize = 0x5D;
+/// The I/O registers start at this data address
+const IO_START: u16 = 0x20;
+
+/// The X pointer register, r27:r26
+const X: u8 = 26;
+/// The Y pointer register, r29:r28
+const Y: u8 = 28;
+/// The Z pointer register, r31:r30
+const Z: u8 = 30;
+
+/// The word address call pushes as the return address
+///
+/// It's outside of flash, so the routine can't get there any other
+/// way than returning.
+const RETURN_ADDRESS: u32 = 0xFFFF;
+
+/// The registers avr-gcc passes arguments in, from r25:r24 down
+const MAX_ARGUMENTS: usize = 9;
+
+/// The registers a routine has to preserve, r2-r17 and r29:r28
+const CALL_SAVED: [u8; 18] = [
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 28, 29,
+];
+
+/// How an indirect load or store changes the pointer register
+#[derive(Clone, Copy)]
+enum Pointer {
+    /// Leave it alone
+    Unchanged,
+    /// Increment it after the access
+    PostIncrement,
+    /// Decrement it before the access
+    PreDecrement,
+}
+
+/// An ATmega328p core
+pub struct Cpu {
+    /// The program memory
+    flash: Vec<u8>,
+    /// The data memory, including the registers and I/O registers
+    data: Vec<u8>,
+    /// The program counter, a word address
+    pc: u32,
+    /// The number of cycles run so far
+    cycles: u64,
+    /// The number of instructions a call can run before it fails
+    step_limit: u64,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cpu {
+    /// Create a new Cpu with empty flash and cleared memory
+    ///
+    /// The stack pointer starts at RAMEND.
+    pub fn new() -> Self {
+        let mut cpu = Cpu {
+            flash: vec![0xFF; FLASH_SIZE],
+            data: vec![0; DATA_SIZE],
+            pc: 0,
+            cycles: 0,
+            step_limit: DEFAULT_STEP_LIMIT,
+        };
+        cpu.set_sp(RAMEND);
+        cpu
+    }
+
+    /// Create a new Cpu with a program in flash
+    pub fn with_program(program: &Program) -> Result<Self, Error> {
+        let mut cpu = Self::new();
+        cpu.load_flash(0, program.flash())?;
+        Ok(cpu)
+    }
+
+    /// Copy bytes into flash starting at a byte address
+    pub fn load_flash(&mut self, address: u32, bytes: &[u8]) -> Result<(), Error> {
+        let start = address as usize;
+        let end = start + bytes.len();
+        if end > self.flash.len() {
+            return Err(Error::ProgramAddress(end as u32));
+        }
+        self.flash[start..end].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Load instruction words into flash starting at a byte address
+    pub fn load_words(&mut self, address: u32, words: &[u16]) -> Result<(), Error> {
+        let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        self.load_flash(address, &bytes)
+    }
+
+    /// Set the number of instructions a call can run
+    pub fn set_step_limit(&mut self, step_limit: u64) {
+        self.step_limit = step_limit;
+    }
+
+    /// The program counter as a byte address
+    pub fn pc(&self) -> u32 {
+        self.pc * 2
+    }
+
+    /// Set the program counter to a byte address
+    pub fn set_pc(&mut self, address: u32) {
+        self.pc = address / 2;
+    }
+
+    /// The number of cycles run so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Get a register
+    pub fn reg(&self, register: u8) -> u8 {
+        self.data[register as usize]
+    }
+
+    /// Set a register
+    pub fn set_reg(&mut self, register: u8, value: u8) {
+        self.data[register as usize] = value;
+    }
+
+    /// Get a register pair, register is the low register
+    pub fn reg_pair(&self, register: u8) -> u16 {
+        u16::from_le_bytes([self.reg(register), self.reg(register + 1)])
+    }
+
+    /// Set a register pair, register is the low register
+    pub fn set_reg_pair(&mut self, register: u8, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.set_reg(register, low);
+        self.set_reg(register + 1, high);
+    }
+
+    /// Get the status register
+    pub fn sreg(&self) -> u8 {
+        self.data[SREG]
+    }
+
+    /// Set the status register
+    pub fn set_sreg(&mut self, value: u8) {
+        self.data[SREG] = value;
+    }
+
+    /// Get a flag in the status register
+    pub fn flag(&self, bit: u8) -> bool {
+        self.sreg() & (1 << bit) != 0
+    }
+
+    /// Set or clear a flag in the status register
+    pub fn set_flag(&mut self, bit: u8, value: bool) {
+        if value {
+            self.data[SREG] |= 1 << bit;
+        } else {
+            self.data[SREG] &= !(1 << bit);
+        }
+    }
+
+    /// Get the stack pointer
+    pub fn sp(&self) -> u16 {
+        u16::from_le_bytes([self.data[SPL], self.data[SPL + 1]])
+    }
+
+    /// Set the stack pointer
+    pub fn set_sp(&mut self, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.data[SPL] = low;
+        self.data[SPL + 1] = high;
+    }
+
+    /// Read a byte of data memory
+    pub fn read(&self, address: u16) -> Result<u8, Error> {
+        self.data
+            .get(address as usize)
+            .copied()
+            .ok_or(Error::DataAddress(address as u32))
+    }
+
+    /// Write a byte of data memory
+    pub fn write(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        match self.data.get_mut(address as usize) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(Error::DataAddress(address as u32)),
+        }
+    }
+
+    /// Read a little-endian word of data memory
+    pub fn read_u16(&self, address: u16) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes([
+            self.read(address)?,
+            self.read(address.wrapping_add(1))?,
+        ]))
+    }
+
+    /// Write a little-endian word of data memory
+    pub fn write_u16(&mut self, address: u16, value: u16) -> Result<(), Error> {
+        let [low, high] = value.to_le_bytes();
+        self.write(address, low)?;
+        self.write(address.wrapping_add(1), high)
+    }
+
+    /// Read bytes of data memory
+    pub fn read_bytes(&self, address: u16, len: usize) -> Result<Vec<u8>, Error> {
+        (0..len)
+            .map(|i| self.read(address.wrapping_add(i as u16)))
+            .collect()
+    }
+
+    /// Write bytes of data memory
+    pub fn write_bytes(&mut self, address: u16, bytes: &[u8]) -> Result<(), Error> {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.write(address.wrapping_add(i as u16), *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Read a byte of flash
+    pub fn read_flash(&self, address: u32) -> Result<u8, Error> {
+        self.flash
+            .get(address as usize)
+            .copied()
+            .ok_or(Error::ProgramAddress(address))
+    }
+
+    /// Call a routine with the avr-gcc calling convention
+    ///
+    /// address is the byte address of the routine.  The arguments are
+    /// 8- or 16-bit values, the first goes in r25:r24, the second in
+    /// r23:r22 and so on.  An 8-bit argument is in the low register.
+    /// The return value is r25:r24, an 8-bit return value is the low
+    /// byte.
+    ///
+    /// The call fails if the routine doesn't return within the step
+    /// limit, doesn't restore the stack pointer, changes a call-saved
+    /// register or leaves r1 non-zero.
+    pub fn call(&mut self, address: u32, args: &[u16]) -> Result<u16, Error> {
+        if args.len() > MAX_ARGUMENTS {
+            return Err(Error::TooManyArguments(args.len()));
+        }
+
+        for (i, arg) in args.iter().enumerate() {
+            self.set_reg_pair(24 - 2 * i as u8, *arg);
+        }
+        self.set_reg(1, 0);
+
+        let saved: Vec<u8> = CALL_SAVED.iter().map(|r| self.reg(*r)).collect();
+        let sp = self.sp();
+
+        self.push_pc(RETURN_ADDRESS)?;
+        self.set_pc(address);
+
+        let mut steps = 0;
+        while self.pc != RETURN_ADDRESS {
+            if steps == self.step_limit {
+                return Err(Error::StepLimit(steps));
+            }
+            self.step()?;
+            steps += 1;
+        }
+
+        if self.sp() != sp {
+            return Err(Error::StackImbalance(sp, self.sp()));
+        }
+        for (register, value) in CALL_SAVED.iter().zip(saved) {
+            if self.reg(*register) != value {
+                return Err(Error::Clobbered(*register));
+            }
+        }
+        if self.reg(1) != 0 {
+            return Err(Error::Clobbered(1));
+        }
+
+        Ok(self.reg_pair(24))
+    }
+
+    /// Run one instruction
+    pub fn step(&mut self) -> Result<(), Error> {
+        let address = self.pc;
+        let opcode = self.fetch(address)?;
+        self.pc = address + 1;
+        self.cycles += self.execute(address, opcode)?;
+        Ok(())
+    }
+
+    /// The number of words in flash
+    fn flash_words(&self) -> u32 {
+        (self.flash.len() / 2) as u32
+    }
+
+    /// Fetch the instruction word at a word address
+    fn fetch(&self, address: u32) -> Result<u16, Error> {
+        if address >= self.flash_words() {
+            return Err(Error::ProgramAddress(address * 2));
+        }
+        let i = address as usize * 2;
+        Ok(u16::from_le_bytes([self.flash[i], self.flash[i + 1]]))
+    }
+
+    /// Fetch the second word of a two-word instruction
+    fn fetch_operand(&mut self) -> Result<u16, Error> {
+        let word = self.fetch(self.pc)?;
+        self.pc += 1;
+        Ok(word)
+    }
+
+    /// Jump relative to the program counter
+    ///
+    /// The program counter wraps around at the end of flash.
+    fn jump_relative(&mut self, offset: i32) {
+        self.pc = (self.pc as i32 + offset) as u32 & (self.flash_words() - 1);
+    }
+
+    /// Skip the next instruction, returns the number of words skipped
+    fn skip(&mut self) -> Result<u64, Error> {
+        let words = if is_two_words(self.fetch(self.pc)?) {
+            2
+        } else {
+            1
+        };
+        self.pc += words as u32;
+        Ok(words)
+    }
+
+    /// Push a byte on the stack
+    fn push(&mut self, value: u8) -> Result<(), Error> {
+        let sp = self.sp();
+        self.write(sp, value)?;
+        self.set_sp(sp.wrapping_sub(1));
+        Ok(())
+    }
+
+    /// Pop a byte off the stack
+    fn pop(&mut self) -> Result<u8, Error> {
+        let sp = self.sp().wrapping_add(1);
+        self.set_sp(sp);
+        self.read(sp)
+    }
+
+    /// Push a return address, the low byte first
+    fn push_pc(&mut self, pc: u32) -> Result<(), Error> {
+        self.push(pc as u8)?;
+        self.push((pc >> 8) as u8)
+    }
+
+    /// Pop a return address
+    fn pop_pc(&mut self) -> Result<u32, Error> {
+        let high = self.pop()? as u32;
+        let low = self.pop()? as u32;
+        Ok((high << 8) | low)
+    }
+
+    /// Set N, V, S and Z from a result and the overflow
+    fn set_nvsz(&mut self, result: u8, overflow: bool) {
+        let negative = result & 0x80 != 0;
+        self.set_flag(FLAG_N, negative);
+        self.set_flag(FLAG_V, overflow);
+        self.set_flag(FLAG_S, negative ^ overflow);
+        self.set_flag(FLAG_Z, result == 0);
+    }
+
+    /// Add with an optional carry in and set the flags
+    fn add(&mut self, d: u8, r: u8, carry: bool) -> u8 {
+        let result = d.wrapping_add(r).wrapping_add(carry as u8);
+        let carries = (d & r) | (r & !result) | (!result & d);
+        self.set_flag(FLAG_H, carries & 0x08 != 0);
+        self.set_flag(FLAG_C, carries & 0x80 != 0);
+        self.set_nvsz(result, (d & r & !result | !d & !r & result) & 0x80 != 0);
+        result
+    }
+
+    /// Subtract with an optional borrow in and set the flags
+    ///
+    /// With a borrow in, Z is only cleared, so a multi-byte compare
+    /// is zero only if every byte is.
+    fn sub(&mut self, d: u8, r: u8, borrow: bool) -> u8 {
+        let zero = self.flag(FLAG_Z);
+        let result = d.wrapping_sub(r).wrapping_sub(borrow as u8);
+        let borrows = (!d & r) | (r & result) | (result & !d);
+        self.set_flag(FLAG_H, borrows & 0x08 != 0);
+        self.set_flag(FLAG_C, borrows & 0x80 != 0);
+        self.set_nvsz(result, (d & !r & !result | !d & r & result) & 0x80 != 0);
+        if borrow {
+            self.set_flag(FLAG_Z, result == 0 && zero);
+        }
+        result
+    }
+
+    /// Set the flags for a logical operation
+    fn logic(&mut self, result: u8) -> u8 {
+        self.set_nvsz(result, false);
+        result
+    }
+
+    /// Set the flags for a shift right, bit is the bit shifted out
+    fn shift_right(&mut self, result: u8, bit: bool) -> u8 {
+        let negative = result & 0x80 != 0;
+        self.set_flag(FLAG_C, bit);
+        self.set_nvsz(result, negative ^ bit);
+        result
+    }
+
+    /// Set the flags for a multiply and store the product in r1:r0
+    fn multiply(&mut self, product: u16) {
+        self.set_flag(FLAG_C, product & 0x8000 != 0);
+        self.set_flag(FLAG_Z, product == 0);
+        self.set_reg_pair(0, product);
+    }
+
+    /// Load a register from the address in a pointer register
+    fn load_indirect(&mut self, d: u8, pointer: u8, mode: Pointer) -> Result<u64, Error> {
+        let address = self.pointer_address(pointer, mode);
+        let value = self.read(address)?;
+        self.set_reg(d, value);
+        Ok(2)
+    }
+
+    /// Store a register at the address in a pointer register
+    fn store_indirect(&mut self, r: u8, pointer: u8, mode: Pointer) -> Result<u64, Error> {
+        let address = self.pointer_address(pointer, mode);
+        self.write(address, self.reg(r))?;
+        Ok(2)
+    }
+
+    /// Get the address in a pointer register and update the register
+    fn pointer_address(&mut self, pointer: u8, mode: Pointer) -> u16 {
+        let value = self.reg_pair(pointer);
+        match mode {
+            Pointer::Unchanged => value,
+            Pointer::PostIncrement => {
+                self.set_reg_pair(pointer, value.wrapping_add(1));
+                value
+            }
+            Pointer::PreDecrement => {
+                let value = value.wrapping_sub(1);
+                self.set_reg_pair(pointer, value);
+                value
+            }
+        }
+    }
+
+    /// Load a register from the flash address in Z
+    fn load_program(&mut self, d: u8, mode: Pointer) -> Result<u64, Error> {
+        let address = self.pointer_address(Z, mode);
+        let value = self.read_flash(address as u32)?;
+        self.set_reg(d, value);
+        Ok(3)
+    }
+
+    /// Run an instruction, returns the number of cycles it took
+    ///
+    /// address is the word address of the instruction, the program
+    /// counter already points to the next word.
+    fn execute(&mut self, address: u32, op: u16) -> Result<u64, Error> {
+        let illegal = Err(Error::IllegalInstruction(address * 2, op));
+
+        // The operand fields most instructions use
+        let d = ((op >> 4) & 0x1F) as u8;
+        let r = ((op & 0x0F) | ((op >> 5) & 0x10)) as u8;
+        let d_high = 16 + ((op >> 4) & 0x0F) as u8;
+        let k = ((op & 0x0F) | ((op >> 4) & 0xF0)) as u8;
+
+        match op >> 12 {
+            0x0 => match (op >> 10) & 0x03 {
+                0 => match op >> 8 {
+                    // nop
+                    0x00 if op == 0 => Ok(1),
+                    // movw
+                    0x01 => {
+                        let d = ((op >> 4) & 0x0F) as u8 * 2;
+                        let r = (op & 0x0F) as u8 * 2;
+                        self.set_reg_pair(d, self.reg_pair(r));
+                        Ok(1)
+                    }
+                    // muls
+                    0x02 => {
+                        let r = 16 + (op & 0x0F) as u8;
+                        let product = self.reg(d_high) as i8 as i16 * self.reg(r) as i8 as i16;
+                        self.multiply(product as u16);
+                        Ok(2)
+                    }
+                    // mulsu
+                    0x03 if op & 0x88 == 0 => {
+                        let d = 16 + ((op >> 4) & 0x07) as u8;
+                        let r = 16 + (op & 0x07) as u8;
+                        let product = self.reg(d) as i8 as i16 * self.reg(r) as i16;
+                        self.multiply(product as u16);
+                        Ok(2)
+                    }
+                    _ => illegal,
+                },
+                // cpc
+                1 => {
+                    self.sub(self.reg(d), self.reg(r), self.flag(FLAG_C));
+                    Ok(1)
+                }
+                // sbc
+                2 => {
+                    let result = self.sub(self.reg(d), self.reg(r), self.flag(FLAG_C));
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // add, lsl
+                _ => {
+                    let result = self.add(self.reg(d), self.reg(r), false);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+            },
+            0x1 => match (op >> 10) & 0x03 {
+                // cpse
+                0 => {
+                    if self.reg(d) == self.reg(r) {
+                        Ok(1 + self.skip()?)
+                    } else {
+                        Ok(1)
+                    }
+                }
+                // cp
+                1 => {
+                    self.sub(self.reg(d), self.reg(r), false);
+                    Ok(1)
+                }
+                // sub
+                2 => {
+                    let result = self.sub(self.reg(d), self.reg(r), false);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // adc, rol
+                _ => {
+                    let result = self.add(self.reg(d), self.reg(r), self.flag(FLAG_C));
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+            },
+            0x2 => {
+                let result = match (op >> 10) & 0x03 {
+                    // and, tst
+                    0 => self.logic(self.reg(d) & self.reg(r)),
+                    // eor, clr
+                    1 => self.logic(self.reg(d) ^ self.reg(r)),
+                    // or
+                    2 => self.logic(self.reg(d) | self.reg(r)),
+                    // mov
+                    _ => self.reg(r),
+                };
+                self.set_reg(d, result);
+                Ok(1)
+            }
+            // cpi
+            0x3 => {
+                self.sub(self.reg(d_high), k, false);
+                Ok(1)
+            }
+            // sbci
+            0x4 => {
+                let result = self.sub(self.reg(d_high), k, self.flag(FLAG_C));
+                self.set_reg(d_high, result);
+                Ok(1)
+            }
+            // subi
+            0x5 => {
+                let result = self.sub(self.reg(d_high), k, false);
+                self.set_reg(d_high, result);
+                Ok(1)
+            }
+            // ori, sbr
+            0x6 => {
+                let result = self.logic(self.reg(d_high) | k);
+                self.set_reg(d_high, result);
+                Ok(1)
+            }
+            // andi, cbr
+            0x7 => {
+                let result = self.logic(self.reg(d_high) & k);
+                self.set_reg(d_high, result);
+                Ok(1)
+            }
+            // ldd and std with a displacement from Y or Z, including ld
+            // and st through Y or Z
+            0x8 | 0xA => {
+                let q = (op & 0x07) | ((op >> 7) & 0x18) | ((op >> 8) & 0x20);
+                let pointer = if op & 0x08 != 0 { Y } else { Z };
+                let address = self.reg_pair(pointer).wrapping_add(q);
+                if op & 0x0200 != 0 {
+                    self.write(address, self.reg(d))?;
+                } else {
+                    let value = self.read(address)?;
+                    self.set_reg(d, value);
+                }
+                Ok(2)
+            }
+            0x9 => self.execute_9(address, op, d, r),
+            0xB => {
+                let a = (op & 0x0F) | ((op >> 5) & 0x30);
+                if op & 0x0800 != 0 {
+                    // out
+                    self.write(IO_START + a, self.reg(d))?;
+                } else {
+                    // in
+                    let value = self.read(IO_START + a)?;
+                    self.set_reg(d, value);
+                }
+                Ok(1)
+            }
+            // rjmp
+            0xC => {
+                self.jump_relative(sign_extend(op & 0x0FFF, 12));
+                Ok(2)
+            }
+            // rcall
+            0xD => {
+                self.push_pc(self.pc)?;
+                self.jump_relative(sign_extend(op & 0x0FFF, 12));
+                Ok(3)
+            }
+            // ldi, ser
+            0xE => {
+                self.set_reg(d_high, k);
+                Ok(1)
+            }
+            _ => {
+                let bit = (op & 0x07) as u8;
+                match (op >> 9) & 0x07 {
+                    // brbs and brbc
+                    0..=3 => {
+                        let set = op & 0x0400 == 0;
+                        if self.flag(bit) == set {
+                            self.jump_relative(sign_extend((op >> 3) & 0x7F, 7));
+                            Ok(2)
+                        } else {
+                            Ok(1)
+                        }
+                    }
+                    // bld
+                    4 if op & 0x08 == 0 => {
+                        let value = if self.flag(FLAG_T) {
+                            self.reg(d) | (1 << bit)
+                        } else {
+                            self.reg(d) & !(1 << bit)
+                        };
+                        self.set_reg(d, value);
+                        Ok(1)
+                    }
+                    // bst
+                    5 if op & 0x08 == 0 => {
+                        self.set_flag(FLAG_T, self.reg(d) & (1 << bit) != 0);
+                        Ok(1)
+                    }
+                    // sbrc and sbrs
+                    6 | 7 if op & 0x08 == 0 => {
+                        let set = op & 0x0200 != 0;
+                        if (self.reg(d) & (1 << bit) != 0) == set {
+                            Ok(1 + self.skip()?)
+                        } else {
+                            Ok(1)
+                        }
+                    }
+                    _ => illegal,
+                }
+            }
+        }
+    }
+
+    /// Run an instruction with 1001 in the top four bits
+    fn execute_9(&mut self, address: u32, op: u16, d: u8, r: u8) -> Result<u64, Error> {
+        let illegal = Err(Error::IllegalInstruction(address * 2, op));
+
+        match (op >> 8) & 0x0F {
+            // The loads
+            0x0 | 0x1 => match op & 0x0F {
+                // lds
+                0x0 => {
+                    let address = self.fetch_operand()?;
+                    let value = self.read(address)?;
+                    self.set_reg(d, value);
+                    Ok(2)
+                }
+                0x1 => self.load_indirect(d, Z, Pointer::PostIncrement),
+                0x2 => self.load_indirect(d, Z, Pointer::PreDecrement),
+                0x4 => self.load_program(d, Pointer::Unchanged),
+                0x5 => self.load_program(d, Pointer::PostIncrement),
+                0x9 => self.load_indirect(d, Y, Pointer::PostIncrement),
+                0xA => self.load_indirect(d, Y, Pointer::PreDecrement),
+                0xC => self.load_indirect(d, X, Pointer::Unchanged),
+                0xD => self.load_indirect(d, X, Pointer::PostIncrement),
+                0xE => self.load_indirect(d, X, Pointer::PreDecrement),
+                // pop
+                0xF => {
+                    let value = self.pop()?;
+                    self.set_reg(d, value);
+                    Ok(2)
+                }
+                _ => illegal,
+            },
+            // The stores
+            0x2 | 0x3 => match op & 0x0F {
+                // sts
+                0x0 => {
+                    let address = self.fetch_operand()?;
+                    self.write(address, self.reg(d))?;
+                    Ok(2)
+                }
+                0x1 => self.store_indirect(d, Z, Pointer::PostIncrement),
+                0x2 => self.store_indirect(d, Z, Pointer::PreDecrement),
+                0x9 => self.store_indirect(d, Y, Pointer::PostIncrement),
+                0xA => self.store_indirect(d, Y, Pointer::PreDecrement),
+                0xC => self.store_indirect(d, X, Pointer::Unchanged),
+                0xD => self.store_indirect(d, X, Pointer::PostIncrement),
+                0xE => self.store_indirect(d, X, Pointer::PreDecrement),
+                // push
+                0xF => {
+                    self.push(self.reg(d))?;
+                    Ok(2)
+                }
+                _ => illegal,
+            },
+            // The one-operand instructions, jumps and calls
+            0x4 | 0x5 => match op & 0x0F {
+                // com
+                0x0 => {
+                    let result = self.logic(!self.reg(d));
+                    self.set_flag(FLAG_C, true);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // neg
+                0x1 => {
+                    let value = self.reg(d);
+                    let result = self.sub(0, value, false);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // swap
+                0x2 => {
+                    self.set_reg(d, self.reg(d).rotate_left(4));
+                    Ok(1)
+                }
+                // inc
+                0x3 => {
+                    let result = self.reg(d).wrapping_add(1);
+                    self.set_nvsz(result, result == 0x80);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // asr
+                0x5 => {
+                    let value = self.reg(d);
+                    let result = self.shift_right((value & 0x80) | (value >> 1), value & 1 != 0);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // lsr
+                0x6 => {
+                    let value = self.reg(d);
+                    let result = self.shift_right(value >> 1, value & 1 != 0);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // ror
+                0x7 => {
+                    let value = self.reg(d);
+                    let carry = (self.flag(FLAG_C) as u8) << 7;
+                    let result = self.shift_right(carry | (value >> 1), value & 1 != 0);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                0x8 => match op {
+                    // bset and bclr, sec, clc, sei, cli and the rest
+                    0x9408..=0x94F8 => {
+                        self.set_flag(((op >> 4) & 0x07) as u8, op & 0x80 == 0);
+                        Ok(1)
+                    }
+                    // ret
+                    0x9508 => {
+                        self.pc = self.pop_pc()?;
+                        Ok(4)
+                    }
+                    // reti
+                    0x9518 => {
+                        self.pc = self.pop_pc()?;
+                        self.set_flag(FLAG_I, true);
+                        Ok(4)
+                    }
+                    // sleep, break and wdr, there's nothing to wait for
+                    0x9588 | 0x9598 | 0x95A8 => Ok(1),
+                    // lpm r0, Z
+                    0x95C8 => self.load_program(0, Pointer::Unchanged),
+                    _ => illegal,
+                },
+                0x9 => match op {
+                    // ijmp
+                    0x9409 => {
+                        self.pc = self.reg_pair(Z) as u32;
+                        Ok(2)
+                    }
+                    // icall
+                    0x9509 => {
+                        self.push_pc(self.pc)?;
+                        self.pc = self.reg_pair(Z) as u32;
+                        Ok(3)
+                    }
+                    _ => illegal,
+                },
+                // dec
+                0xA => {
+                    let result = self.reg(d).wrapping_sub(1);
+                    self.set_nvsz(result, result == 0x7F);
+                    self.set_reg(d, result);
+                    Ok(1)
+                }
+                // jmp
+                0xC | 0xD => {
+                    self.pc = self.long_address(op)?;
+                    Ok(3)
+                }
+                // call
+                0xE | 0xF => {
+                    let target = self.long_address(op)?;
+                    self.push_pc(self.pc)?;
+                    self.pc = target;
+                    Ok(4)
+                }
+                _ => illegal,
+            },
+            // adiw and sbiw
+            0x6 | 0x7 => {
+                let d = 24 + ((op >> 4) & 0x03) as u8 * 2;
+                let k = (op & 0x0F) | ((op >> 2) & 0x30);
+                let value = self.reg_pair(d);
+                let (result, carry, overflow) = if op & 0x0100 == 0 {
+                    let result = value.wrapping_add(k);
+                    (result, result < value, !value & result & 0x8000 != 0)
+                } else {
+                    let result = value.wrapping_sub(k);
+                    (result, result > value, value & !result & 0x8000 != 0)
+                };
+                let negative = result & 0x8000 != 0;
+                self.set_flag(FLAG_C, carry);
+                self.set_flag(FLAG_V, overflow);
+                self.set_flag(FLAG_N, negative);
+                self.set_flag(FLAG_S, negative ^ overflow);
+                self.set_flag(FLAG_Z, result == 0);
+                self.set_reg_pair(d, result);
+                Ok(2)
+            }
+            // cbi, sbic, sbi and sbis
+            0x8..=0xB => {
+                let address = IO_START + ((op >> 3) & 0x1F);
+                let bit = 1 << (op & 0x07);
+                let value = self.read(address)?;
+                match (op >> 8) & 0x03 {
+                    0 => {
+                        self.write(address, value & !bit)?;
+                        Ok(2)
+                    }
+                    2 => {
+                        self.write(address, value | bit)?;
+                        Ok(2)
+                    }
+                    kind => {
+                        let set = kind == 3;
+                        if (value & bit != 0) == set {
+                            Ok(1 + self.skip()?)
+                        } else {
+                            Ok(1)
+                        }
+                    }
+                }
+            }
+            // mul
+            _ => {
+                let product = self.reg(d) as u16 * self.reg(r) as u16;
+                self.multiply(product);
+                Ok(2)
+            }
+        }
+    }
+
+    /// Get the 22-bit word address of a jmp or call
+    fn long_address(&mut self, op: u16) -> Result<u32, Error> {
+        let high = (((op >> 3) & 0x3E) | (op & 0x01)) as u32;
+        let low = self.fetch_operand()? as u32;
+        Ok((high << 16) | low)
+    }
+}
+
+/// Check if an instruction has a second word: lds, sts, jmp and call
+fn is_two_words(op: u16) -> bool {
+    matches!(op & 0xFE0F, 0x9000 | 0x9200) || matches!(op & 0xFE0E, 0x940C | 0x940E)
+}
+
+/// Sign extend a field of bits bits
+fn sign_extend(value: u16, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value as i32) << shift) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ret
+    const RET: u16 = 0x9508;
+
+    /// Load a routine at address 0 and call it
+    fn call(words: &[u16], args: &[u16]) -> (Cpu, Result<u16, Error>) {
+        let mut cpu = Cpu::new();
+        cpu.load_words(0, words).unwrap();
+        let result = cpu.call(0, args);
+        (cpu, result)
+    }
+
+    #[test]
+    fn add_with_carry_out() {
+        // The same code as basino_add
+        let words = [
+            0x0F86, // add r24, r22
+            0xF010, // brcs .+4
+            0xE090, // ldi r25, 0
+            0xC001, // rjmp .+2
+            0xE091, // ldi r25, 1
+            RET,
+        ];
+
+        assert_eq!(call(&words, &[1, 2]).1.unwrap(), 3);
+        assert_eq!(call(&words, &[200, 100]).1.unwrap(), 0x012C);
+    }
+
+    #[test]
+    fn add_sets_flags() {
+        let (cpu, result) = call(&[0x0F86, RET], &[0x7F, 0x01]);
+
+        assert_eq!(result.unwrap() & 0xFF, 0x80);
+        assert!(cpu.flag(FLAG_H));
+        assert!(cpu.flag(FLAG_V));
+        assert!(cpu.flag(FLAG_N));
+        assert!(!cpu.flag(FLAG_S));
+        assert!(!cpu.flag(FLAG_C));
+        assert!(!cpu.flag(FLAG_Z));
+    }
+
+    #[test]
+    fn cp_cpc_compares_words() {
+        // The same code as basino_gt, returns a > b
+        let words = [
+            0x1768, // cp r22, r24
+            0x0779, // cpc r23, r25
+            0xF410, // brcc .+4
+            0xE081, // ldi r24, 1
+            0xC001, // rjmp .+2
+            0xE080, // ldi r24, 0
+            RET,
+        ];
+
+        assert_eq!(call(&words, &[0x0100, 0x00FF]).1.unwrap() & 0xFF, 1);
+        assert_eq!(call(&words, &[0x00FF, 0x0100]).1.unwrap() & 0xFF, 0);
+        assert_eq!(call(&words, &[0x1234, 0x1234]).1.unwrap() & 0xFF, 0);
+    }
+
+    #[test]
+    fn cpc_only_clears_zero() {
+        let words = [
+            0x1786, // cp r24, r22
+            0x0797, // cpc r25, r23
+            RET,
+        ];
+
+        let (cpu, _) = call(&words, &[0x0100, 0x0000]);
+        assert!(!cpu.flag(FLAG_Z));
+        let (cpu, _) = call(&words, &[0x0001, 0x0101]);
+        assert!(!cpu.flag(FLAG_Z));
+        assert!(cpu.flag(FLAG_C));
+        let (cpu, _) = call(&words, &[0x1234, 0x1234]);
+        assert!(cpu.flag(FLAG_Z));
+    }
+
+    #[test]
+    fn st_pre_decrement_and_ld_post_increment() {
+        let words = [
+            0x01DC, // movw r26, r24
+            0x936E, // st -X, r22
+            0x937E, // st -X, r23
+            0x918D, // ld r24, X+
+            0x919D, // ld r25, X+
+            RET,
+        ];
+
+        let (cpu, result) = call(&words, &[0x0200, 0x1234]);
+
+        assert_eq!(result.unwrap(), 0x3412);
+        assert_eq!(cpu.read(0x01FF).unwrap(), 0x34);
+        assert_eq!(cpu.read(0x01FE).unwrap(), 0x12);
+        assert_eq!(cpu.reg_pair(26), 0x0200);
+    }
+
+    #[test]
+    fn st_x_through_argument_pointer() {
+        let words = [
+            0x01DA, // movw r26, r20
+            0x93EC, // st X, r30
+            RET,
+        ];
+        let mut cpu = Cpu::new();
+        cpu.load_words(0, &words).unwrap();
+        cpu.set_reg(30, 0x5A);
+
+        cpu.call(0, &[0, 0, 0x0300]).unwrap();
+
+        assert_eq!(cpu.read(0x0300).unwrap(), 0x5A);
+    }
+
+    #[test]
+    fn ldd_std_with_displacement() {
+        let words = [
+            0x93CF, // push r28
+            0x93DF, // push r29
+            0x01EC, // movw r28, r24
+            0x836E, // std Y+6, r22
+            0x837F, // std Y+7, r23
+            0x818E, // ldd r24, Y+6
+            0x91DF, // pop r29
+            0x91CF, // pop r28
+            RET,
+        ];
+
+        let (cpu, result) = call(&words, &[0x0100, 0xBEEF]);
+
+        assert_eq!(result.unwrap() & 0xFF, 0xEF);
+        assert_eq!(cpu.read_u16(0x0106).unwrap(), 0xBEEF);
+    }
+
+    #[test]
+    fn rcall_ret_and_cycles() {
+        let words = [
+            0xD001, // rcall .+2
+            RET, 0x93CF, // push r28
+            0xE087, // ldi r24, 7
+            0x91CF, // pop r28
+            RET,
+        ];
+
+        let (cpu, result) = call(&words, &[]);
+
+        assert_eq!(result.unwrap() & 0xFF, 7);
+        assert_eq!(cpu.cycles(), 3 + 2 + 1 + 2 + 4 + 4);
+        assert_eq!(cpu.sp(), RAMEND);
+    }
+
+    #[test]
+    fn lpm_reads_flash() {
+        let words = [
+            0x01FC, // movw r30, r24
+            0x9184, // lpm r24, Z
+            RET, 0xBEEF,
+        ];
+
+        assert_eq!(call(&words, &[6]).1.unwrap(), 0xEF);
+        assert_eq!(call(&words, &[7]).1.unwrap(), 0xBE);
+    }
+
+    #[test]
+    fn sbiw_brne_loop() {
+        let words = [
+            0x9701, // sbiw r24, 1
+            0xF7F1, // brne .-4
+            RET,
+        ];
+
+        let (cpu, result) = call(&words, &[10]);
+
+        assert_eq!(result.unwrap(), 0);
+        assert_eq!(cpu.cycles(), 10 * 2 + 9 * 2 + 1 + 4);
+    }
+
+    #[test]
+    fn out_writes_io_register() {
+        // The same code as basino_sleep_idle
+        let words = [
+            0xE081, // ldi r24, 1
+            0xBF83, // out 0x33, r24
+            0x9588, // sleep
+            0x2788, // clr r24
+            0xBF83, // out 0x33, r24
+            RET,
+        ];
+
+        let (cpu, result) = call(&words, &[]);
+
+        assert_eq!(result.unwrap() & 0xFF, 0);
+        assert_eq!(cpu.read(0x53).unwrap(), 0);
+        assert!(cpu.flag(FLAG_Z));
+    }
+
+    #[test]
+    fn call_checks_call_saved_registers() {
+        let words = [
+            0xE001, // ldi r16, 1
+            RET,
+        ];
+
+        assert!(matches!(call(&words, &[]).1, Err(Error::Clobbered(16))));
+    }
+
+    #[test]
+    fn call_stops_at_step_limit() {
+        let mut cpu = Cpu::new();
+        cpu.load_words(0, &[0xCFFF]).unwrap(); // rjmp .-2
+        cpu.set_step_limit(100);
+
+        assert!(matches!(cpu.call(0, &[]), Err(Error::StepLimit(100))));
+    }
+
+    #[test]
+    fn erased_flash_is_illegal() {
+        assert!(matches!(
+            call(&[], &[]).1,
+            Err(Error::IllegalInstruction(0, 0xFFFF))
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+//! Run the basino assembly routines on the host
+//!
+//! The rust-basino tests run on an Uno or under simavr or QEMU and
+//! report over the serial port.  This crate is a small AVR core
+//! emulator so the assembly routines can also be tested with cargo
+//! test on the host.
+//!
+//! Program loads the code out of libbasino.a, a relocatable ELF file
+//! made by avr-ld -r, or an ar archive of object files.  It places
+//! the code in flash and applies the relocations.  Cpu emulates the
+//! ATmega328p core: the registers, the data memory and the
+//! instructions basino uses, plus most of the rest of the AVR5
+//! instruction set.  Cpu::call calls a routine with the avr-gcc
+//! calling convention and checks that the routine followed it.
+//!
+//! There are no peripherals or interrupts.  sleep doesn't wait for
+//! anything, and writes to I/O registers only change the data memory.
+#![warn(missing_docs)]
+
+use std::{fmt, io};
+
+pub mod cpu;
+pub mod object;
+
+pub use cpu::Cpu;
+pub use object::{library_path, Program};
+
+/// Errors that can occur loading or running a program
+#[derive(Debug)]
+pub enum Error {
+    /// The object file couldn't be read
+    Io(io::Error),
+    /// The object file isn't valid or uses something the loader
+    /// doesn't support
+    Format(String),
+    /// A relocation type the loader doesn't support
+    UnsupportedRelocation(u8),
+    /// A relocated value doesn't fit in the instruction
+    RelocationOutOfRange(u32),
+    /// A symbol isn't defined in the program
+    UndefinedSymbol(String),
+    /// An instruction the emulator doesn't know, at a byte address
+    IllegalInstruction(u32, u16),
+    /// The program accessed data memory outside the address space
+    DataAddress(u32),
+    /// The program jumped outside of flash, the byte address
+    ProgramAddress(u32),
+    /// A call didn't return within the step limit
+    StepLimit(u64),
+    /// A call didn't preserve a call-saved register, or r1 isn't zero
+    Clobbered(u8),
+    /// A call returned with a different stack pointer
+    StackImbalance(u16, u16),
+    /// More arguments than the calling convention passes in registers
+    TooManyArguments(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Format(reason) => write!(f, "invalid object file: {}", reason),
+            Error::UnsupportedRelocation(kind) => {
+                write!(f, "unsupported relocation type {}", kind)
+            }
+            Error::RelocationOutOfRange(address) => {
+                write!(f, "relocation at 0x{:04x} is out of range", address)
+            }
+            Error::UndefinedSymbol(name) => write!(f, "undefined symbol {}", name),
+            Error::IllegalInstruction(address, opcode) => write!(
+                f,
+                "illegal instruction 0x{:04x} at 0x{:04x}",
+                opcode, address
+            ),
+            Error::DataAddress(address) => {
+                write!(f, "data address 0x{:04x} is out of range", address)
+            }
+            Error::ProgramAddress(address) => {
+                write!(f, "program address 0x{:04x} is out of range", address)
+            }
+            Error::StepLimit(steps) => write!(f, "the call didn't return in {} steps", steps),
+            Error::Clobbered(register) => write!(f, "the call clobbered r{}", register),
+            Error::StackImbalance(expected, found) => write!(
+                f,
+                "the call returned with SP 0x{:04x}, expected 0x{:04x}",
+                found, expected
+            ),
+            Error::TooManyArguments(count) => write!(f, "too many arguments: {}", count),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,705 @@
+//! Load AVR object code
+//!
+//! basino/Makefile links the assembled files into libbasino.a with
+//! avr-ld -r, so despite the name it's a single relocatable ELF file.
+//! The loader also takes plain object files and ar archives of them.
+//!
+//! The code sections of every object go into flash one after another
+//! starting at address 0.  Then the relocations are applied, the same
+//! way avr-ld would when linking the final program.  Only code and
+//! progmem sections are loaded, there's nothing to copy .data to RAM.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::Error;
+
+/// Where libbasino.a is
+///
+/// This is the file named by the BASINO_LIB environment variable, or
+/// basino/libbasino.a in this repository, built with make -C basino.
+pub fn library_path() -> PathBuf {
+    env::var_os("BASINO_LIB")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../basino/libbasino.a"))
+}
+
+/// The start of an ar archive
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+/// The size of an ar member header
+const AR_HEADER_SIZE: usize = 60;
+/// The start of an ELF file
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// The ELF machine number for AVR
+const EM_AVR: u16 = 83;
+/// The ELF file type of a relocatable object file
+const ET_REL: u16 = 1;
+
+/// A symbol table section
+const SHT_SYMTAB: u32 = 2;
+/// A relocation section with explicit addends
+const SHT_RELA: u32 = 4;
+/// A section that takes no space in the file, like .bss
+const SHT_NOBITS: u32 = 8;
+/// A relocation section without addends
+const SHT_REL: u32 = 9;
+
+/// The section is in memory when the program runs
+const SHF_ALLOC: u32 = 0x2;
+/// The section is code
+const SHF_EXECINSTR: u32 = 0x4;
+
+/// The section index of undefined symbols
+const SHN_UNDEF: u16 = 0;
+/// The section index of absolute symbols
+const SHN_ABS: u16 = 0xFFF1;
+
+/// A global symbol
+const STB_GLOBAL: u8 = 1;
+/// A weak symbol
+const STB_WEAK: u8 = 2;
+
+/// The AVR relocation types the loader supports
+const R_AVR_NONE: u8 = 0;
+const R_AVR_32: u8 = 1;
+const R_AVR_7_PCREL: u8 = 2;
+const R_AVR_13_PCREL: u8 = 3;
+const R_AVR_16: u8 = 4;
+const R_AVR_16_PM: u8 = 5;
+const R_AVR_LO8_LDI: u8 = 6;
+const R_AVR_HI8_LDI: u8 = 7;
+const R_AVR_LO8_LDI_PM: u8 = 12;
+const R_AVR_HI8_LDI_PM: u8 = 13;
+const R_AVR_CALL: u8 = 18;
+
+/// A section header
+struct Section {
+    name: String,
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+    align: u32,
+}
+
+/// A symbol table entry
+struct Symbol {
+    name: String,
+    value: u32,
+    bind: u8,
+    section: u16,
+}
+
+/// A parsed ELF object file
+struct Object<'a> {
+    name: String,
+    data: &'a [u8],
+    sections: Vec<Section>,
+}
+
+/// Code loaded out of object files and ready to put in flash
+pub struct Program {
+    /// The flash image
+    flash: Vec<u8>,
+    /// The byte addresses of the global symbols
+    symbols: HashMap<String, u32>,
+}
+
+impl Program {
+    /// Load a program from an object file or archive on disk
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::load(&fs::read(path)?)
+    }
+
+    /// Load a program from the bytes of an object file or archive
+    pub fn load(bytes: &[u8]) -> Result<Self, Error> {
+        let objects = if bytes.starts_with(AR_MAGIC) {
+            archive_members(bytes)?
+                .into_iter()
+                .filter(|(_, data)| data.starts_with(ELF_MAGIC))
+                .map(|(name, data)| Object::parse(name, data))
+                .collect::<Result<Vec<_>, _>>()?
+        } else if bytes.starts_with(ELF_MAGIC) {
+            vec![Object::parse(String::from("object"), bytes)?]
+        } else {
+            return Err(Error::Format(String::from(
+                "not an ELF object file or ar archive",
+            )));
+        };
+
+        link(&objects)
+    }
+
+    /// The flash image, starting at address 0
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Get the byte address of a global symbol
+    pub fn symbol(&self, name: &str) -> Result<u32, Error> {
+        self.symbols
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::UndefinedSymbol(String::from(name)))
+    }
+
+    /// The names of the global symbols
+    pub fn symbol_names(&self) -> impl Iterator<Item = &str> {
+        self.symbols.keys().map(|name| name.as_str())
+    }
+}
+
+/// Lay out the code of every object in flash and relocate it
+fn link(objects: &[Object]) -> Result<Program, Error> {
+    let mut flash = Vec::new();
+    // The flash address of each loaded section, by object and section
+    let mut bases: HashMap<(usize, usize), u32> = HashMap::new();
+
+    for (i, object) in objects.iter().enumerate() {
+        for (j, section) in object.sections.iter().enumerate() {
+            if section.flags & SHF_ALLOC == 0 || section.size == 0 {
+                continue;
+            }
+            if !is_flash_section(section) {
+                return Err(Error::Format(format!(
+                    "{}: section {} isn't code, only code and progmem can be loaded",
+                    object.name, section.name
+                )));
+            }
+            let align = section.align.max(2) as usize;
+            flash.resize(flash.len().div_ceil(align) * align, 0);
+            bases.insert((i, j), flash.len() as u32);
+            flash.extend_from_slice(object.bytes(section)?);
+        }
+    }
+
+    let mut symbols = HashMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        for symbol in object.symbols()? {
+            if symbol.bind != STB_GLOBAL && symbol.bind != STB_WEAK {
+                continue;
+            }
+            let value = match symbol.section {
+                SHN_UNDEF => continue,
+                SHN_ABS => symbol.value,
+                section => match bases.get(&(i, section as usize)) {
+                    Some(base) => base + symbol.value,
+                    None => continue,
+                },
+            };
+            if symbols.contains_key(&symbol.name) && symbol.bind == STB_GLOBAL {
+                return Err(Error::Format(format!(
+                    "{}: {} is defined more than once",
+                    object.name, symbol.name
+                )));
+            }
+            symbols.entry(symbol.name).or_insert(value);
+        }
+    }
+
+    for (i, object) in objects.iter().enumerate() {
+        for section in &object.sections {
+            if section.kind != SHT_RELA && section.kind != SHT_REL {
+                continue;
+            }
+            // Relocations for sections that aren't loaded, like the
+            // debug information, don't matter
+            let target = match bases.get(&(i, section.info)) {
+                Some(base) => *base,
+                None => continue,
+            };
+            if section.kind == SHT_REL {
+                return Err(Error::Format(format!(
+                    "{}: REL relocations aren't supported",
+                    object.name
+                )));
+            }
+            let object_symbols = object.symbols_in(section.link)?;
+            let data = object.bytes(section)?;
+            for entry in data.chunks_exact(12) {
+                let offset = read_u32(entry, 0)?;
+                let info = read_u32(entry, 4)?;
+                let addend = read_u32(entry, 8)? as i32;
+                let symbol = object_symbols.get((info >> 8) as usize).ok_or_else(|| {
+                    Error::Format(format!("{}: bad relocation symbol", object.name))
+                })?;
+                let value = match symbol.section {
+                    // Symbol zero is the null symbol, the addend is the
+                    // whole value
+                    _ if info >> 8 == 0 => 0,
+                    SHN_UNDEF => *symbols
+                        .get(&symbol.name)
+                        .ok_or_else(|| Error::UndefinedSymbol(symbol.name.clone()))?,
+                    SHN_ABS => symbol.value,
+                    section => {
+                        bases.get(&(i, section as usize)).ok_or_else(|| {
+                            Error::Format(format!(
+                                "{}: relocation against a section that isn't loaded",
+                                object.name
+                            ))
+                        })? + symbol.value
+                    }
+                };
+                relocate(
+                    &mut flash,
+                    target + offset,
+                    info as u8,
+                    value.wrapping_add(addend as u32),
+                )?;
+            }
+        }
+    }
+
+    Ok(Program { flash, symbols })
+}
+
+/// Check if a section goes in flash
+fn is_flash_section(section: &Section) -> bool {
+    section.kind != SHT_NOBITS
+        && (section.flags & SHF_EXECINSTR != 0 || section.name.starts_with(".progmem"))
+}
+
+/// Apply one relocation
+///
+/// address is the flash byte address being relocated, value is the
+/// symbol address plus the addend.
+fn relocate(flash: &mut [u8], address: u32, kind: u8, value: u32) -> Result<(), Error> {
+    let out_of_range = Error::RelocationOutOfRange(address);
+    let i = address as usize;
+    if i + 2 > flash.len() {
+        return Err(out_of_range);
+    }
+    let word = u16::from_le_bytes([flash[i], flash[i + 1]]);
+    // The offset of a relative jump, in words from the next instruction
+    let relative = || (value as i32 - (address as i32 + 2)) >> 1;
+
+    let word = match kind {
+        R_AVR_NONE => return Ok(()),
+        R_AVR_32 => {
+            if i + 4 > flash.len() {
+                return Err(out_of_range);
+            }
+            flash[i..i + 4].copy_from_slice(&value.to_le_bytes());
+            return Ok(());
+        }
+        R_AVR_7_PCREL => {
+            let offset = relative();
+            if !(-64..64).contains(&offset) {
+                return Err(out_of_range);
+            }
+            (word & 0xFC07) | (((offset as u16) & 0x7F) << 3)
+        }
+        // Relative jumps wrap around at the end of flash, so any
+        // offset reaches
+        R_AVR_13_PCREL => (word & 0xF000) | (relative() as u16 & 0x0FFF),
+        R_AVR_16 => value as u16,
+        R_AVR_16_PM => (value >> 1) as u16,
+        R_AVR_LO8_LDI => ldi_immediate(word, value as u8),
+        R_AVR_HI8_LDI => ldi_immediate(word, (value >> 8) as u8),
+        R_AVR_LO8_LDI_PM => ldi_immediate(word, (value >> 1) as u8),
+        R_AVR_HI8_LDI_PM => ldi_immediate(word, (value >> 9) as u8),
+        R_AVR_CALL => {
+            if i + 4 > flash.len() {
+                return Err(out_of_range);
+            }
+            let target = value >> 1;
+            let high = ((((target >> 16) & 0x3E) << 3) | ((target >> 16) & 0x01)) as u16;
+            flash[i + 2..i + 4].copy_from_slice(&(target as u16).to_le_bytes());
+            (word & 0xFE0E) | high
+        }
+        _ => return Err(Error::UnsupportedRelocation(kind)),
+    };
+    flash[i..i + 2].copy_from_slice(&word.to_le_bytes());
+    Ok(())
+}
+
+/// Put an 8-bit immediate into an ldi-style instruction
+fn ldi_immediate(word: u16, value: u8) -> u16 {
+    let value = value as u16;
+    (word & 0xF0F0) | (value & 0x0F) | ((value & 0xF0) << 4)
+}
+
+/// Split an ar archive into its members
+///
+/// Returns the name and contents of each member.  The GNU symbol
+/// table and long name table members aren't returned.
+fn archive_members(bytes: &[u8]) -> Result<Vec<(String, &[u8])>, Error> {
+    let truncated = || Error::Format(String::from("truncated ar archive"));
+    let mut members = Vec::new();
+    let mut long_names: &[u8] = &[];
+    let mut offset = AR_MAGIC.len();
+
+    while offset < bytes.len() {
+        let header = bytes
+            .get(offset..offset + AR_HEADER_SIZE)
+            .ok_or_else(truncated)?;
+        let name = String::from_utf8_lossy(&header[0..16])
+            .trim_end()
+            .to_string();
+        let size: usize = String::from_utf8_lossy(&header[48..58])
+            .trim()
+            .parse()
+            .map_err(|_| Error::Format(String::from("bad ar member size")))?;
+        let start = offset + AR_HEADER_SIZE;
+        let data = bytes.get(start..start + size).ok_or_else(truncated)?;
+        // Members start on even offsets
+        offset = start + size + size % 2;
+
+        if name == "/" || name == "/SYM64/" {
+            continue;
+        } else if name == "//" {
+            long_names = data;
+            continue;
+        }
+
+        let name = match name.strip_prefix('/').map(str::parse::<usize>) {
+            Some(Ok(index)) => {
+                let rest = long_names.get(index..).ok_or_else(truncated)?;
+                let end = rest.iter().position(|b| *b == b'/').unwrap_or(rest.len());
+                String::from_utf8_lossy(&rest[..end]).to_string()
+            }
+            _ => name.trim_end_matches('/').to_string(),
+        };
+        members.push((name, data));
+    }
+
+    Ok(members)
+}
+
+impl<'a> Object<'a> {
+    /// Parse the header and section table of an ELF object file
+    fn parse(name: String, data: &'a [u8]) -> Result<Self, Error> {
+        let bad = |reason: &str| Error::Format(format!("{}: {}", name, reason));
+
+        if data.len() < 52 || !data.starts_with(ELF_MAGIC) {
+            return Err(bad("not an ELF file"));
+        }
+        if data[4] != 1 || data[5] != 1 {
+            return Err(bad("not a 32-bit little-endian ELF file"));
+        }
+        if read_u16(data, 18)? != EM_AVR {
+            return Err(bad("not an AVR ELF file"));
+        }
+        if read_u16(data, 16)? != ET_REL {
+            return Err(bad("not a relocatable object file"));
+        }
+
+        let table = read_u32(data, 32)? as usize;
+        let entry_size = read_u16(data, 46)? as usize;
+        let count = read_u16(data, 48)? as usize;
+        let names = read_u16(data, 50)? as usize;
+
+        let mut sections = Vec::with_capacity(count);
+        let mut name_offsets = Vec::with_capacity(count);
+        for i in 0..count {
+            let header = table + i * entry_size;
+            name_offsets.push(read_u32(data, header)? as usize);
+            sections.push(Section {
+                name: String::new(),
+                kind: read_u32(data, header + 4)?,
+                flags: read_u32(data, header + 8)?,
+                offset: read_u32(data, header + 16)? as usize,
+                size: read_u32(data, header + 20)? as usize,
+                link: read_u32(data, header + 24)? as usize,
+                info: read_u32(data, header + 28)? as usize,
+                align: read_u32(data, header + 32)?,
+            });
+        }
+
+        let mut object = Object {
+            name,
+            data,
+            sections,
+        };
+        if names != 0 {
+            let table = object
+                .sections
+                .get(names)
+                .ok_or_else(|| Error::Format(format!("{}: bad section name table", object.name)))?;
+            let names: Vec<String> = name_offsets
+                .iter()
+                .map(|offset| object.string(table, *offset))
+                .collect::<Result<_, _>>()?;
+            for (section, name) in object.sections.iter_mut().zip(names) {
+                section.name = name;
+            }
+        }
+
+        Ok(object)
+    }
+
+    /// The contents of a section
+    fn bytes(&self, section: &Section) -> Result<&'a [u8], Error> {
+        if section.kind == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        self.data
+            .get(section.offset..section.offset + section.size)
+            .ok_or_else(|| Error::Format(format!("{}: truncated section", self.name)))
+    }
+
+    /// Read a null-terminated string out of a string table section
+    fn string(&self, table: &Section, offset: usize) -> Result<String, Error> {
+        let bytes = self.bytes(table)?;
+        let rest = bytes
+            .get(offset..)
+            .ok_or_else(|| Error::Format(format!("{}: bad string offset", self.name)))?;
+        let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+        Ok(String::from_utf8_lossy(&rest[..end]).to_string())
+    }
+
+    /// The symbols in every symbol table
+    fn symbols(&self) -> Result<Vec<Symbol>, Error> {
+        let mut symbols = Vec::new();
+        for (i, section) in self.sections.iter().enumerate() {
+            if section.kind == SHT_SYMTAB {
+                symbols.extend(self.symbols_in(i)?);
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// The symbols in a symbol table section, in table order
+    fn symbols_in(&self, index: usize) -> Result<Vec<Symbol>, Error> {
+        let bad = || Error::Format(format!("{}: bad symbol table", self.name));
+        let table = self.sections.get(index).ok_or_else(bad)?;
+        let names = self.sections.get(table.link).ok_or_else(bad)?;
+
+        self.bytes(table)?
+            .chunks_exact(16)
+            .map(|entry| {
+                Ok(Symbol {
+                    name: self.string(names, read_u32(entry, 0)? as usize)?,
+                    value: read_u32(entry, 4)?,
+                    bind: entry[12] >> 4,
+                    section: read_u16(entry, 14)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read a little-endian u16
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Error> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| Error::Format(String::from("truncated ELF file")))
+}
+
+/// Read a little-endian u32
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::Format(String::from("truncated ELF file")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+
+    /// ret
+    const RET: u16 = 0x9508;
+
+    /// Build an AVR relocatable ELF file with one .text section
+    ///
+    /// Each symbol is global, defined at an offset in .text or
+    /// undefined if the offset is None.  Each relocation is an offset
+    /// in .text, a symbol table index, a type and an addend, symbol
+    /// zero is the null symbol.
+    fn elf(
+        text: &[u16],
+        symbols: &[(&str, Option<u32>)],
+        relocations: &[(u32, u32, u8, i32)],
+    ) -> Vec<u8> {
+        let text: Vec<u8> = text.iter().flat_map(|w| w.to_le_bytes()).collect();
+
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 16];
+        for (name, value) in symbols {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&value.unwrap_or(0).to_le_bytes());
+            symtab.extend_from_slice(&0u32.to_le_bytes());
+            symtab.extend_from_slice(&[STB_GLOBAL << 4, 0]);
+            symtab.extend_from_slice(&(value.is_some() as u16).to_le_bytes());
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let mut rela = Vec::new();
+        for (offset, symbol, kind, addend) in relocations {
+            rela.extend_from_slice(&offset.to_le_bytes());
+            rela.extend_from_slice(&((symbol << 8) | *kind as u32).to_le_bytes());
+            rela.extend_from_slice(&addend.to_le_bytes());
+        }
+
+        let shstrtab = b"\0.text\0.symtab\0.strtab\0.rela.text\0.shstrtab\0";
+
+        let mut file = vec![0; 52];
+        let place = |file: &mut Vec<u8>, bytes: &[u8]| {
+            file.resize(file.len().div_ceil(4) * 4, 0);
+            let offset = file.len() as u32;
+            file.extend_from_slice(bytes);
+            (offset, bytes.len() as u32)
+        };
+        let text = place(&mut file, &text);
+        let symtab = place(&mut file, &symtab);
+        let strtab = place(&mut file, &strtab);
+        let rela = place(&mut file, &rela);
+        let shstrtab = place(&mut file, shstrtab);
+        let table = place(&mut file, &[]).0;
+
+        // name, type, flags, (offset, size), link, info, align, entry size
+        let headers = [
+            (0, 0, 0, (0, 0), 0, 0, 0, 0),
+            (1, 1, SHF_ALLOC | SHF_EXECINSTR, text, 0, 0, 2, 0),
+            (7, SHT_SYMTAB, 0, symtab, 3, 1, 4, 16),
+            (15, 3, 0, strtab, 0, 0, 1, 0),
+            (23, SHT_RELA, 0, rela, 2, 1, 4, 12),
+            (34, 3, 0, shstrtab, 0, 0, 1, 0),
+        ];
+        for (name, kind, flags, (offset, size), link, info, align, entry_size) in headers {
+            for field in [
+                name, kind, flags, 0, offset, size, link, info, align, entry_size,
+            ] {
+                file.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        file[0..7].copy_from_slice(b"\x7fELF\x01\x01\x01");
+        file[16..18].copy_from_slice(&ET_REL.to_le_bytes());
+        file[18..20].copy_from_slice(&EM_AVR.to_le_bytes());
+        file[20..24].copy_from_slice(&1u32.to_le_bytes());
+        file[32..36].copy_from_slice(&table.to_le_bytes());
+        file[40..42].copy_from_slice(&52u16.to_le_bytes());
+        file[46..48].copy_from_slice(&40u16.to_le_bytes());
+        file[48..50].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+        file[50..52].copy_from_slice(&5u16.to_le_bytes());
+        file
+    }
+
+    /// Build an ar archive
+    fn archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut file = AR_MAGIC.to_vec();
+        for (name, data) in members {
+            let header = format!(
+                "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                format!("{}/", name),
+                0,
+                0,
+                0,
+                644,
+                data.len()
+            );
+            file.extend_from_slice(header.as_bytes());
+            file.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                file.push(b'\n');
+            }
+        }
+        file
+    }
+
+    /// An object with main calling an undefined helper
+    fn main_object() -> Vec<u8> {
+        elf(
+            &[
+                0xD000, // rcall helper
+                RET,
+            ],
+            &[("main", Some(0)), ("helper", None)],
+            &[(0, 2, R_AVR_13_PCREL, 0)],
+        )
+    }
+
+    /// An object with a helper that returns a constant
+    fn helper_object() -> Vec<u8> {
+        elf(
+            &[
+                0x9400, // com r0, padding so helper isn't at 0
+                0xE080, // ldi r24, lo8(value)
+                0xE090, // ldi r25, hi8(value)
+                RET,
+            ],
+            &[("helper", Some(2))],
+            &[(2, 0, R_AVR_LO8_LDI, 0x1234), (4, 0, R_AVR_HI8_LDI, 0x1234)],
+        )
+    }
+
+    #[test]
+    fn load_elf_relocates_calls() {
+        let program = Program::load(&elf(
+            &[
+                0xD000, // rcall helper
+                RET, 0xE087, // helper: ldi r24, 7
+                RET,
+            ],
+            &[("main", Some(0)), ("helper", Some(4))],
+            &[(0, 2, R_AVR_13_PCREL, 0)],
+        ))
+        .unwrap();
+
+        assert_eq!(program.symbol("main").unwrap(), 0);
+        assert_eq!(program.symbol("helper").unwrap(), 4);
+        assert_eq!(&program.flash()[0..2], &0xD001u16.to_le_bytes());
+
+        let mut cpu = Cpu::with_program(&program).unwrap();
+        assert_eq!(
+            cpu.call(program.symbol("main").unwrap(), &[]).unwrap() & 0xFF,
+            7
+        );
+    }
+
+    #[test]
+    fn load_elf_relocates_branches() {
+        let program = Program::load(&elf(
+            &[
+                0xF001, // breq done
+                0xE081, // ldi r24, 1
+                RET,    // done:
+            ],
+            &[("done", Some(4))],
+            &[(0, 1, R_AVR_7_PCREL, 0)],
+        ))
+        .unwrap();
+
+        assert_eq!(&program.flash()[0..2], &0xF009u16.to_le_bytes());
+    }
+
+    #[test]
+    fn load_archive_links_members() {
+        let main = main_object();
+        let helper = helper_object();
+        let program = Program::load(&archive(&[("main.o", &main), ("helper.o", &helper)])).unwrap();
+
+        assert_eq!(program.symbol("helper").unwrap(), 6);
+
+        let mut cpu = Cpu::with_program(&program).unwrap();
+        assert_eq!(
+            cpu.call(program.symbol("main").unwrap(), &[]).unwrap(),
+            0x1234
+        );
+    }
+
+    #[test]
+    fn load_fails_with_undefined_symbol() {
+        assert!(matches!(
+            Program::load(&main_object()),
+            Err(Error::UndefinedSymbol(name)) if name == "helper"
+        ));
+    }
+
+    #[test]
+    fn load_fails_with_unknown_file() {
+        assert!(matches!(
+            Program::load(b"not an object file"),
+            Err(Error::Format(_))
+        ));
+    }
+}
@@ -0,0 +1,308 @@
+//! Test the basino assembly routines in the emulator
+//!
+//! These load ../basino/libbasino.a, or the file named by the
+//! BASINO_LIB environment variable.  Not every machine has the AVR
+//! toolchain to build it, so the tests are ignored by default.  make
+//! emu-test builds the library and runs them, and they fail if it
+//! isn't there.
+
+use basino_emu::{library_path, Cpu, Program};
+
+/// Where the tests put the stack struct
+const STACK: u16 = 0x0100;
+/// Where the tests put the queue struct
+const QUEUE: u16 = 0x0110;
+/// Where routines that return a status through a pointer write it
+const STATUS: u16 = 0x0120;
+/// Where the tests put the stack or queue array
+const ARRAY: u16 = 0x0200;
+
+/// The stack struct field offsets, from stack.S
+const STACK_TOP_SENTINEL: u16 = 2;
+const STACK_BOTTOM: u16 = 4;
+const STACK_TOP: u16 = 6;
+
+/// The queue struct field offsets, from queue.S
+const QUEUE_START: u16 = 2;
+const QUEUE_END: u16 = 4;
+const QUEUE_HEAD: u16 = 6;
+const QUEUE_LAST_HEAD: u16 = 8;
+const QUEUE_TAIL: u16 = 10;
+
+/// libbasino loaded into an emulated ATmega328p
+struct Basino {
+    program: Program,
+    cpu: Cpu,
+}
+
+impl Basino {
+    /// Load libbasino, panics if it hasn't been built
+    fn load() -> Self {
+        let path = library_path();
+        if !path.exists() {
+            panic!(
+                "{} doesn't exist, build it with make -C basino",
+                path.display()
+            );
+        }
+
+        let program = Program::load_file(&path)
+            .unwrap_or_else(|e| panic!("couldn't load {}: {}", path.display(), e));
+        let cpu = Cpu::with_program(&program).unwrap();
+        Basino { program, cpu }
+    }
+
+    /// Call a routine, panicking if the emulator fails
+    fn call(&mut self, name: &str, args: &[u16]) -> u16 {
+        let address = self.program.symbol(name).unwrap();
+        self.cpu
+            .call(address, args)
+            .unwrap_or_else(|e| panic!("{} failed: {}", name, e))
+    }
+
+    /// Call a routine that returns an 8-bit value
+    fn call_u8(&mut self, name: &str, args: &[u16]) -> u8 {
+        self.call(name, args) as u8
+    }
+
+    /// Call a routine that returns a value and writes a status to STATUS
+    ///
+    /// Returns the value and the status.
+    fn call_with_status(&mut self, name: &str, object: u16) -> (u8, u8) {
+        self.cpu.write(STATUS, 0xFF).unwrap();
+        let value = self.call_u8(name, &[object, STATUS]);
+        (value, self.cpu.read(STATUS).unwrap())
+    }
+
+    /// Read a pointer field
+    fn field(&self, object: u16, offset: u16) -> u16 {
+        self.cpu.read_u16(object + offset).unwrap()
+    }
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn add_returns_carry_in_high_byte() {
+    let mut basino = Basino::load();
+
+    assert_eq!(basino.call("basino_add", &[1, 2]), 3);
+    assert_eq!(basino.call("basino_add", &[200, 100]), 0x012C);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn address_add_writes_carry() {
+    let mut basino = Basino::load();
+
+    assert_eq!(
+        basino.call("basino_address_add", &[0x1234, 0x0101, STATUS]),
+        0x1335
+    );
+    assert_eq!(basino.cpu.read(STATUS).unwrap(), 0);
+    assert_eq!(
+        basino.call("basino_address_add", &[0xFFFF, 0x0002, STATUS]),
+        0x0001
+    );
+    assert_eq!(basino.cpu.read(STATUS).unwrap(), 1);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn gt_compares_words() {
+    let mut basino = Basino::load();
+
+    assert_eq!(basino.call_u8("basino_gt", &[0x0100, 0x00FF]), 1);
+    assert_eq!(basino.call_u8("basino_gt", &[0x00FF, 0x0100]), 0);
+    assert_eq!(basino.call_u8("basino_gt", &[0x1234, 0x1234]), 0);
+    assert_eq!(basino.call_u8("basino_gt_eq", &[0x1234, 0x1234]), 1);
+    assert_eq!(basino.call_u8("basino_gt_eq", &[0x1233, 0x1234]), 0);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn progmem_read_reads_flash() {
+    let mut basino = Basino::load();
+
+    // basino_start is a single ret, 0x9508
+    let address = basino.program.symbol("basino_start").unwrap() as u16;
+    assert_eq!(basino.call_u8("basino_progmem_read", &[address]), 0x08);
+    assert_eq!(basino.call_u8("basino_progmem_read", &[address + 1]), 0x95);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn sleep_idle_clears_sleep_mode() {
+    let mut basino = Basino::load();
+
+    basino.call("basino_sleep_idle", &[]);
+
+    // SMCR is I/O register 0x33
+    assert_eq!(basino.cpu.read(0x53).unwrap(), 0);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn stack_init_sets_pointers() {
+    let mut basino = Basino::load();
+
+    assert_eq!(
+        basino.call_u8("basino_stack_init", &[STACK, ARRAY + 3, ARRAY]),
+        0
+    );
+    assert_eq!(basino.field(STACK, STACK_BOTTOM), ARRAY);
+    assert_eq!(basino.field(STACK, STACK_TOP), ARRAY + 3);
+    assert_eq!(basino.field(STACK, STACK_TOP_SENTINEL), ARRAY + 3);
+    assert_eq!(
+        basino.call("basino_get_basino_stack_top", &[STACK]),
+        ARRAY + 3
+    );
+    assert_eq!(
+        basino.call("basino_get_basino_stack_bottom", &[STACK]),
+        ARRAY
+    );
+    assert_eq!(
+        basino.call("basino_get_basino_stack_top_sentinel", &[STACK]),
+        ARRAY + 3
+    );
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn stack_init_checks_arguments() {
+    let mut basino = Basino::load();
+
+    assert_eq!(
+        basino.call_u8("basino_stack_init", &[0, ARRAY + 3, ARRAY]),
+        1
+    );
+    assert_eq!(
+        basino.call_u8("basino_stack_init", &[STACK, ARRAY, ARRAY]),
+        2
+    );
+    assert_eq!(
+        basino.call_u8("basino_stack_init", &[STACK, ARRAY, ARRAY + 3]),
+        2
+    );
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn stack_push_pop() {
+    let mut basino = Basino::load();
+    basino.call("basino_stack_init", &[STACK, ARRAY + 3, ARRAY]);
+
+    for (i, value) in [10, 20, 30].iter().enumerate() {
+        assert_eq!(basino.call_u8("basino_stack_push", &[STACK, *value]), 0);
+        assert_eq!(basino.field(STACK, STACK_TOP), ARRAY + 2 - i as u16);
+        assert_eq!(basino.cpu.read(ARRAY + 2 - i as u16).unwrap(), *value as u8);
+    }
+    assert_eq!(basino.call_u8("basino_stack_push", &[STACK, 40]), 2);
+    assert_eq!(basino.field(STACK, STACK_TOP), ARRAY);
+
+    for value in [30, 20, 10] {
+        assert_eq!(
+            basino.call_with_status("basino_stack_pop", STACK),
+            (value, 0)
+        );
+    }
+    assert_eq!(basino.call_with_status("basino_stack_pop", STACK).1, 2);
+    assert_eq!(basino.field(STACK, STACK_TOP), ARRAY + 3);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn stack_null_pointer() {
+    let mut basino = Basino::load();
+
+    assert_eq!(basino.call_u8("basino_stack_push", &[0, 1]), 1);
+    assert_eq!(basino.call_with_status("basino_stack_pop", 0).1, 1);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn queue_init_sets_pointers() {
+    let mut basino = Basino::load();
+
+    assert_eq!(
+        basino.call_u8("basino_queue_init", &[QUEUE, ARRAY, ARRAY + 3]),
+        0
+    );
+    assert_eq!(basino.field(QUEUE, QUEUE_START), ARRAY);
+    assert_eq!(basino.field(QUEUE, QUEUE_END), ARRAY + 3);
+    assert_eq!(basino.field(QUEUE, QUEUE_HEAD), ARRAY);
+    assert_eq!(basino.field(QUEUE, QUEUE_LAST_HEAD), ARRAY + 3);
+    assert_eq!(basino.field(QUEUE, QUEUE_TAIL), ARRAY);
+
+    for (name, expected) in [
+        ("basino_queue_get_queue_start", ARRAY),
+        ("basino_queue_get_queue_end", ARRAY + 3),
+        ("basino_queue_get_head", ARRAY),
+        ("basino_queue_get_last_head", ARRAY + 3),
+        ("basino_queue_get_tail", ARRAY),
+    ] {
+        basino.cpu.write(STATUS, 0xFF).unwrap();
+        assert_eq!(basino.call(name, &[QUEUE, STATUS]), expected, "{}", name);
+        assert_eq!(basino.cpu.read(STATUS).unwrap(), 0, "{}", name);
+    }
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn queue_put_get() {
+    let mut basino = Basino::load();
+    basino.call("basino_queue_init", &[QUEUE, ARRAY, ARRAY + 3]);
+
+    for value in [10, 20, 30] {
+        assert_eq!(basino.call_u8("basino_queue_put", &[QUEUE, value]), 0);
+    }
+    assert_eq!(basino.call_u8("basino_queue_put", &[QUEUE, 40]), 2);
+
+    assert_eq!(basino.call_with_status("basino_queue_get", QUEUE), (10, 0));
+    assert_eq!(basino.field(QUEUE, QUEUE_LAST_HEAD), ARRAY);
+    assert_eq!(basino.call_u8("basino_queue_put", &[QUEUE, 40]), 0);
+    assert_eq!(basino.field(QUEUE, QUEUE_TAIL), ARRAY);
+    assert_eq!(basino.call_u8("basino_queue_put", &[QUEUE, 50]), 2);
+
+    for value in [20, 30, 40] {
+        assert_eq!(
+            basino.call_with_status("basino_queue_get", QUEUE),
+            (value, 0)
+        );
+    }
+    assert_eq!(basino.field(QUEUE, QUEUE_HEAD), ARRAY);
+    assert_eq!(basino.call_with_status("basino_queue_get", QUEUE).1, 2);
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn queue_wraps_around() {
+    let mut basino = Basino::load();
+    basino.call("basino_queue_init", &[QUEUE, ARRAY, ARRAY + 3]);
+
+    for value in 0..20 {
+        assert_eq!(basino.call_u8("basino_queue_put", &[QUEUE, value]), 0);
+        assert_eq!(basino.call_u8("basino_queue_put", &[QUEUE, value + 100]), 0);
+        assert_eq!(
+            basino.call_with_status("basino_queue_get", QUEUE),
+            (value as u8, 0)
+        );
+        assert_eq!(
+            basino.call_with_status("basino_queue_get", QUEUE),
+            (value as u8 + 100, 0)
+        );
+        assert_eq!(basino.call_with_status("basino_queue_get", QUEUE).1, 2);
+    }
+}
+
+#[test]
+#[ignore = "needs libbasino.a, run make emu-test"]
+fn queue_null_pointer() {
+    let mut basino = Basino::load();
+
+    assert_eq!(
+        basino.call_u8("basino_queue_init", &[0, ARRAY, ARRAY + 3]),
+        1
+    );
+    assert_eq!(basino.call_u8("basino_queue_put", &[0, 1]), 1);
+    assert_eq!(basino.call_with_status("basino_queue_get", 0).1, 1);
+}
@@ -0,0 +1,304 @@
+//! Compare benchmark cycle counts against a baseline
+//!
+//! The rust-basino bench example prints a row for each libbasino
+//! routine it times: BENCH, the name of the benchmark and the number
+//! of cycles.  A baseline file holds rows in the same format, so the
+//! serial output of a run on a board can be saved and used as one.
+//! Lines that aren't rows are ignored, so comments can start with #.
+//!
+//! A benchmark regressed if it takes more than the threshold percent
+//! more cycles than the baseline.  A benchmark in the baseline that's
+//! missing from the run also counts as a regression, so a rename
+//! doesn't quietly drop a routine from the comparison.
+#![warn(missing_docs)]
+
+use std::{fmt, fs, io, path::Path};
+
+use crate::{build_suite, run_with, strip_line, Config, Error, Summary};
+
+/// The prefix of a benchmark row
+///
+/// This must match rust_basino::bench::ROW_PREFIX.
+pub const ROW_PREFIX: &str = "BENCH";
+
+/// The name of the benchmark example
+pub const BENCH_EXAMPLE: &str = "bench";
+
+/// The default regression threshold, in percent
+pub const DEFAULT_THRESHOLD: u32 = 5;
+
+/// A benchmark and its cycle count
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Benchmark {
+    /// The name of the benchmark, the routine and sometimes the case
+    pub name: String,
+    /// The number of cycles
+    pub cycles: u32,
+}
+
+/// Parse a benchmark row, returns None if the line isn't one
+pub fn parse_row(line: &str) -> Option<Benchmark> {
+    let mut words = line.split_whitespace();
+    if words.next()? != ROW_PREFIX {
+        return None;
+    }
+    let name = words.next()?.to_string();
+    let cycles = words.next()?.parse().ok()?;
+
+    Some(Benchmark { name, cycles })
+}
+
+/// Parse the benchmark rows in some output
+pub fn parse(output: &str) -> Vec<Benchmark> {
+    output
+        .lines()
+        .filter_map(|line| parse_row(&strip_line(line)))
+        .collect()
+}
+
+/// Read the benchmark rows from a file
+pub fn read_file(path: &Path) -> io::Result<Vec<Benchmark>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+/// Write benchmarks to a baseline file
+pub fn write_baseline(path: &Path, benchmarks: &[Benchmark]) -> io::Result<()> {
+    let mut contents = String::from("# basino cycle counts, written by basino-runner\n");
+    for benchmark in benchmarks {
+        contents.push_str(&format!(
+            "{} {} {}\n",
+            ROW_PREFIX, benchmark.name, benchmark.cycles
+        ));
+    }
+
+    fs::write(path, contents)
+}
+
+/// Build the bench example in a crate and run it
+///
+/// Returns the summary of the run, which tells whether it finished,
+/// and the benchmark rows it printed.
+pub fn run_bench(config: &Config, crate_dir: &Path) -> Result<(Summary, Vec<Benchmark>), Error> {
+    let elf = build_suite(crate_dir, BENCH_EXAMPLE)?;
+    let mut benchmarks = Vec::new();
+
+    let summary = run_with(
+        &Config {
+            elf,
+            ..config.clone()
+        },
+        |line| benchmarks.extend(parse_row(line)),
+    )?;
+
+    Ok((summary, benchmarks))
+}
+
+/// How a benchmark changed from the baseline
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// The same number of cycles
+    Same,
+    /// Fewer cycles
+    Faster,
+    /// More cycles, but within the threshold
+    Slower,
+    /// More cycles than the threshold allows
+    Regression,
+    /// The benchmark isn't in the baseline
+    New,
+    /// The benchmark is in the baseline but not in the run
+    Missing,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Change::Same => "same",
+            Change::Faster => "faster",
+            Change::Slower => "slower",
+            Change::Regression => "REGRESSION",
+            Change::New => "new",
+            Change::Missing => "MISSING",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A benchmark compared against the baseline
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Comparison {
+    /// The name of the benchmark
+    pub name: String,
+    /// The cycles in the baseline
+    pub baseline: Option<u32>,
+    /// The cycles in the run
+    pub current: Option<u32>,
+    /// How it changed
+    pub change: Change,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cycles = |cycles: Option<u32>| cycles.map_or("-".to_string(), |c| c.to_string());
+
+        write!(
+            f,
+            "{:<38} {:>8} {:>8}  {}",
+            self.name,
+            cycles(self.baseline),
+            cycles(self.current),
+            self.change
+        )?;
+        if let (Some(baseline), Some(current)) = (self.baseline, self.current) {
+            if baseline != 0 && baseline != current {
+                let percent = (current as f64 - baseline as f64) * 100.0 / baseline as f64;
+                write!(f, " {:+.1}%", percent)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Compare a run against the baseline
+///
+/// threshold is the percentage of extra cycles allowed before a
+/// benchmark counts as a regression.  The comparisons are in the
+/// order of the run, followed by the benchmarks that are missing from
+/// it.
+pub fn compare(baseline: &[Benchmark], current: &[Benchmark], threshold: u32) -> Vec<Comparison> {
+    let find = |benchmarks: &[Benchmark], name: &str| {
+        benchmarks.iter().find(|b| b.name == name).map(|b| b.cycles)
+    };
+
+    let mut comparisons: Vec<Comparison> = current
+        .iter()
+        .map(|benchmark| {
+            let base = find(baseline, &benchmark.name);
+            let cycles = benchmark.cycles;
+            let change = match base {
+                None => Change::New,
+                Some(base) if cycles == base => Change::Same,
+                Some(base) if cycles < base => Change::Faster,
+                Some(base) if cycles as u64 * 100 > base as u64 * (100 + threshold as u64) => {
+                    Change::Regression
+                }
+                Some(_) => Change::Slower,
+            };
+
+            Comparison {
+                name: benchmark.name.clone(),
+                baseline: base,
+                current: Some(cycles),
+                change,
+            }
+        })
+        .collect();
+
+    comparisons.extend(
+        baseline
+            .iter()
+            .filter(|b| find(current, &b.name).is_none())
+            .map(|b| Comparison {
+                name: b.name.clone(),
+                baseline: Some(b.cycles),
+                current: None,
+                change: Change::Missing,
+            }),
+    );
+
+    comparisons
+}
+
+/// True if any benchmark regressed or went missing
+pub fn has_regressions(comparisons: &[Comparison]) -> bool {
+    comparisons
+        .iter()
+        .any(|c| matches!(c.change, Change::Regression | Change::Missing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn benchmark(name: &str, cycles: u32) -> Benchmark {
+        Benchmark {
+            name: name.to_string(),
+            cycles,
+        }
+    }
+
+    #[test]
+    fn parse_row_works() {
+        assert_eq!(
+            parse_row("BENCH basino_stack_push                     23"),
+            Some(benchmark("basino_stack_push", 23))
+        );
+        assert_eq!(parse_row("# a comment"), None);
+        assert_eq!(parse_row("BENCH basino_add"), None);
+        assert_eq!(parse_row("BENCH basino_add many"), None);
+        assert_eq!(parse_row("BENCHMARK basino_add 5"), None);
+    }
+
+    #[test]
+    fn parse_skips_other_lines() {
+        let output = "starting\r\n\x1b[32mBENCH basino_add 5\x1b[0m\r\nEND OF TESTS\r\n";
+
+        assert_eq!(parse(output), vec![benchmark("basino_add", 5)]);
+    }
+
+    #[test]
+    fn compare_works() {
+        let baseline = [
+            benchmark("same", 100),
+            benchmark("faster", 100),
+            benchmark("slower", 100),
+            benchmark("regression", 100),
+            benchmark("missing", 100),
+        ];
+        let current = [
+            benchmark("same", 100),
+            benchmark("faster", 90),
+            benchmark("slower", 105),
+            benchmark("regression", 106),
+            benchmark("new", 10),
+        ];
+
+        let changes: Vec<(String, Change)> = compare(&baseline, &current, 5)
+            .into_iter()
+            .map(|c| (c.name, c.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("same".to_string(), Change::Same),
+                ("faster".to_string(), Change::Faster),
+                ("slower".to_string(), Change::Slower),
+                ("regression".to_string(), Change::Regression),
+                ("new".to_string(), Change::New),
+                ("missing".to_string(), Change::Missing),
+            ]
+        );
+    }
+
+    #[test]
+    fn has_regressions_works() {
+        let baseline = [benchmark("a", 100), benchmark("b", 100)];
+
+        let faster = compare(&baseline, &[benchmark("a", 90), benchmark("b", 100)], 5);
+        assert!(!has_regressions(&faster));
+
+        let slower = compare(&baseline, &[benchmark("a", 110), benchmark("b", 100)], 5);
+        assert!(has_regressions(&slower));
+
+        let missing = compare(&baseline, &[benchmark("a", 100)], 5);
+        assert!(has_regressions(&missing));
+
+        let new = compare(
+            &baseline,
+            &[benchmark("a", 100), benchmark("b", 100), benchmark("c", 1)],
+            5,
+        );
+        assert!(!has_regressions(&new));
+    }
+}
@@ -0,0 +1,751 @@
+//! Run the basino on-device tests on the host
+//!
+//! The rust-basino test suites print a SUCCESS, FAILURE or SKIPPED
+//! line for each check over USART0, and an end-of-tests marker when
+//! they are done.  Firmware built with the tap feature prints Test
+//! Anything Protocol lines instead, the runner understands both.
+//!
+//! This crate launches an ELF file under simavr or qemu-system-avr,
+//! reads the serial output, and turns it into a summary and an exit
+//! code.
+//!
+//! Each test suite is also an example binary in rust-basino/examples,
+//! they don't all fit in flash at once.  run_suites builds every
+//! test_* example with cargo, runs them one after another and adds up
+//! the results.
+//!
+//! simavr exits by itself when the firmware sleeps with interrupts
+//! disabled, QEMU runs forever.  The runner stops the simulator when
+//! it sees the end-of-tests marker, when the simulator exits, or when
+//! the timeout runs out.  run_interactive is for firmware that isn't
+//! a test, it connects the serial console to the terminal instead.
+//!
+//! The bench module runs the cycle count benchmarks the same way and
+//! compares them against a baseline.
+#![warn(missing_docs)]
+
+pub mod bench;
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The line the firmware prints after the last test
+///
+/// This must match rust_basino::testing::END_OF_TESTS_MARKER.
+pub const END_OF_TESTS_MARKER: &str = "END OF TESTS";
+
+/// The line the serial RX stress test prints when it's ready for data
+///
+/// This must match rust_basino::serial::tests::STRESS_READY_MARKER.
+pub const SERIAL_RX_STRESS_MARKER: &str = "SERIAL RX STRESS READY";
+
+/// The number of bytes the serial RX stress test expects
+///
+/// This must match rust_basino::serial::tests::STRESS_LEN.
+pub const SERIAL_RX_STRESS_LEN: usize = 1024;
+
+/// The byte left out of the serial RX stress data
+///
+/// This must match rust_basino::serial::BREAK_KEY, the board sets its
+/// break flag instead of queueing it.
+pub const SERIAL_RX_STRESS_SKIP: u8 = 0x03;
+
+/// The exit code when every test passed
+pub const EXIT_SUCCESS: i32 = 0;
+/// The exit code when a test failed
+pub const EXIT_FAILURE: i32 = 1;
+/// The exit code when the tests didn't finish
+pub const EXIT_INCOMPLETE: i32 = 2;
+/// The exit code when the simulator couldn't be run
+pub const EXIT_ERROR: i32 = 3;
+
+/// Errors that can occur running the simulator
+#[derive(Debug)]
+pub enum Error {
+    /// The simulator couldn't be started
+    Launch(String, io::Error),
+    /// An I/O error talking to the simulator
+    Io(io::Error),
+    /// The simulator name isn't known
+    UnknownSimulator(String),
+    /// cargo couldn't build a test suite
+    Build(String, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Launch(program, e) => write!(f, "couldn't start {}: {}", program, e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::UnknownSimulator(name) => {
+                write!(f, "unknown simulator {}, use simavr or qemu", name)
+            }
+            Error::Build(suite, reason) => write!(f, "couldn't build {}: {}", suite, reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// The simulators the runner can launch
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Simulator {
+    /// simavr, the output only simulator used in CI
+    Simavr,
+    /// qemu-system-avr, which can also send input to the firmware
+    Qemu,
+}
+
+impl FromStr for Simulator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simavr" => Ok(Simulator::Simavr),
+            "qemu" | "qemu-system-avr" => Ok(Simulator::Qemu),
+            _ => Err(Error::UnknownSimulator(s.to_string())),
+        }
+    }
+}
+
+impl Simulator {
+    /// Build the command that runs an ELF file in the simulator
+    pub fn command(&self, elf: &Path) -> Command {
+        match self {
+            Simulator::Simavr => {
+                let mut command = Command::new("simavr");
+                command
+                    .args(["--mcu", "atmega328p", "--freq", "16000000"])
+                    .arg(elf);
+                command
+            }
+            Simulator::Qemu => {
+                let mut command = Command::new("qemu-system-avr");
+                command
+                    .args([
+                        "-M",
+                        "uno",
+                        "-nographic",
+                        "-monitor",
+                        "none",
+                        "-serial",
+                        "stdio",
+                        "-bios",
+                    ])
+                    .arg(elf);
+                command
+            }
+        }
+    }
+}
+
+/// What a line of output means
+#[derive(Debug, Eq, PartialEq)]
+pub enum LineKind {
+    /// A check passed
+    Success,
+    /// A check failed
+    Failure,
+    /// A check was skipped
+    Skipped,
+    /// The tests are finished
+    EndOfTests,
+    /// The serial RX stress test is waiting for data
+    SerialRxStressReady,
+    /// Any other output
+    Other,
+}
+
+/// Remove ANSI escape sequences and carriage returns from a line
+///
+/// simavr colors the UART output it prints.
+pub fn strip_line(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip a CSI sequence: ESC [ parameters final-byte
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else if c != '\r' {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// Classify a stripped line of output
+///
+/// TAP output prints the markers as diagnostics, with a leading "# ".
+pub fn classify(line: &str) -> LineKind {
+    let line = line.trim();
+    let marker = line.strip_prefix("# ").unwrap_or(line);
+
+    if line.starts_with("SUCCESS") {
+        LineKind::Success
+    } else if line.starts_with("FAILURE") || line.starts_with("not ok") {
+        LineKind::Failure
+    } else if line.starts_with("SKIPPED") {
+        LineKind::Skipped
+    } else if line == "ok" || line.starts_with("ok ") {
+        if line.contains("# SKIP") {
+            LineKind::Skipped
+        } else {
+            LineKind::Success
+        }
+    } else if marker.starts_with(END_OF_TESTS_MARKER) {
+        LineKind::EndOfTests
+    } else if marker.starts_with(SERIAL_RX_STRESS_MARKER) {
+        LineKind::SerialRxStressReady
+    } else {
+        LineKind::Other
+    }
+}
+
+/// The results of a run
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Summary {
+    /// The number of checks that passed
+    pub passed: usize,
+    /// The number of checks that failed
+    pub failed: usize,
+    /// The number of checks that were skipped
+    pub skipped: usize,
+    /// The failure messages
+    pub failures: Vec<String>,
+    /// True if the end-of-tests marker was seen
+    pub finished: bool,
+    /// True if the timeout ran out
+    pub timed_out: bool,
+}
+
+impl Summary {
+    /// Add a line of output to the summary
+    pub fn add_line(&mut self, line: &str) -> LineKind {
+        let kind = classify(line);
+
+        match kind {
+            LineKind::Success => self.passed += 1,
+            LineKind::Failure => {
+                self.failed += 1;
+                self.failures.push(line.trim().to_string());
+            }
+            LineKind::Skipped => self.skipped += 1,
+            LineKind::EndOfTests => self.finished = true,
+            _ => {}
+        }
+
+        kind
+    }
+
+    /// Add up the summaries of several runs
+    ///
+    /// The combined run is finished if every run finished, and timed
+    /// out if any run timed out.
+    pub fn combine<'a, I: IntoIterator<Item = &'a Summary>>(summaries: I) -> Summary {
+        let mut total = Summary {
+            finished: true,
+            ..Summary::default()
+        };
+
+        for summary in summaries {
+            total.passed += summary.passed;
+            total.failed += summary.failed;
+            total.skipped += summary.skipped;
+            total.failures.extend(summary.failures.iter().cloned());
+            total.finished &= summary.finished;
+            total.timed_out |= summary.timed_out;
+        }
+
+        total
+    }
+
+    /// The exit code for the run
+    ///
+    /// A run with no checks at all is incomplete, it usually means
+    /// the firmware crashed before printing anything.
+    pub fn exit_code(&self) -> i32 {
+        if self.failed > 0 {
+            EXIT_FAILURE
+        } else if !self.finished || self.timed_out || self.passed == 0 {
+            EXIT_INCOMPLETE
+        } else {
+            EXIT_SUCCESS
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.passed, self.failed, self.skipped
+        )?;
+        if self.timed_out {
+            write!(f, ", timed out")?;
+        } else if !self.finished {
+            write!(f, ", no end-of-tests marker")?;
+        }
+
+        for failure in &self.failures {
+            write!(f, "\n  {}", failure)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Options for a run
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The simulator to use
+    pub simulator: Simulator,
+    /// How long to wait for the end-of-tests marker
+    pub timeout: Duration,
+    /// Print the firmware output as it arrives
+    pub echo: bool,
+    /// The ELF file to run
+    pub elf: PathBuf,
+}
+
+/// Output from the reader threads
+enum Event {
+    Line(String),
+    Closed,
+}
+
+/// Read lines from a simulator pipe and send them to the main thread
+fn spawn_reader<R: Read + Send + 'static>(pipe: R, tx: mpsc::Sender<Event>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut buffer = Vec::new();
+
+        loop {
+            buffer.clear();
+            match reader.read_until(b'\n', &mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buffer);
+                    let line = line.trim_end_matches('\n');
+                    if tx.send(Event::Line(strip_line(line))).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+
+        let _ = tx.send(Event::Closed);
+    });
+}
+
+/// Send the serial RX stress test data
+///
+/// The bytes count up from zero and wrap at 256, skipping
+/// SERIAL_RX_STRESS_SKIP.
+fn send_stress_data(stdin: &mut Option<ChildStdin>) -> io::Result<()> {
+    if let Some(stdin) = stdin {
+        let data: Vec<u8> = (0..=u8::MAX)
+            .cycle()
+            .filter(|&byte| byte != SERIAL_RX_STRESS_SKIP)
+            .take(SERIAL_RX_STRESS_LEN)
+            .collect();
+        stdin.write_all(&data)?;
+        stdin.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Stop the simulator
+fn stop(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Run an ELF file and collect the results
+pub fn run(config: &Config) -> Result<Summary, Error> {
+    run_with(config, |_| {})
+}
+
+/// Run an ELF file and collect the results, passing each line of
+/// output to on_line as well
+pub fn run_with<F: FnMut(&str)>(config: &Config, mut on_line: F) -> Result<Summary, Error> {
+    let mut command = config.simulator.command(&config.elf);
+    let program = command.get_program().to_string_lossy().to_string();
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Launch(program, e))?;
+
+    let (tx, rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        spawn_reader(stdout, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        spawn_reader(stderr, tx.clone());
+    }
+    drop(tx);
+
+    // simavr doesn't read stdin, only QEMU can feed the stress test,
+    // run_suites runs it under QEMU
+    let mut stdin = match config.simulator {
+        Simulator::Qemu => child.stdin.take(),
+        Simulator::Simavr => None,
+    };
+
+    let deadline = Instant::now() + config.timeout;
+    let mut summary = Summary::default();
+    let mut open_pipes = 2;
+
+    while open_pipes > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        match rx.recv_timeout(remaining) {
+            Ok(Event::Line(line)) => {
+                if config.echo {
+                    println!("{}", line);
+                }
+                on_line(&line);
+                match summary.add_line(&line) {
+                    LineKind::EndOfTests => break,
+                    LineKind::SerialRxStressReady => send_stress_data(&mut stdin)?,
+                    _ => {}
+                }
+            }
+            Ok(Event::Closed) => open_pipes -= 1,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                summary.timed_out = true;
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    stop(&mut child);
+
+    Ok(summary)
+}
+
+/// Run an ELF file with the serial console on the terminal
+///
+/// This is for firmware that reads from the serial port, like the
+/// memory monitor.  The output isn't summarized and there's no
+/// timeout or end-of-tests marker, the run ends when the simulator
+/// exits.  It always uses QEMU, simavr doesn't connect its UART to
+/// stdin.
+pub fn run_interactive(elf: &Path) -> Result<(), Error> {
+    let mut command = Simulator::Qemu.command(elf);
+    let program = command.get_program().to_string_lossy().to_string();
+
+    command
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .map_err(|e| Error::Launch(program, e))?;
+
+    Ok(())
+}
+
+/// The prefix of the example binaries that are test suites
+pub const SUITE_PREFIX: &str = "test_";
+
+/// The test suites that need input on the serial port
+///
+/// simavr can't send the firmware any input, so run_suites runs these
+/// under QEMU whatever the configured simulator is.
+pub const INPUT_SUITES: &[&str] = &["test_serial"];
+
+/// Find the test suite examples in a crate
+///
+/// These are the examples/test_*.rs files, the names are returned
+/// without the .rs extension and sorted.
+pub fn find_suites(crate_dir: &Path) -> io::Result<Vec<String>> {
+    let mut suites = Vec::new();
+
+    for entry in std::fs::read_dir(crate_dir.join("examples"))? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("rs") {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            if stem.starts_with(SUITE_PREFIX) {
+                suites.push(stem.to_string());
+            }
+        }
+    }
+    suites.sort();
+
+    Ok(suites)
+}
+
+/// Find the executable in cargo's JSON build messages
+///
+/// This is a plain string search to keep the runner free of
+/// dependencies, cargo doesn't escape anything in a normal path.
+fn find_executable(messages: &str) -> Option<PathBuf> {
+    const KEY: &str = "\"executable\":\"";
+
+    messages.lines().rev().find_map(|line| {
+        let start = line.find(KEY)? + KEY.len();
+        let end = line[start..].find('"')?;
+        Some(PathBuf::from(&line[start..start + end]))
+    })
+}
+
+/// Build an example in release mode and return the ELF file
+pub fn build_suite(crate_dir: &Path, suite: &str) -> Result<PathBuf, Error> {
+    let output = Command::new("cargo")
+        .current_dir(crate_dir)
+        .args([
+            "build",
+            "--release",
+            "--message-format=json-render-diagnostics",
+        ])
+        .args(["--example", suite])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::Launch("cargo".to_string(), e))?;
+
+    if !output.status.success() {
+        return Err(Error::Build(suite.to_string(), output.status.to_string()));
+    }
+
+    find_executable(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| Error::Build(suite.to_string(), "no executable".to_string()))
+}
+
+/// The result of building and running one test suite
+#[derive(Debug)]
+pub struct SuiteResult {
+    /// The name of the suite
+    pub suite: String,
+    /// The summary, or the error that stopped the suite from running
+    pub result: Result<Summary, Error>,
+}
+
+/// Build and run test suites one after another
+///
+/// config.elf is ignored, each suite is built in crate_dir.  A suite
+/// that can't be built or run doesn't stop the others.  The suites in
+/// INPUT_SUITES always run under QEMU.
+pub fn run_suites(config: &Config, crate_dir: &Path, suites: &[String]) -> Vec<SuiteResult> {
+    suites
+        .iter()
+        .map(|suite| {
+            println!("basino-runner: running {}", suite);
+
+            let simulator = if INPUT_SUITES.contains(&suite.as_str()) {
+                Simulator::Qemu
+            } else {
+                config.simulator
+            };
+            let result = build_suite(crate_dir, suite).and_then(|elf| {
+                run(&Config {
+                    simulator,
+                    elf,
+                    ..config.clone()
+                })
+            });
+
+            match &result {
+                Ok(summary) => println!("basino-runner: {}: {}", suite, summary),
+                Err(e) => println!("basino-runner: {}: {}", suite, e),
+            }
+
+            SuiteResult {
+                suite: suite.clone(),
+                result,
+            }
+        })
+        .collect()
+}
+
+/// The exit code for a set of suite results
+///
+/// A suite that couldn't be built or run is an error, otherwise this
+/// is the exit code of the combined summary.
+pub fn suites_exit_code(results: &[SuiteResult]) -> i32 {
+    if results.iter().any(|r| r.result.is_err()) {
+        return EXIT_ERROR;
+    }
+
+    Summary::combine(results.iter().filter_map(|r| r.result.as_ref().ok())).exit_code()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_line_removes_colors_and_carriage_returns() {
+        assert_eq!(
+            strip_line("\x1b[32mSUCCESS: push works\x1b[0m\r"),
+            "SUCCESS: push works"
+        );
+        assert_eq!(strip_line("plain"), "plain");
+        assert_eq!(strip_line("\x1b[1;31mred"), "red");
+    }
+
+    #[test]
+    fn classify_plain_lines() {
+        assert_eq!(classify("SUCCESS: push works"), LineKind::Success);
+        assert_eq!(classify("FAILURE: push failed"), LineKind::Failure);
+        assert_eq!(classify("SKIPPED: no stress data"), LineKind::Skipped);
+        assert_eq!(classify("END OF TESTS"), LineKind::EndOfTests);
+        assert_eq!(
+            classify("SERIAL RX STRESS READY"),
+            LineKind::SerialRxStressReady
+        );
+        assert_eq!(classify("test_stack: 10 passed"), LineKind::Other);
+        assert_eq!(classify("  SUCCESS: indented  "), LineKind::Success);
+    }
+
+    #[test]
+    fn classify_tap_lines() {
+        assert_eq!(classify("ok 1 - push works"), LineKind::Success);
+        assert_eq!(classify("ok"), LineKind::Success);
+        assert_eq!(classify("not ok 2 - pop works"), LineKind::Failure);
+        assert_eq!(
+            classify("ok 3 - stress # SKIP no host data"),
+            LineKind::Skipped
+        );
+        assert_eq!(classify("# END OF TESTS"), LineKind::EndOfTests);
+        assert_eq!(
+            classify("# SERIAL RX STRESS READY"),
+            LineKind::SerialRxStressReady
+        );
+        assert_eq!(classify("okay"), LineKind::Other);
+        assert_eq!(classify("1..3"), LineKind::Other);
+    }
+
+    #[test]
+    fn summary_counts_lines() {
+        let mut summary = Summary::default();
+
+        summary.add_line("SUCCESS: a");
+        summary.add_line("ok 2 - b");
+        summary.add_line("FAILURE: c");
+        summary.add_line("SKIPPED: d");
+        summary.add_line("some output");
+        assert!(!summary.finished);
+        assert_eq!(summary.add_line("END OF TESTS"), LineKind::EndOfTests);
+
+        assert_eq!(summary.passed, 2);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failures, vec!["FAILURE: c".to_string()]);
+        assert!(summary.finished);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let passed = Summary {
+            passed: 3,
+            finished: true,
+            ..Summary::default()
+        };
+        assert_eq!(passed.exit_code(), EXIT_SUCCESS);
+
+        let failed = Summary {
+            failed: 1,
+            ..passed.clone()
+        };
+        assert_eq!(failed.exit_code(), EXIT_FAILURE);
+
+        let unfinished = Summary {
+            finished: false,
+            ..passed.clone()
+        };
+        assert_eq!(unfinished.exit_code(), EXIT_INCOMPLETE);
+
+        let timed_out = Summary {
+            timed_out: true,
+            ..passed.clone()
+        };
+        assert_eq!(timed_out.exit_code(), EXIT_INCOMPLETE);
+
+        let empty = Summary {
+            finished: true,
+            ..Summary::default()
+        };
+        assert_eq!(empty.exit_code(), EXIT_INCOMPLETE);
+    }
+
+    #[test]
+    fn combine_adds_up_runs() {
+        let first = Summary {
+            passed: 2,
+            skipped: 1,
+            finished: true,
+            ..Summary::default()
+        };
+        let second = Summary {
+            passed: 1,
+            failed: 1,
+            failures: vec!["FAILURE: x".to_string()],
+            finished: true,
+            ..Summary::default()
+        };
+
+        let total = Summary::combine([&first, &second]);
+        assert_eq!(total.passed, 3);
+        assert_eq!(total.failed, 1);
+        assert_eq!(total.skipped, 1);
+        assert_eq!(total.failures, vec!["FAILURE: x".to_string()]);
+        assert!(total.finished);
+        assert!(!total.timed_out);
+
+        let unfinished = Summary {
+            timed_out: true,
+            ..Summary::default()
+        };
+        let total = Summary::combine([&first, &unfinished]);
+        assert!(!total.finished);
+        assert!(total.timed_out);
+
+        assert!(Summary::combine([]).finished);
+    }
+
+    #[test]
+    fn find_executable_takes_the_last_one() {
+        let messages = concat!(
+            "{\"reason\":\"compiler-artifact\",\"executable\":null}\n",
+            "{\"reason\":\"compiler-artifact\",\"executable\":\"/t/a.elf\"}\n",
+            "{\"reason\":\"compiler-artifact\",\"executable\":\"/t/test_stack.elf\"}\n",
+            "{\"reason\":\"build-finished\",\"success\":true}\n",
+        );
+
+        assert_eq!(
+            find_executable(messages),
+            Some(PathBuf::from("/t/test_stack.elf"))
+        );
+        assert_eq!(find_executable("{\"reason\":\"build-finished\"}"), None);
+    }
+}
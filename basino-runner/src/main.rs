@@ -0,0 +1,369 @@
+//! Run an ELF file under a simulator and report the test results
+//!
+//! This can be used as the cargo runner for rust-basino:
+//!
+//!   runner = "../basino-runner/target/release/basino-runner --timeout 60"
+//!
+//! cargo appends the ELF file to the runner command line.  Arguments
+//! after -- in cargo run come after it, so
+//!
+//!   cargo run --features monitor -- --interactive
+//!
+//! runs the firmware under QEMU with the serial console on the
+//! terminal.
+//!
+//! With --suites it builds and runs every test suite example in a
+//! crate instead, and prints the combined results:
+//!
+//!   basino-runner --suites rust-basino
+//!
+//! With --bench it builds and runs the cycle count benchmarks and
+//! compares them against a baseline:
+//!
+//!   basino-runner --bench rust-basino --baseline bench-baseline.txt
+#![warn(missing_docs)]
+
+use basino_runner::{
+    bench::{self, Benchmark, DEFAULT_THRESHOLD},
+    find_suites, run, run_interactive, run_suites, suites_exit_code, Config, Simulator, Summary,
+    EXIT_ERROR, EXIT_FAILURE, EXIT_INCOMPLETE, EXIT_SUCCESS,
+};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
+
+const USAGE: &str =
+    "usage: basino-runner [--simulator simavr|qemu] [--timeout SECONDS] [--quiet] ELF
+       basino-runner --interactive ELF
+       basino-runner [options] --suites CRATE_DIR [SUITE...]
+       basino-runner [options] --bench CRATE_DIR --baseline FILE
+                     [--threshold PERCENT] [--update-baseline]
+       basino-runner --bench-log LOG --baseline FILE
+                     [--threshold PERCENT] [--update-baseline]
+
+Runs ELF under the simulator, prints its serial output and a summary.
+The simulator can also be set with the BASINO_SIMULATOR environment
+variable.
+
+With --interactive, runs ELF under QEMU with the serial console on the
+terminal, for firmware that reads input like the memory monitor.
+There's no summary or timeout, Ctrl-C stops QEMU.
+
+With --suites, builds each test suite example in CRATE_DIR with cargo
+and runs them one after another.  The suites are the examples named
+test_*, or the SUITEs given.  The timeout is for each suite.
+
+With --bench, builds the bench example in CRATE_DIR, runs it and
+compares the cycle counts against the baseline FILE.  --bench-log
+compares the output of a run saved in LOG instead, for example from
+a board.  A benchmark regressed if it takes more than PERCENT more
+cycles than the baseline, the default is 5.  --update-baseline writes
+the counts to FILE instead of comparing.
+
+Exit codes:
+  0  every test passed, or no benchmark regressed
+  1  a test failed, or a benchmark regressed or is missing
+  2  the tests didn't finish: timeout, crash or no end-of-tests marker
+  3  the simulator couldn't be run";
+
+/// Print an error and the usage and exit
+fn usage_error(message: &str) -> ! {
+    eprintln!("basino-runner: {}", message);
+    eprintln!("{}", USAGE);
+    exit(EXIT_ERROR);
+}
+
+/// What to run
+enum Mode {
+    /// Run one ELF file
+    Elf(Config),
+    /// Run one ELF file with the console on the terminal
+    Interactive(PathBuf),
+    /// Build and run the test suites in a crate
+    Suites(Config, PathBuf, Vec<String>),
+    /// Compare benchmarks against a baseline
+    Bench(Config, BenchOptions),
+}
+
+/// Where the benchmark counts come from
+enum BenchSource {
+    /// Build and run the bench example in a crate
+    Crate(PathBuf),
+    /// Read the saved output of a run
+    Log(PathBuf),
+}
+
+/// Options for comparing benchmarks
+struct BenchOptions {
+    /// Where the counts come from
+    source: BenchSource,
+    /// The baseline file
+    baseline: PathBuf,
+    /// The percentage of extra cycles allowed
+    threshold: u32,
+    /// Write the counts to the baseline instead of comparing
+    update: bool,
+}
+
+/// Parse the command line
+fn parse_args() -> Mode {
+    let mut simulator = match env::var("BASINO_SIMULATOR") {
+        Ok(name) => name
+            .parse()
+            .unwrap_or_else(|e| usage_error(&format!("{}", e))),
+        Err(_) => Simulator::Simavr,
+    };
+    let mut timeout = Duration::from_secs(60);
+    let mut echo = true;
+    let mut elf = None;
+    let mut crate_dir = None;
+    let mut suites = Vec::new();
+    let mut bench_source = None;
+    let mut baseline = None;
+    let mut threshold = DEFAULT_THRESHOLD;
+    let mut update = false;
+    let mut interactive = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--simulator" => {
+                let name = args
+                    .next()
+                    .unwrap_or_else(|| usage_error("--simulator needs a name"));
+                simulator = name
+                    .parse()
+                    .unwrap_or_else(|e| usage_error(&format!("{}", e)));
+            }
+            "--timeout" => {
+                let seconds = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| usage_error("--timeout needs a number of seconds"));
+                timeout = Duration::from_secs(seconds);
+            }
+            "--suites" => {
+                crate_dir =
+                    Some(PathBuf::from(args.next().unwrap_or_else(|| {
+                        usage_error("--suites needs a crate directory")
+                    })));
+            }
+            "--bench" => {
+                bench_source = Some(BenchSource::Crate(PathBuf::from(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--bench needs a crate directory")),
+                )));
+            }
+            "--bench-log" => {
+                bench_source = Some(BenchSource::Log(PathBuf::from(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--bench-log needs a file")),
+                )));
+            }
+            "--baseline" => {
+                baseline = Some(PathBuf::from(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--baseline needs a file")),
+                ));
+            }
+            "--threshold" => {
+                threshold = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| usage_error("--threshold needs a percentage"));
+            }
+            "--update-baseline" => update = true,
+            "--quiet" => echo = false,
+            "--interactive" => interactive = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ if crate_dir.is_some() => suites.push(arg),
+            _ if elf.is_none() => elf = Some(PathBuf::from(arg)),
+            _ => usage_error(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if interactive {
+        if crate_dir.is_some() || bench_source.is_some() {
+            usage_error("--interactive needs an ELF file, not --suites or --bench");
+        }
+        return Mode::Interactive(elf.unwrap_or_else(|| usage_error("no ELF file given")));
+    }
+
+    if let Some(source) = bench_source {
+        if elf.is_some() || crate_dir.is_some() {
+            usage_error("give only one of an ELF file, --suites and --bench");
+        }
+        let config = Config {
+            simulator,
+            timeout,
+            echo,
+            elf: PathBuf::new(),
+        };
+        let options = BenchOptions {
+            source,
+            baseline: baseline.unwrap_or_else(|| usage_error("--bench needs --baseline")),
+            threshold,
+            update,
+        };
+        return Mode::Bench(config, options);
+    }
+
+    match crate_dir {
+        Some(crate_dir) => {
+            if elf.is_some() {
+                usage_error("give either an ELF file or --suites");
+            }
+            let config = Config {
+                simulator,
+                timeout,
+                echo,
+                elf: PathBuf::new(),
+            };
+            Mode::Suites(config, crate_dir, suites)
+        }
+        None => Mode::Elf(Config {
+            simulator,
+            timeout,
+            echo,
+            elf: elf.unwrap_or_else(|| usage_error("no ELF file given")),
+        }),
+    }
+}
+
+/// Build and run the test suites and print the combined results
+fn main_suites(config: &Config, crate_dir: &Path, mut suites: Vec<String>) -> ! {
+    if suites.is_empty() {
+        suites = find_suites(crate_dir).unwrap_or_else(|e| {
+            eprintln!("basino-runner: couldn't find suites: {}", e);
+            exit(EXIT_ERROR);
+        });
+    }
+
+    let results = run_suites(config, crate_dir, &suites);
+    let total = Summary::combine(results.iter().filter_map(|r| r.result.as_ref().ok()));
+
+    println!("basino-runner: {} suites", results.len());
+    for r in &results {
+        match &r.result {
+            Ok(summary) => println!(
+                "  {}: {} passed, {} failed, {} skipped",
+                r.suite, summary.passed, summary.failed, summary.skipped
+            ),
+            Err(e) => println!("  {}: {}", r.suite, e),
+        }
+    }
+    println!("basino-runner: total: {}", total);
+
+    exit(suites_exit_code(&results));
+}
+
+/// Get the benchmark counts, from a run or a saved log
+///
+/// Exits if the run couldn't be done or didn't finish.
+fn bench_counts(config: &Config, source: &BenchSource) -> Vec<Benchmark> {
+    let benchmarks = match source {
+        BenchSource::Crate(crate_dir) => {
+            let (summary, benchmarks) = bench::run_bench(config, crate_dir).unwrap_or_else(|e| {
+                eprintln!("basino-runner: {}", e);
+                exit(EXIT_ERROR);
+            });
+            if summary.timed_out || !summary.finished {
+                eprintln!("basino-runner: the benchmarks didn't finish");
+                exit(EXIT_INCOMPLETE);
+            }
+            benchmarks
+        }
+        BenchSource::Log(log) => bench::read_file(log).unwrap_or_else(|e| {
+            eprintln!("basino-runner: couldn't read {}: {}", log.display(), e);
+            exit(EXIT_ERROR);
+        }),
+    };
+
+    if benchmarks.is_empty() {
+        eprintln!("basino-runner: no benchmark rows");
+        exit(EXIT_INCOMPLETE);
+    }
+
+    benchmarks
+}
+
+/// Compare the benchmarks against the baseline, or update it
+fn main_bench(config: &Config, options: &BenchOptions) -> ! {
+    let current = bench_counts(config, &options.source);
+
+    if options.update {
+        bench::write_baseline(&options.baseline, &current).unwrap_or_else(|e| {
+            eprintln!(
+                "basino-runner: couldn't write {}: {}",
+                options.baseline.display(),
+                e
+            );
+            exit(EXIT_ERROR);
+        });
+        println!(
+            "basino-runner: wrote {} benchmarks to {}",
+            current.len(),
+            options.baseline.display()
+        );
+        exit(EXIT_SUCCESS);
+    }
+
+    let baseline = bench::read_file(&options.baseline).unwrap_or_else(|e| {
+        eprintln!(
+            "basino-runner: couldn't read {}: {}, create it with --update-baseline",
+            options.baseline.display(),
+            e
+        );
+        exit(EXIT_ERROR);
+    });
+
+    let comparisons = bench::compare(&baseline, &current, options.threshold);
+    println!("basino-runner: {} benchmarks", comparisons.len());
+    println!("  {:<38} {:>8} {:>8}", "benchmark", "baseline", "cycles");
+    for comparison in &comparisons {
+        println!("  {}", comparison);
+    }
+
+    if bench::has_regressions(&comparisons) {
+        println!(
+            "basino-runner: benchmarks regressed by more than {}%",
+            options.threshold
+        );
+        exit(EXIT_FAILURE);
+    }
+    println!("basino-runner: no regressions");
+    exit(EXIT_SUCCESS);
+}
+
+fn main() {
+    let config = match parse_args() {
+        Mode::Elf(config) => config,
+        Mode::Interactive(elf) => match run_interactive(&elf) {
+            Ok(()) => exit(EXIT_SUCCESS),
+            Err(e) => {
+                eprintln!("basino-runner: {}", e);
+                exit(EXIT_ERROR);
+            }
+        },
+        Mode::Suites(config, crate_dir, suites) => main_suites(&config, &crate_dir, suites),
+        Mode::Bench(config, options) => main_bench(&config, &options),
+    };
+
+    match run(&config) {
+        Ok(summary) => {
+            println!("basino-runner: {}", summary);
+            exit(summary.exit_code());
+        }
+        Err(e) => {
+            eprintln!("basino-runner: {}", e);
+            exit(EXIT_ERROR);
+        }
+    }
+}
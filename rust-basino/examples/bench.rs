@@ -0,0 +1,32 @@
+//! Print cycle counts for the libbasino routines
+//!
+//! basino-runner --bench runs this and compares the counts against a
+//! baseline.
+#![warn(missing_docs)]
+#![cfg_attr(target_arch = "avr", no_std)]
+#![cfg_attr(target_arch = "avr", no_main)]
+
+#[cfg(target_arch = "avr")]
+use panic_halt as _;
+
+#[cfg(target_arch = "avr")]
+use rust_basino::bench::run_benchmarks;
+
+#[cfg(target_arch = "avr")]
+#[arduino_hal::entry]
+fn main() -> ! {
+    let dp = arduino_hal::Peripherals::take().unwrap();
+    let pins = arduino_hal::pins!(dp);
+
+    let mut serial = arduino_hal::default_serial!(dp, pins, 57600);
+
+    run_benchmarks(&mut serial);
+
+    loop {
+        avr_device::asm::sleep();
+    }
+}
+
+/// The firmware only runs on AVR
+#[cfg(not(target_arch = "avr"))]
+fn main() {}
@@ -0,0 +1,34 @@
+//! Test the interrupt driven events for Arduino devices
+#![warn(missing_docs)]
+#![cfg_attr(target_arch = "avr", no_std)]
+#![cfg_attr(target_arch = "avr", no_main)]
+
+#[cfg(target_arch = "avr")]
+use panic_halt as _;
+
+#[cfg(target_arch = "avr")]
+use rust_basino::{
+    events::tests::run_tests,
+    testing::{Format, Reporter, TestReporter},
+};
+
+#[cfg(target_arch = "avr")]
+#[arduino_hal::entry]
+fn main() -> ! {
+    let dp = arduino_hal::Peripherals::take().unwrap();
+    let pins = arduino_hal::pins!(dp);
+
+    let mut serial = arduino_hal::default_serial!(dp, pins, 57600);
+    let mut reporter = Reporter::new(&mut serial, Format::default());
+
+    run_tests(&mut reporter);
+    reporter.finish();
+
+    loop {
+        avr_device::asm::sleep();
+    }
+}
+
+/// The firmware only runs on AVR
+#[cfg(not(target_arch = "avr"))]
+fn main() {}
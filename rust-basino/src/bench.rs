@@ -0,0 +1,426 @@
+//! Cycle counts for the libbasino routines
+//!
+//! Timer1 runs in normal mode without a prescaler, so TCNT1 counts
+//! CPU cycles.  Each routine is called with interrupts disabled
+//! between two reads of TCNT1.  The same reads with nothing in between
+//! are timed first and that overhead is subtracted, so a count is the
+//! cost of the call: setting up the arguments, the call and the
+//! routine itself.  Every benchmark runs RUNS times and the smallest
+//! count is kept.
+//!
+//! The stack and queue routines take different branches depending on
+//! the state, so some routines have more than one row, for example
+//! basino_queue_put/wrap times a put that wraps the tail around.
+//!
+//! run_benchmarks prints a table over serial with one row per
+//! benchmark, then the end-of-tests marker:
+//!
+//!   BENCH basino_stack_push                     23
+//!
+//! basino-runner --bench runs the bench example and compares the rows
+//! against a baseline.
+#![warn(missing_docs)]
+
+use crate::{
+    basino_add, basino_address_add, basino_get_basino_stack_bottom, basino_get_basino_stack_top,
+    basino_get_basino_stack_top_sentinel, basino_gt, basino_gt_eq, basino_progmem_read,
+    basino_queue_get, basino_queue_get_head, basino_queue_get_last_head,
+    basino_queue_get_queue_end, basino_queue_get_queue_start, basino_queue_get_tail,
+    basino_queue_init, basino_queue_put, basino_stack_init, basino_stack_pop, basino_stack_push,
+    hardware::{read_reg, write_reg, TCCR1A, TCCR1B},
+    stack::StackImpl,
+    testing::END_OF_TESTS_MARKER,
+    ArrayHandle, Queue, Stack,
+};
+
+use avr_device::interrupt::free;
+use core::hint::black_box;
+use ufmt::uWrite;
+
+/// The prefix of a benchmark row
+///
+/// basino-runner looks for rows starting with this.
+pub const ROW_PREFIX: &str = "BENCH";
+
+/// The number of times each benchmark runs
+pub const RUNS: u8 = 8;
+
+/// The column the cycle counts start at, after the prefix and name
+const COUNT_COLUMN: usize = 44;
+
+// Timer/Counter1 register addresses
+const TCNT1L: usize = 0x84;
+const TCNT1H: usize = 0x85;
+
+// TCCR1B bits
+const CS10: u8 = 0;
+
+/// The length of the stack and queue arrays
+const ARRAY_LEN: usize = 4;
+
+/// Start Timer1 counting CPU cycles
+///
+/// This takes Timer1 away from PWM on D9 and D10.
+pub fn init() {
+    write_reg(TCCR1A, 0x00);
+    write_reg(TCCR1B, 1 << CS10);
+}
+
+/// Read TCNT1
+///
+/// The low byte has to be read first, that latches the high byte.
+#[inline(always)]
+fn read_tcnt1() -> u16 {
+    let low = read_reg(TCNT1L);
+    let high = read_reg(TCNT1H);
+    u16::from_le_bytes([low, high])
+}
+
+/// Time one call of routine
+///
+/// The timer wraps every 65536 cycles, the routines are much shorter
+/// than that so wrapping_sub is enough.
+#[inline(always)]
+fn measure<F: FnMut()>(routine: &mut F) -> u16 {
+    free(|_cs| {
+        let start = read_tcnt1();
+        routine();
+        let end = read_tcnt1();
+        end.wrapping_sub(start)
+    })
+}
+
+/// Time a routine RUNS times and return the smallest count
+///
+/// setup runs before each call and isn't timed.  overhead is
+/// subtracted from the count.
+fn cycles<S: FnMut(), F: FnMut()>(overhead: u16, mut setup: S, mut routine: F) -> u16 {
+    let mut best = u16::MAX;
+
+    for _ in 0..RUNS {
+        setup();
+        best = best.min(measure(&mut routine));
+    }
+
+    best.saturating_sub(overhead)
+}
+
+/// Print a row of the table
+fn print_row<W: uWrite>(writer: &mut W, name: &str, cycles: u16) {
+    let _ = ufmt::uwrite!(writer, "{} {}", ROW_PREFIX, name);
+    for _ in (ROW_PREFIX.len() + 1 + name.len())..COUNT_COLUMN - 1 {
+        let _ = writer.write_char(' ');
+    }
+    let _ = ufmt::uwrite!(writer, " {}\r\n", cycles);
+}
+
+/// Time the libbasino routines and print the table
+///
+/// basino_sleep_idle isn't timed, it waits for an interrupt.
+pub fn run_benchmarks<W: uWrite>(writer: &mut W) {
+    init();
+
+    // The cost of the timer reads themselves
+    let overhead = cycles(0, || {}, || {});
+    let mut row = |name: &str, count: u16| print_row(writer, name, count);
+    let mut carry = 0;
+
+    row(
+        "basino_add",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_add(black_box(200), black_box(100)));
+            },
+        ),
+    );
+    row(
+        "basino_address_add",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_address_add(
+                    black_box(0xFFFF),
+                    black_box(0x0002),
+                    &mut carry,
+                ));
+            },
+        ),
+    );
+    row(
+        "basino_gt",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_gt(black_box(0x1000), black_box(0x0010)));
+            },
+        ),
+    );
+    row(
+        "basino_gt_eq",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_gt_eq(black_box(0x1000), black_box(0x0010)));
+            },
+        ),
+    );
+    row(
+        "basino_progmem_read",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_progmem_read(black_box(0x0000)));
+            },
+        ),
+    );
+
+    let mut stack_array = [0u8; ARRAY_LEN];
+    let stack_handle = ArrayHandle::new(stack_array.as_mut_ptr(), stack_array.len());
+    let mut stack = match Stack::new(&stack_handle) {
+        Ok(stack) => stack,
+        Err(_) => return,
+    };
+    let (bottom, top) = (stack.bottom, stack.top_sentinel);
+    let stack_ptr = core::ptr::addr_of_mut!(stack);
+    let mut status = 0;
+
+    row(
+        "basino_stack_init",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_stack_init(stack_ptr, top, bottom));
+            },
+        ),
+    );
+    row(
+        "basino_stack_push",
+        cycles(
+            overhead,
+            || unsafe { (*stack_ptr).top = top },
+            || unsafe {
+                black_box(basino_stack_push(stack_ptr, black_box(1)));
+            },
+        ),
+    );
+    row(
+        "basino_stack_push/full",
+        cycles(
+            overhead,
+            || unsafe { (*stack_ptr).top = bottom },
+            || unsafe {
+                black_box(basino_stack_push(stack_ptr, black_box(1)));
+            },
+        ),
+    );
+    row(
+        "basino_stack_pop",
+        cycles(
+            overhead,
+            || unsafe {
+                (*stack_ptr).top = top;
+                basino_stack_push(stack_ptr, 1);
+            },
+            || unsafe {
+                black_box(basino_stack_pop(stack_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_stack_pop/empty",
+        cycles(
+            overhead,
+            || unsafe { (*stack_ptr).top = top },
+            || unsafe {
+                black_box(basino_stack_pop(stack_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_get_basino_stack_bottom",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_get_basino_stack_bottom(stack_ptr));
+            },
+        ),
+    );
+    row(
+        "basino_get_basino_stack_top",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_get_basino_stack_top(stack_ptr));
+            },
+        ),
+    );
+    row(
+        "basino_get_basino_stack_top_sentinel",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_get_basino_stack_top_sentinel(stack_ptr));
+            },
+        ),
+    );
+
+    let mut queue_array = [0u8; ARRAY_LEN];
+    let start = queue_array.as_mut_ptr();
+    let end = unsafe { start.add(ARRAY_LEN - 1) };
+    let mut queue = Queue::uninit();
+    if unsafe { queue.init(start, ARRAY_LEN) }.is_err() {
+        return;
+    }
+    let queue_ptr = core::ptr::addr_of_mut!(queue.queue);
+
+    // Initialize the queue and do some puts and gets, untimed, to get
+    // it into a state
+    let fill = |puts: usize, gets: usize| unsafe {
+        let mut result = 0;
+        basino_queue_init(queue_ptr, start, end);
+        for _ in 0..puts {
+            basino_queue_put(queue_ptr, 1);
+        }
+        for _ in 0..gets {
+            basino_queue_get(queue_ptr, &mut result);
+        }
+    };
+
+    row(
+        "basino_queue_init",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_queue_init(queue_ptr, start, end));
+            },
+        ),
+    );
+    row(
+        "basino_queue_put",
+        cycles(
+            overhead,
+            || fill(0, 0),
+            || unsafe {
+                black_box(basino_queue_put(queue_ptr, black_box(1)));
+            },
+        ),
+    );
+    // The tail is at the end and the last head has moved off it
+    row(
+        "basino_queue_put/wrap",
+        cycles(
+            overhead,
+            || fill(ARRAY_LEN - 1, 1),
+            || unsafe {
+                black_box(basino_queue_put(queue_ptr, black_box(1)));
+            },
+        ),
+    );
+    row(
+        "basino_queue_put/full",
+        cycles(
+            overhead,
+            || fill(ARRAY_LEN - 1, 0),
+            || unsafe {
+                black_box(basino_queue_put(queue_ptr, black_box(1)));
+            },
+        ),
+    );
+    row(
+        "basino_queue_get",
+        cycles(
+            overhead,
+            || fill(1, 0),
+            || unsafe {
+                black_box(basino_queue_get(queue_ptr, &mut status));
+            },
+        ),
+    );
+    // The head is at the end, and one more put wrapped the tail
+    row(
+        "basino_queue_get/wrap",
+        cycles(
+            overhead,
+            || unsafe {
+                fill(ARRAY_LEN - 1, ARRAY_LEN - 1);
+                basino_queue_put(queue_ptr, 1);
+            },
+            || unsafe {
+                black_box(basino_queue_get(queue_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_queue_get/empty",
+        cycles(
+            overhead,
+            || fill(0, 0),
+            || unsafe {
+                black_box(basino_queue_get(queue_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_queue_get_queue_start",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_queue_get_queue_start(queue_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_queue_get_queue_end",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_queue_get_queue_end(queue_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_queue_get_head",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_queue_get_head(queue_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_queue_get_last_head",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_queue_get_last_head(queue_ptr, &mut status));
+            },
+        ),
+    );
+    row(
+        "basino_queue_get_tail",
+        cycles(
+            overhead,
+            || {},
+            || unsafe {
+                black_box(basino_queue_get_tail(queue_ptr, &mut status));
+            },
+        ),
+    );
+
+    let _ = ufmt::uwrite!(writer, "{}\r\n", END_OF_TESTS_MARKER);
+}
@@ -0,0 +1,761 @@
+//! EEPROM storage and program persistence
+//!
+//! Programs are saved to EEPROM as an image with a small header in
+//! front of them:
+//!
+//!   offset  size  field
+//!   0       2     magic, "BA"
+//!   2       1     version
+//!   3       1     flags, bit 0 is the autorun flag
+//!   4       2     program length, little-endian
+//!   6       2     CRC-16 of bytes 2 to 5 and the program, little-endian
+//!   8       n     program
+//!
+//! The CRC covers the header fields as well as the program, so a
+//! corrupted length or flag byte is detected too.  Images that don't
+//! pass the checks are refused.
+//!
+//! Storage is accessed through the Storage trait.  The ATmega328P
+//! EEPROM implements it, and so does RamStorage, an in-RAM EEPROM
+//! that tests can use without wearing out the real one.
+#![warn(missing_docs)]
+
+use core::fmt::{Debug, Display, Formatter};
+use ufmt::{uDebug, uWrite};
+
+/// The kinds of errors that can occur working with EEPROM storage
+#[derive(Eq, PartialEq)]
+pub enum ErrorKind {
+    /// An address past the end of the storage was accessed
+    AddressOutOfRange,
+    /// There isn't a saved program, the magic number is wrong
+    NoProgram,
+    /// The program was saved by an incompatible version
+    UnsupportedVersion,
+    /// The saved program failed the CRC check
+    ChecksumMismatch,
+    /// The program doesn't fit in the storage
+    ProgramTooLarge,
+    /// The buffer passed in is too small for the saved program
+    BufferTooSmall,
+    /// An unknown error type
+    Unknown,
+}
+
+impl uDebug for ErrorKind {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        match self {
+            ErrorKind::AddressOutOfRange => f.write_str("The address is out of range"),
+            ErrorKind::NoProgram => f.write_str("There is no saved program"),
+            ErrorKind::UnsupportedVersion => {
+                f.write_str("The saved program version is unsupported")
+            }
+            ErrorKind::ChecksumMismatch => f.write_str("The saved program is corrupted"),
+            ErrorKind::ProgramTooLarge => f.write_str("The program is too large"),
+            ErrorKind::BufferTooSmall => f.write_str("The buffer is too small"),
+            ErrorKind::Unknown => f.write_str("An unknown error occurred"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ErrorKind::AddressOutOfRange => write!(f, "The address is out of range"),
+            ErrorKind::NoProgram => write!(f, "There is no saved program"),
+            ErrorKind::UnsupportedVersion => write!(f, "The saved program version is unsupported"),
+            ErrorKind::ChecksumMismatch => write!(f, "The saved program is corrupted"),
+            ErrorKind::ProgramTooLarge => write!(f, "The program is too large"),
+            ErrorKind::BufferTooSmall => write!(f, "The buffer is too small"),
+            ErrorKind::Unknown => write!(f, "An unknown error occurred"),
+        }
+    }
+}
+
+/// An error that can occur when working with EEPROM storage
+#[derive(PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// A byte-addressable non-volatile storage backend
+pub trait Storage {
+    /// The number of bytes in the storage
+    fn capacity(&self) -> u16;
+
+    /// Read a byte
+    fn read_byte(&mut self, address: u16) -> Result<u8, Error>;
+
+    /// Write a byte
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error>;
+
+    /// Write a byte only if it's different from the stored byte
+    ///
+    /// EEPROM cells wear out after about 100,000 erase and write
+    /// cycles, skipping unchanged bytes saves cycles.
+    fn update_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        if self.read_byte(address)? != value {
+            self.write_byte(address, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "avr")]
+impl Storage for arduino_hal::Eeprom {
+    fn capacity(&self) -> u16 {
+        arduino_hal::Eeprom::capacity(self)
+    }
+
+    fn read_byte(&mut self, address: u16) -> Result<u8, Error> {
+        if address >= arduino_hal::Eeprom::capacity(self) {
+            return Err(Error::new(ErrorKind::AddressOutOfRange));
+        }
+
+        Ok(arduino_hal::Eeprom::read_byte(self, address))
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        if address >= arduino_hal::Eeprom::capacity(self) {
+            return Err(Error::new(ErrorKind::AddressOutOfRange));
+        }
+
+        arduino_hal::Eeprom::write_byte(self, address, value);
+
+        Ok(())
+    }
+}
+
+/// An in-RAM EEPROM backed by a byte slice
+///
+/// This is meant for tests.  The ATmega328P only has 2 KB of SRAM,
+/// so it's usually much smaller than the real 1 KB EEPROM.
+pub struct RamStorage<'a> {
+    /// The bytes of the storage
+    pub data: &'a mut [u8],
+}
+
+impl<'a> RamStorage<'a> {
+    /// Create a new RamStorage from a byte slice
+    ///
+    /// The slice is erased to 0xFF, the value of erased EEPROM
+    /// cells.
+    pub fn new(data: &'a mut [u8]) -> Self {
+        data.fill(0xFF);
+
+        RamStorage { data }
+    }
+}
+
+impl<'a> Storage for RamStorage<'a> {
+    fn capacity(&self) -> u16 {
+        self.data.len() as u16
+    }
+
+    fn read_byte(&mut self, address: u16) -> Result<u8, Error> {
+        match self.data.get(address as usize) {
+            Some(v) => Ok(*v),
+            None => Err(Error::new(ErrorKind::AddressOutOfRange)),
+        }
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        match self.data.get_mut(address as usize) {
+            Some(v) => {
+                *v = value;
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::AddressOutOfRange)),
+        }
+    }
+}
+
+/// The start of the program partition in the ATmega328P EEPROM
+pub const PROGRAM_PARTITION_START: u16 = 0;
+
+/// The length of the program partition in the ATmega328P EEPROM
+///
+/// The last 256 bytes of the 1 KB EEPROM are left for the key/value
+/// store.
+pub const PROGRAM_PARTITION_LEN: u16 = 768;
+
+/// A window into another storage
+///
+/// This lets program images and the key/value store share the
+/// EEPROM without overwriting each other.  Addresses are relative to
+/// the start of the partition.
+pub struct Partition<'a, S: Storage> {
+    /// The underlying storage
+    pub storage: &'a mut S,
+    /// The address of the partition in the underlying storage
+    pub start: u16,
+    /// The length of the partition
+    pub len: u16,
+}
+
+impl<'a, S: Storage> Partition<'a, S> {
+    /// Create a new partition
+    ///
+    /// Returns an AddressOutOfRange error if the partition doesn't
+    /// fit in the underlying storage.
+    pub fn new(storage: &'a mut S, start: u16, len: u16) -> Result<Self, Error> {
+        if start as u32 + len as u32 > storage.capacity() as u32 {
+            return Err(Error::new(ErrorKind::AddressOutOfRange));
+        }
+
+        Ok(Partition {
+            storage,
+            start,
+            len,
+        })
+    }
+}
+
+impl<'a, S: Storage> Storage for Partition<'a, S> {
+    fn capacity(&self) -> u16 {
+        self.len
+    }
+
+    fn read_byte(&mut self, address: u16) -> Result<u8, Error> {
+        if address >= self.len {
+            return Err(Error::new(ErrorKind::AddressOutOfRange));
+        }
+
+        self.storage.read_byte(self.start + address)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        if address >= self.len {
+            return Err(Error::new(ErrorKind::AddressOutOfRange));
+        }
+
+        self.storage.write_byte(self.start + address, value)
+    }
+}
+
+/// Update a CRC-16 with one byte
+///
+/// This is CRC-16/CCITT-FALSE: polynomial 0x1021, initial value
+/// 0xFFFF.  It's computed bit by bit to avoid a 512 byte table.
+pub fn crc16_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ ((byte as u16) << 8);
+
+    for _ in 0..8 {
+        if crc & 0x8000 != 0 {
+            crc = (crc << 1) ^ 0x1021;
+        } else {
+            crc <<= 1;
+        }
+    }
+
+    crc
+}
+
+/// The initial value of the CRC-16
+pub const CRC16_INIT: u16 = 0xFFFF;
+
+/// The magic number at the start of a program image
+pub const PROGRAM_MAGIC: [u8; 2] = [b'B', b'A'];
+
+/// The current program image version
+pub const PROGRAM_VERSION: u8 = 1;
+
+/// The size of the program image header
+pub const PROGRAM_HEADER_SIZE: u16 = 8;
+
+/// The autorun bit in the flags byte
+pub const FLAG_AUTORUN: u8 = 0x01;
+
+/// The header of a saved program
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct ProgramHeader {
+    /// The image version
+    pub version: u8,
+    /// The flags byte
+    pub flags: u8,
+    /// The length of the program in bytes
+    pub length: u16,
+    /// The CRC-16 stored in the image
+    pub crc: u16,
+}
+
+impl ProgramHeader {
+    /// Test if the autorun flag is set
+    pub fn autorun(&self) -> bool {
+        self.flags & FLAG_AUTORUN != 0
+    }
+
+    /// Start a CRC over the header fields
+    fn crc_start(&self) -> u16 {
+        let mut crc = CRC16_INIT;
+        crc = crc16_update(crc, self.version);
+        crc = crc16_update(crc, self.flags);
+        crc = crc16_update(crc, self.length as u8);
+        crc16_update(crc, (self.length >> 8) as u8)
+    }
+}
+
+/// Read and check the program header
+///
+/// This checks the magic number and version, not the CRC.
+pub fn read_header<S: Storage>(storage: &mut S) -> Result<ProgramHeader, Error> {
+    if storage.read_byte(0)? != PROGRAM_MAGIC[0] || storage.read_byte(1)? != PROGRAM_MAGIC[1] {
+        return Err(Error::new(ErrorKind::NoProgram));
+    }
+
+    let header = ProgramHeader {
+        version: storage.read_byte(2)?,
+        flags: storage.read_byte(3)?,
+        length: storage.read_byte(4)? as u16 | ((storage.read_byte(5)? as u16) << 8),
+        crc: storage.read_byte(6)? as u16 | ((storage.read_byte(7)? as u16) << 8),
+    };
+
+    if header.version != PROGRAM_VERSION {
+        return Err(Error::new(ErrorKind::UnsupportedVersion));
+    }
+
+    if header.length > storage.capacity().saturating_sub(PROGRAM_HEADER_SIZE) {
+        return Err(Error::new(ErrorKind::ChecksumMismatch));
+    }
+
+    Ok(header)
+}
+
+/// Write the program header
+///
+/// The magic number is written last, byte 0 after byte 1.  save
+/// erases byte 0 first, so the image only becomes valid once the rest
+/// of the header is in place.
+fn write_header<S: Storage>(storage: &mut S, header: &ProgramHeader) -> Result<(), Error> {
+    storage.update_byte(2, header.version)?;
+    storage.update_byte(3, header.flags)?;
+    storage.update_byte(4, header.length as u8)?;
+    storage.update_byte(5, (header.length >> 8) as u8)?;
+    storage.update_byte(6, header.crc as u8)?;
+    storage.update_byte(7, (header.crc >> 8) as u8)?;
+    storage.update_byte(1, PROGRAM_MAGIC[1])?;
+    storage.update_byte(0, PROGRAM_MAGIC[0])
+}
+
+/// Compute the CRC of a saved program from storage
+fn stored_crc<S: Storage>(storage: &mut S, header: &ProgramHeader) -> Result<u16, Error> {
+    let mut crc = header.crc_start();
+
+    for i in 0..header.length {
+        crc = crc16_update(crc, storage.read_byte(PROGRAM_HEADER_SIZE + i)?);
+    }
+
+    Ok(crc)
+}
+
+/// Save a program
+///
+/// The program is written first and the header last.  The magic
+/// number is erased before the program is written, so a save
+/// interrupted by a power loss leaves no image instead of a corrupted
+/// one.
+pub fn save<S: Storage>(storage: &mut S, program: &[u8], autorun: bool) -> Result<(), Error> {
+    if program.len() > storage.capacity().saturating_sub(PROGRAM_HEADER_SIZE) as usize {
+        return Err(Error::new(ErrorKind::ProgramTooLarge));
+    }
+
+    let mut header = ProgramHeader {
+        version: PROGRAM_VERSION,
+        flags: if autorun { FLAG_AUTORUN } else { 0 },
+        length: program.len() as u16,
+        crc: 0,
+    };
+
+    let mut crc = header.crc_start();
+
+    storage.update_byte(0, 0xFF)?;
+
+    for (i, b) in program.iter().enumerate() {
+        storage.update_byte(PROGRAM_HEADER_SIZE + i as u16, *b)?;
+        crc = crc16_update(crc, *b);
+    }
+
+    header.crc = crc;
+
+    write_header(storage, &header)
+}
+
+/// Load a saved program into buffer
+///
+/// Returns the header of the program, the program length is in the
+/// header.  Images with a bad magic number, version or CRC are
+/// refused.
+pub fn load<S: Storage>(storage: &mut S, buffer: &mut [u8]) -> Result<ProgramHeader, Error> {
+    let header = read_header(storage)?;
+
+    if header.length as usize > buffer.len() {
+        return Err(Error::new(ErrorKind::BufferTooSmall));
+    }
+
+    let mut crc = header.crc_start();
+
+    for i in 0..header.length {
+        let b = storage.read_byte(PROGRAM_HEADER_SIZE + i)?;
+        buffer[i as usize] = b;
+        crc = crc16_update(crc, b);
+    }
+
+    if crc != header.crc {
+        return Err(Error::new(ErrorKind::ChecksumMismatch));
+    }
+
+    Ok(header)
+}
+
+/// Test if there is a valid saved program with the autorun flag set
+///
+/// This is what the firmware checks at startup to decide whether to
+/// run the saved program.
+pub fn autorun<S: Storage>(storage: &mut S) -> bool {
+    match read_header(storage) {
+        Ok(header) => header.autorun() && stored_crc(storage, &header) == Ok(header.crc),
+        Err(_) => false,
+    }
+}
+
+/// Set or clear the autorun flag of the saved program
+///
+/// The program has to pass the CRC check, the CRC is recomputed with
+/// the new flags.
+pub fn set_autorun<S: Storage>(storage: &mut S, autorun: bool) -> Result<(), Error> {
+    let mut header = read_header(storage)?;
+
+    if stored_crc(storage, &header)? != header.crc {
+        return Err(Error::new(ErrorKind::ChecksumMismatch));
+    }
+
+    if autorun {
+        header.flags |= FLAG_AUTORUN;
+    } else {
+        header.flags &= !FLAG_AUTORUN;
+    }
+
+    header.crc = stored_crc(storage, &header)?;
+
+    write_header(storage, &header)
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+///
+/// These use a RamStorage so the real EEPROM isn't worn out by test
+/// runs.
+pub mod tests {
+    use crate::{
+        eeprom::{
+            autorun, load, read_header, save, set_autorun, Error, ErrorKind, RamStorage, Storage,
+            PROGRAM_HEADER_SIZE,
+        },
+        testing::{run_suite, TestCase, TestReporter},
+        tests::write_test_result,
+    };
+
+    /// A small tokenized program to save
+    const PROGRAM: [u8; 12] = [
+        0x0A, 0x00, 0x81, b'A', b'=', b'1', 0x00, 0x14, 0x00, 0x80, 0x0A, 0x00,
+    ];
+
+    /// A storage that loses power after a number of writes
+    ///
+    /// Writes after that fail and don't change anything.
+    struct PowerLoss<'a> {
+        storage: RamStorage<'a>,
+        writes_left: usize,
+    }
+
+    impl<'a> Storage for PowerLoss<'a> {
+        fn capacity(&self) -> u16 {
+            self.storage.capacity()
+        }
+
+        fn read_byte(&mut self, address: u16) -> Result<u8, Error> {
+            self.storage.read_byte(address)
+        }
+
+        fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+            if self.writes_left == 0 {
+                return Err(Error::new(ErrorKind::Unknown));
+            }
+            self.writes_left -= 1;
+            self.storage.write_byte(address, value)
+        }
+    }
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("save_load_works", test_eeprom_save_load_works),
+        TestCase::new("load_empty_fails", test_eeprom_load_empty_fails),
+        TestCase::new(
+            "load_corrupted_program_fails",
+            test_eeprom_load_corrupted_program_fails,
+        ),
+        TestCase::new(
+            "load_corrupted_length_fails",
+            test_eeprom_load_corrupted_length_fails,
+        ),
+        TestCase::new("save_too_large_fails", test_eeprom_save_too_large_fails),
+        TestCase::new(
+            "load_small_buffer_fails",
+            test_eeprom_load_small_buffer_fails,
+        ),
+        TestCase::new("autorun_works", test_eeprom_autorun_works),
+        TestCase::new(
+            "interrupted_save_never_corrupts",
+            test_eeprom_interrupted_save_never_corrupts,
+        ),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "eeprom", TEST_CASES);
+    }
+
+    /// Test that a saved program can be loaded
+    pub fn test_eeprom_save_load_works(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 32] = [0; 32];
+        let mut storage = RamStorage::new(&mut data);
+
+        let res = save(&mut storage, &PROGRAM, false);
+        write_test_result(reporter, res.is_ok(), "should save program");
+
+        let mut buffer: [u8; 16] = [0; 16];
+        let res = load(&mut storage, &mut buffer);
+        match res {
+            Ok(header) => {
+                write_test_result(
+                    reporter,
+                    header.length as usize == PROGRAM.len(),
+                    "loaded program should have the saved length",
+                );
+                write_test_result(
+                    reporter,
+                    buffer[..PROGRAM.len()] == PROGRAM,
+                    "loaded program should equal the saved program",
+                );
+            }
+            Err(_e) => {
+                write_test_result(reporter, false, "should load saved program");
+            }
+        }
+    }
+
+    /// Test that loading from erased storage fails
+    pub fn test_eeprom_load_empty_fails(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 32] = [0; 32];
+        let mut storage = RamStorage::new(&mut data);
+
+        let mut buffer: [u8; 16] = [0; 16];
+        let res = load(&mut storage, &mut buffer);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::NoProgram,
+                    "load from empty storage should fail with NoProgram",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "load from empty storage should fail");
+            }
+        }
+    }
+
+    /// Test that a corrupted program byte is detected
+    pub fn test_eeprom_load_corrupted_program_fails(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 32] = [0; 32];
+        let mut storage = RamStorage::new(&mut data);
+
+        let _res = save(&mut storage, &PROGRAM, false);
+
+        // Flip a bit in the middle of the program
+        let b = storage.read_byte(PROGRAM_HEADER_SIZE + 4).unwrap();
+        let _res = storage.write_byte(PROGRAM_HEADER_SIZE + 4, b ^ 0x10);
+
+        let mut buffer: [u8; 16] = [0; 16];
+        let res = load(&mut storage, &mut buffer);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::ChecksumMismatch,
+                    "load of corrupted program should fail with ChecksumMismatch",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "load of corrupted program should fail");
+            }
+        }
+    }
+
+    /// Test that a corrupted length in the header is detected
+    pub fn test_eeprom_load_corrupted_length_fails(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 32] = [0; 32];
+        let mut storage = RamStorage::new(&mut data);
+
+        let _res = save(&mut storage, &PROGRAM, false);
+
+        // Shorten the program by one byte
+        let _res = storage.write_byte(4, PROGRAM.len() as u8 - 1);
+
+        let mut buffer: [u8; 16] = [0; 16];
+        let res = load(&mut storage, &mut buffer);
+        write_test_result(
+            reporter,
+            res.is_err(),
+            "load with corrupted length should fail",
+        );
+    }
+
+    /// Test that saving a program larger than the storage fails
+    pub fn test_eeprom_save_too_large_fails(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 16] = [0; 16];
+        let mut storage = RamStorage::new(&mut data);
+
+        let res = save(&mut storage, &PROGRAM, false);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::ProgramTooLarge,
+                    "save of large program should fail with ProgramTooLarge",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "save of large program should fail");
+            }
+        }
+
+        let res = read_header(&mut storage);
+        write_test_result(
+            reporter,
+            res.is_err(),
+            "failed save should not leave a program behind",
+        );
+    }
+
+    /// Test that loading into a buffer that's too small fails
+    pub fn test_eeprom_load_small_buffer_fails(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 32] = [0; 32];
+        let mut storage = RamStorage::new(&mut data);
+
+        let _res = save(&mut storage, &PROGRAM, false);
+
+        let mut buffer: [u8; 4] = [0; 4];
+        let res = load(&mut storage, &mut buffer);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::BufferTooSmall,
+                    "load into small buffer should fail with BufferTooSmall",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "load into small buffer should fail");
+            }
+        }
+    }
+
+    /// Test that the autorun flag can be set, cleared and is refused
+    /// on a corrupted image
+    pub fn test_eeprom_autorun_works(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 32] = [0; 32];
+        let mut storage = RamStorage::new(&mut data);
+
+        write_test_result(
+            reporter,
+            !autorun(&mut storage),
+            "empty storage should not autorun",
+        );
+
+        let _res = save(&mut storage, &PROGRAM, true);
+        write_test_result(
+            reporter,
+            autorun(&mut storage),
+            "program saved with autorun should autorun",
+        );
+
+        let res = set_autorun(&mut storage, false);
+        write_test_result(reporter, res.is_ok(), "should clear autorun flag");
+        write_test_result(
+            reporter,
+            !autorun(&mut storage),
+            "program should not autorun after clearing flag",
+        );
+
+        let res = set_autorun(&mut storage, true);
+        write_test_result(reporter, res.is_ok(), "should set autorun flag");
+
+        let _res = storage.write_byte(PROGRAM_HEADER_SIZE, 0x00);
+        write_test_result(
+            reporter,
+            !autorun(&mut storage),
+            "corrupted program should not autorun",
+        );
+    }
+
+    /// Test that a save interrupted at any write leaves the old
+    /// program, no program or the whole new one, never a corrupted
+    /// image
+    pub fn test_eeprom_interrupted_save_never_corrupts(reporter: &mut dyn TestReporter) {
+        const OTHER: [u8; 4] = [0x0A, 0x00, 0x80, 0x0A];
+        let mut ok = true;
+
+        // Enough writes for every byte of the header and the program
+        for writes in 0..=PROGRAM_HEADER_SIZE as usize + PROGRAM.len() {
+            let mut data: [u8; 32] = [0; 32];
+            let mut storage = PowerLoss {
+                storage: RamStorage::new(&mut data),
+                writes_left: usize::MAX,
+            };
+            let _res = save(&mut storage, &OTHER, false);
+
+            storage.writes_left = writes;
+            let _res = save(&mut storage, &PROGRAM, false);
+
+            let mut buffer: [u8; 16] = [0; 16];
+            ok &= match load(&mut storage, &mut buffer) {
+                Ok(header) => {
+                    let loaded = &buffer[..header.length as usize];
+                    loaded == PROGRAM || loaded == OTHER
+                }
+                Err(e) => e.kind == ErrorKind::NoProgram,
+            };
+        }
+
+        write_test_result(
+            reporter,
+            ok,
+            "an interrupted save should never leave a corrupted image",
+        );
+    }
+}
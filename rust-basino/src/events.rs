@@ -0,0 +1,681 @@
+//! Interrupt driven events
+//!
+//! These are the building blocks for the BASIC ON TIMER GOSUB and ON
+//! PIN GOSUB statements.  A program registers the line number of a
+//! subroutine for an event.  The interrupt handlers don't run any
+//! BASIC code, they only put the event ID into a basino queue.  The
+//! interpreter takes events off the queue with poll between
+//! statements and runs the subroutine for each one.
+//!
+//! Events are only queued if a handler is registered for them.  If
+//! the queue is full the event is dropped and an overflow counter is
+//! incremented, so the program can report lost events instead of
+//! missing them silently.
+//!
+//! The periodic timer is checked on every Timer0 overflow of the
+//! system tick in the tick module, against the tick's millisecond
+//! count.  Timer0 overflows every 1.024 ms at 16 MHz, so an event can
+//! be up to one overflow late, but the period doesn't drift.
+#![warn(missing_docs)]
+
+use crate::{
+    hardware::{port_pin, read_reg, update_reg, NUM_PINS, PINB, PINC, PIND},
+    queue::QueueImpl,
+    tick, Queue,
+};
+
+use avr_device::interrupt::{free, Mutex};
+use core::{
+    cell::{Cell, RefCell},
+    fmt::{Debug, Display, Formatter},
+};
+use ufmt::{uDebug, uWrite};
+
+/// The kinds of errors that can occur working with events
+#[derive(Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The event ID doesn't exist
+    InvalidEvent,
+    /// The pin doesn't exist or can't be watched
+    InvalidPin,
+    /// The timer period is zero
+    InvalidPeriod,
+    /// The event queue couldn't be initialized
+    QueueError,
+    /// An unknown error type
+    Unknown,
+}
+
+impl uDebug for ErrorKind {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        match self {
+            ErrorKind::InvalidEvent => f.write_str("The event doesn't exist"),
+            ErrorKind::InvalidPin => f.write_str("The pin can't be watched"),
+            ErrorKind::InvalidPeriod => f.write_str("The timer period is invalid"),
+            ErrorKind::QueueError => f.write_str("The event queue couldn't be initialized"),
+            ErrorKind::Unknown => f.write_str("An unknown error occurred"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ErrorKind::InvalidEvent => write!(f, "The event doesn't exist"),
+            ErrorKind::InvalidPin => write!(f, "The pin can't be watched"),
+            ErrorKind::InvalidPeriod => write!(f, "The timer period is invalid"),
+            ErrorKind::QueueError => write!(f, "The event queue couldn't be initialized"),
+            ErrorKind::Unknown => write!(f, "An unknown error occurred"),
+        }
+    }
+}
+
+/// An error that can occur when working with events
+#[derive(PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// The event ID of the periodic timer
+pub const EVENT_TIMER: u8 = 0;
+
+/// The event ID of the first pin change event
+/// The event for pin n is EVENT_PIN_BASE + n
+pub const EVENT_PIN_BASE: u8 = 1;
+
+/// The number of event IDs
+pub const NUM_EVENTS: usize = EVENT_PIN_BASE as usize + NUM_PINS as usize;
+
+/// The length of the event queue array
+pub const EVENT_QUEUE_LEN: usize = 8;
+
+// Interrupt register addresses
+const PCICR: usize = 0x68;
+const PCMSK0: usize = 0x6B;
+const PCMSK1: usize = 0x6C;
+const PCMSK2: usize = 0x6D;
+
+/// The array the event queue stores event IDs in
+#[link_section = ".ram2bss"]
+pub(crate) static mut BASINO_EVENT_QUEUE_DATA: [u8; EVENT_QUEUE_LEN] = [0; EVENT_QUEUE_LEN];
+
+/// The event queue shared between the interrupt handlers and the
+/// interpreter.
+///
+/// This can't be in a Mutex, the queue holds raw pointers.  It's
+/// only accessed inside interrupt::free or from an interrupt handler.
+#[link_section = ".ram2bss"]
+pub(crate) static mut BASINO_EVENT_QUEUE: Queue<'static> = Queue::uninit();
+
+/// The line number of the handler for each event, zero if there is
+/// no handler
+static EVENT_HANDLERS: Mutex<RefCell<[u16; NUM_EVENTS]>> =
+    Mutex::new(RefCell::new([0; NUM_EVENTS]));
+
+/// The number of events dropped because the queue was full
+static EVENT_OVERFLOWS: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+/// The timer period in milliseconds, zero if the timer is stopped
+static TIMER_PERIOD: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+/// The tick time in milliseconds of the next timer event
+static TIMER_NEXT: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// The last state of the PINB, PINC and PIND registers, used to find
+/// the pins that changed in the pin change interrupts
+static PIN_STATE: Mutex<Cell<[u8; 3]>> = Mutex::new(Cell::new([0; 3]));
+
+/// Initialize the event system
+///
+/// This clears the event queue, the handlers and the overflow count.
+/// It must be called before any of the other functions.
+pub fn init() -> Result<(), Error> {
+    stop_timer();
+
+    free(|cs| {
+        EVENT_HANDLERS.borrow(cs).replace([0; NUM_EVENTS]);
+        EVENT_OVERFLOWS.borrow(cs).set(0);
+
+        let res = unsafe {
+            (*core::ptr::addr_of_mut!(BASINO_EVENT_QUEUE)).init(
+                core::ptr::addr_of_mut!(BASINO_EVENT_QUEUE_DATA) as *mut u8,
+                EVENT_QUEUE_LEN,
+            )
+        };
+
+        res.map_err(|_| Error::new(ErrorKind::QueueError))
+    })
+}
+
+/// Set the handler line number for an event
+///
+/// A line of zero removes the handler.  Events that are already in
+/// the queue for a removed handler are skipped by poll.
+pub fn set_handler(event: u8, line: u16) -> Result<(), Error> {
+    if event as usize >= NUM_EVENTS {
+        return Err(Error::new(ErrorKind::InvalidEvent));
+    }
+
+    free(|cs| {
+        EVENT_HANDLERS.borrow(cs).borrow_mut()[event as usize] = line;
+    });
+
+    Ok(())
+}
+
+/// Get the handler line number for an event, zero if there is none
+pub fn handler(event: u8) -> Result<u16, Error> {
+    if event as usize >= NUM_EVENTS {
+        return Err(Error::new(ErrorKind::InvalidEvent));
+    }
+
+    Ok(free(|cs| {
+        EVENT_HANDLERS.borrow(cs).borrow()[event as usize]
+    }))
+}
+
+/// Post an event to the queue
+///
+/// This is called from the interrupt handlers.  The event is dropped
+/// if it has no handler.  If the queue is full the overflow count is
+/// incremented.
+pub fn post_event(event: u8) {
+    if event as usize >= NUM_EVENTS {
+        return;
+    }
+
+    free(|cs| {
+        if EVENT_HANDLERS.borrow(cs).borrow()[event as usize] == 0 {
+            return;
+        }
+
+        let res = unsafe { (*core::ptr::addr_of_mut!(BASINO_EVENT_QUEUE)).put(event) };
+        if res.is_err() {
+            let overflows = EVENT_OVERFLOWS.borrow(cs);
+            overflows.set(overflows.get().saturating_add(1));
+        }
+    });
+}
+
+/// Get the next event ID from the queue
+pub fn next_event() -> Option<u8> {
+    free(|_cs| unsafe { (*core::ptr::addr_of_mut!(BASINO_EVENT_QUEUE)).get() }.ok())
+}
+
+/// Get the next event that has a handler
+///
+/// Returns the event ID and the line number of its handler.  This is
+/// called by the interpreter between statements.
+pub fn poll() -> Option<(u8, u16)> {
+    while let Some(event) = next_event() {
+        if let Ok(line) = handler(event) {
+            if line != 0 {
+                return Some((event, line));
+            }
+        }
+    }
+
+    None
+}
+
+/// Get the number of events dropped because the queue was full
+pub fn overflows() -> u16 {
+    free(|cs| EVENT_OVERFLOWS.borrow(cs).get())
+}
+
+/// Get the number of dropped events and reset the count to zero
+pub fn take_overflows() -> u16 {
+    free(|cs| EVENT_OVERFLOWS.borrow(cs).replace(0))
+}
+
+/// Start the periodic timer
+///
+/// period is the time between timer events in milliseconds.  This
+/// starts the system tick if it isn't running.
+pub fn start_timer(period: u16) -> Result<(), Error> {
+    if period == 0 {
+        return Err(Error::new(ErrorKind::InvalidPeriod));
+    }
+
+    free(|cs| {
+        TIMER_PERIOD.borrow(cs).set(period);
+        TIMER_NEXT
+            .borrow(cs)
+            .set(tick::millis().wrapping_add(period as u32));
+    });
+
+    tick::init();
+
+    Ok(())
+}
+
+/// Stop the periodic timer
+///
+/// The system tick keeps running.
+pub fn stop_timer() {
+    free(|cs| {
+        TIMER_PERIOD.borrow(cs).set(0);
+    });
+}
+
+/// Find the PCMSKx register and PCICR bit for a port
+fn pin_change_mask(port: usize) -> (usize, u8) {
+    match port {
+        PINB => (PCMSK0, 0),
+        PINC => (PCMSK1, 1),
+        _ => (PCMSK2, 2),
+    }
+}
+
+/// Find the index into PIN_STATE for a port
+fn port_index(port: usize) -> usize {
+    match port {
+        PINB => 0,
+        PINC => 1,
+        _ => 2,
+    }
+}
+
+/// Post an event when a pin changes state
+///
+/// The pin can be an input or an output, the pin change interrupt
+/// fires for both.
+pub fn watch_pin(pin: u8) -> Result<(), Error> {
+    let p = port_pin(pin).map_err(|_| Error::new(ErrorKind::InvalidPin))?;
+    let (pcmsk, pcie) = pin_change_mask(p.port);
+
+    free(|cs| {
+        let state = PIN_STATE.borrow(cs);
+        let mut ports = state.get();
+        ports[port_index(p.port)] = read_reg(p.port);
+        state.set(ports);
+    });
+
+    update_reg(pcmsk, 1 << p.bit, true);
+    update_reg(PCICR, 1 << pcie, true);
+
+    Ok(())
+}
+
+/// Stop posting events for a pin
+pub fn unwatch_pin(pin: u8) -> Result<(), Error> {
+    let p = port_pin(pin).map_err(|_| Error::new(ErrorKind::InvalidPin))?;
+    let (pcmsk, pcie) = pin_change_mask(p.port);
+
+    update_reg(pcmsk, 1 << p.bit, false);
+    if read_reg(pcmsk) == 0 {
+        update_reg(PCICR, 1 << pcie, false);
+    }
+
+    Ok(())
+}
+
+/// Post events for the pins in a port that changed
+///
+/// first_pin is the Arduino pin number of bit zero of the port.
+fn pin_change(port: usize, pcmsk: usize, first_pin: u8) {
+    let value = read_reg(port);
+
+    let changed = free(|cs| {
+        let state = PIN_STATE.borrow(cs);
+        let mut ports = state.get();
+        let index = port_index(port);
+        let changed = (ports[index] ^ value) & read_reg(pcmsk);
+        ports[index] = value;
+        state.set(ports);
+        changed
+    });
+
+    for bit in 0..8 {
+        if changed & (1 << bit) != 0 {
+            post_event(EVENT_PIN_BASE + first_pin + bit);
+        }
+    }
+}
+
+/// Check the periodic timer
+///
+/// This is called from the Timer0 overflow interrupt in the tick
+/// module, after the millisecond count has been updated.  The next
+/// event time is advanced by the period rather than set from the
+/// current time, so the late overflows don't add up.
+pub(crate) fn timer_overflow() {
+    let fire = free(|cs| {
+        let period = TIMER_PERIOD.borrow(cs).get();
+        if period == 0 {
+            return false;
+        }
+
+        let now = tick::millis();
+        let next = TIMER_NEXT.borrow(cs);
+        if tick::is_after(next.get(), now) {
+            return false;
+        }
+
+        next.set(next.get().wrapping_add(period as u32));
+        // If the events fell more than a period behind, start again
+        // from now instead of firing on every overflow to catch up
+        if !tick::is_after(next.get(), now) {
+            next.set(now.wrapping_add(period as u32));
+        }
+        true
+    });
+
+    if fire {
+        post_event(EVENT_TIMER);
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT0() {
+    pin_change(PINB, PCMSK0, 8);
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT1() {
+    pin_change(PINC, PCMSK1, 14);
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT2() {
+    pin_change(PIND, PCMSK2, 0);
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+pub mod tests {
+    use crate::{
+        events::{
+            handler, init, next_event, overflows, poll, post_event, set_handler, start_timer,
+            stop_timer, take_overflows, unwatch_pin, watch_pin, ErrorKind, EVENT_PIN_BASE,
+            EVENT_QUEUE_LEN, EVENT_TIMER, NUM_EVENTS,
+        },
+        hardware::{digital_write, pin_mode, PinMode},
+        testing::{run_suite, TestCase, TestReporter},
+        tests::write_test_result,
+        tick::{elapsed, millis},
+    };
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("set_handler_works", test_events_set_handler_works),
+        TestCase::new(
+            "set_handler_invalid_event_fails",
+            test_events_set_handler_invalid_event_fails,
+        ),
+        TestCase::new("post_and_poll_works", test_events_post_and_poll_works),
+        TestCase::new(
+            "unhandled_event_dropped",
+            test_events_unhandled_event_dropped,
+        ),
+        TestCase::new("overflow_counted", test_events_overflow_counted),
+        TestCase::new(
+            "start_timer_zero_period_fails",
+            test_events_start_timer_zero_period_fails,
+        ),
+        TestCase::new("timer_fires", test_events_timer_fires),
+        TestCase::new("timer_period_is_millis", test_events_timer_period_is_millis),
+        TestCase::new(
+            "watch_reserved_pin_fails",
+            test_events_watch_reserved_pin_fails,
+        ),
+        TestCase::new("pin_change_fires", test_events_pin_change_fires),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "events", TEST_CASES);
+    }
+
+    /// Test that a handler can be set and read back
+    pub fn test_events_set_handler_works(reporter: &mut dyn TestReporter) {
+        write_test_result(reporter, init().is_ok(), "init should work");
+
+        let res = set_handler(EVENT_TIMER, 100);
+        write_test_result(reporter, res.is_ok(), "set_handler should work");
+
+        let res = handler(EVENT_TIMER);
+        write_test_result(
+            reporter,
+            matches!(res, Ok(100)),
+            "handler should return the line",
+        );
+    }
+
+    /// Test that setting a handler for an event that doesn't exist fails
+    pub fn test_events_set_handler_invalid_event_fails(reporter: &mut dyn TestReporter) {
+        let res = set_handler(NUM_EVENTS as u8, 100);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::InvalidEvent,
+                    "set_handler with an invalid event should fail",
+                );
+            }
+            Ok(_) => {
+                write_test_result(
+                    reporter,
+                    false,
+                    "set_handler with an invalid event should fail",
+                );
+            }
+        }
+    }
+
+    /// Test that a posted event is returned by poll with its handler
+    pub fn test_events_post_and_poll_works(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+        set_handler(EVENT_TIMER, 100).unwrap();
+
+        post_event(EVENT_TIMER);
+
+        let res = poll();
+        write_test_result(
+            reporter,
+            res == Some((EVENT_TIMER, 100)),
+            "poll should return the posted event and handler",
+        );
+
+        let res = poll();
+        write_test_result(reporter, res.is_none(), "poll should return nothing");
+    }
+
+    /// Test that an event without a handler isn't queued
+    pub fn test_events_unhandled_event_dropped(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+
+        post_event(EVENT_PIN_BASE + 2);
+
+        let res = next_event();
+        write_test_result(
+            reporter,
+            res.is_none(),
+            "an event without a handler shouldn't be queued",
+        );
+    }
+
+    /// Test that events posted to a full queue are counted
+    pub fn test_events_overflow_counted(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+        set_handler(EVENT_TIMER, 100).unwrap();
+
+        for _ in 0..EVENT_QUEUE_LEN * 2 {
+            post_event(EVENT_TIMER);
+        }
+
+        write_test_result(
+            reporter,
+            overflows() > 0,
+            "posting to a full queue should count overflows",
+        );
+
+        let count = take_overflows();
+        write_test_result(
+            reporter,
+            count > 0 && overflows() == 0,
+            "take_overflows should reset the count",
+        );
+
+        while poll().is_some() {}
+    }
+
+    /// Test that starting the timer with a zero period fails
+    pub fn test_events_start_timer_zero_period_fails(reporter: &mut dyn TestReporter) {
+        let res = start_timer(0);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::InvalidPeriod,
+                    "start_timer with a zero period should fail",
+                );
+            }
+            Ok(_) => {
+                write_test_result(
+                    reporter,
+                    false,
+                    "start_timer with a zero period should fail",
+                );
+            }
+        }
+    }
+
+    /// Test that the timer posts an event
+    pub fn test_events_timer_fires(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+        set_handler(EVENT_TIMER, 200).unwrap();
+
+        start_timer(2).unwrap();
+        unsafe { avr_device::interrupt::enable() };
+
+        let mut res = None;
+        for _ in 0..10000 {
+            res = poll();
+            if res.is_some() {
+                break;
+            }
+            arduino_hal::delay_us(10);
+        }
+
+        avr_device::interrupt::disable();
+        stop_timer();
+
+        write_test_result(
+            reporter,
+            res == Some((EVENT_TIMER, 200)),
+            "the timer should post an event",
+        );
+    }
+
+    /// Wait up to timeout milliseconds for an event
+    ///
+    /// Returns the event and the tick time it was taken off the queue.
+    fn wait_event(timeout: u32) -> Option<((u8, u16), u32)> {
+        let start = millis();
+        while elapsed(millis(), start) < timeout {
+            if let Some(event) = poll() {
+                return Some((event, millis()));
+            }
+        }
+        None
+    }
+
+    /// Test that the timer period is counted in milliseconds
+    pub fn test_events_timer_period_is_millis(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+        set_handler(EVENT_TIMER, 200).unwrap();
+
+        start_timer(100).unwrap();
+        unsafe { avr_device::interrupt::enable() };
+
+        let first = wait_event(200);
+        let second = wait_event(200);
+
+        avr_device::interrupt::disable();
+        stop_timer();
+
+        match (first, second) {
+            (Some((a, t0)), Some((b, t1))) => {
+                write_test_result(
+                    reporter,
+                    a == (EVENT_TIMER, 200) && b == (EVENT_TIMER, 200),
+                    "the timer should post timer events",
+                );
+                write_test_result(
+                    reporter,
+                    (99..=101).contains(&elapsed(t1, t0)),
+                    "a 100 ms timer should fire every 100 ms",
+                );
+            }
+            _ => {
+                write_test_result(reporter, false, "a 100 ms timer should fire every 100 ms");
+            }
+        }
+    }
+
+    /// Test that watching a pin used by USART0 fails
+    pub fn test_events_watch_reserved_pin_fails(reporter: &mut dyn TestReporter) {
+        let res = watch_pin(0);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::InvalidPin,
+                    "watch_pin on a reserved pin should fail",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "watch_pin on a reserved pin should fail");
+            }
+        }
+    }
+
+    /// Test that changing an output pin posts a pin change event
+    pub fn test_events_pin_change_fires(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+        set_handler(EVENT_PIN_BASE + 7, 300).unwrap();
+
+        pin_mode(7, PinMode::Output).unwrap();
+        digital_write(7, false).unwrap();
+
+        watch_pin(7).unwrap();
+        unsafe { avr_device::interrupt::enable() };
+
+        digital_write(7, true).unwrap();
+        arduino_hal::delay_us(10);
+
+        avr_device::interrupt::disable();
+        unwatch_pin(7).unwrap();
+
+        let res = poll();
+        write_test_result(
+            reporter,
+            res == Some((EVENT_PIN_BASE + 7, 300)),
+            "changing a watched pin should post an event",
+        );
+    }
+}
@@ -0,0 +1,660 @@
+//! Hardware access functions
+//!
+//! These functions map Arduino Uno pin numbers to ATmega328P ports at
+//! runtime.  They are the building blocks for BASIC statements like
+//! PINMODE, DWRITE, DREAD, AREAD and PWM.
+//!
+//! The arduino_hal pin types are checked at compile time, which is
+//! what you want in a normal Rust program.  A BASIC program chooses
+//! pins at runtime, so this module talks to the registers directly.
+//! The register addresses are the memory-mapped addresses from the
+//! data sheet (DS40002061B), the same addresses the avr-device crate
+//! uses.
+//!
+//! Pin numbering follows the Arduino Uno:
+//!   D0 - D7:   PORTD bits 0 - 7
+//!   D8 - D13:  PORTB bits 0 - 5
+//!   A0 - A5:   PORTC bits 0 - 5, also numbered 14 - 19
+//!
+//! D0 and D1 are used by USART0 for the serial console, so they are
+//! rejected.
+#![warn(missing_docs)]
+
+use avr_device::interrupt::free;
+use core::fmt::{Debug, Display, Formatter};
+use ufmt::{uDebug, uWrite};
+
+/// The kinds of errors that can occur working with hardware
+#[derive(Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The pin number doesn't exist on the board
+    InvalidPin,
+    /// The pin is in use by another peripheral, for example USART0
+    PinReserved,
+    /// The pin isn't connected to the ADC
+    NotAnalogPin,
+    /// The pin isn't connected to a timer output compare unit
+    NotPwmPin,
+    /// An unknown error type
+    Unknown,
+}
+
+impl uDebug for ErrorKind {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        match self {
+            ErrorKind::InvalidPin => f.write_str("The pin doesn't exist"),
+            ErrorKind::PinReserved => f.write_str("The pin is reserved by another peripheral"),
+            ErrorKind::NotAnalogPin => f.write_str("The pin is not an analog input"),
+            ErrorKind::NotPwmPin => f.write_str("The pin does not support PWM"),
+            ErrorKind::Unknown => f.write_str("An unknown error occurred"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ErrorKind::InvalidPin => write!(f, "The pin doesn't exist"),
+            ErrorKind::PinReserved => write!(f, "The pin is reserved by another peripheral"),
+            ErrorKind::NotAnalogPin => write!(f, "The pin is not an analog input"),
+            ErrorKind::NotPwmPin => write!(f, "The pin does not support PWM"),
+            ErrorKind::Unknown => write!(f, "An unknown error occurred"),
+        }
+    }
+}
+
+/// An error that can occur when working with hardware
+#[derive(PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+// Port register addresses
+// Each port has three registers in a row: PINx, DDRx and PORTx
+// These are offsets from the PINx register.
+pub(crate) const PINB: usize = 0x23;
+pub(crate) const PINC: usize = 0x26;
+pub(crate) const PIND: usize = 0x29;
+
+const PIN_OFFSET: usize = 0;
+const DDR_OFFSET: usize = 1;
+const PORT_OFFSET: usize = 2;
+
+// ADC register addresses
+const ADCL: usize = 0x78;
+const ADCH: usize = 0x79;
+const ADCSRA: usize = 0x7A;
+const ADMUX: usize = 0x7C;
+
+// ADCSRA bits
+const ADEN: u8 = 7;
+const ADSC: u8 = 6;
+// ADMUX bits
+const REFS0: u8 = 6;
+
+// Timer/Counter register addresses
+pub(crate) const TCCR0A: usize = 0x44;
+pub(crate) const TCCR0B: usize = 0x45;
+const OCR0A: usize = 0x47;
+const OCR0B: usize = 0x48;
+pub(crate) const TCCR1A: usize = 0x80;
+pub(crate) const TCCR1B: usize = 0x81;
+const OCR1AL: usize = 0x88;
+const OCR1AH: usize = 0x89;
+const OCR1BL: usize = 0x8A;
+const OCR1BH: usize = 0x8B;
+const TCCR2A: usize = 0xB0;
+const TCCR2B: usize = 0xB1;
+const OCR2A: usize = 0xB3;
+const OCR2B: usize = 0xB4;
+
+/// The number of digital pins, D0 through D13 and A0 through A5
+pub const NUM_PINS: u8 = 20;
+
+/// The first pin number of the analog pins, A0
+pub const A0: u8 = 14;
+
+/// The pins with an output compare unit that pwm_write can use
+pub const PWM_PINS: [u8; 6] = [3, 5, 6, 9, 10, 11];
+
+/// The modes a digital pin can be set to
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum PinMode {
+    /// Input with the pull-up disabled
+    Input,
+    /// Input with the internal pull-up enabled
+    InputPullUp,
+    /// Output
+    Output,
+}
+
+/// A physical pin: the address of the PINx register of its port and
+/// the bit in the port.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct PortPin {
+    /// Address of the PINx register, DDRx and PORTx follow it
+    pub port: usize,
+    /// The bit in the port registers
+    pub bit: u8,
+}
+
+/// Find the port and bit for an Arduino Uno pin number
+///
+/// Returns an InvalidPin error if the pin doesn't exist and a
+/// PinReserved error if the pin is used by USART0.
+pub fn port_pin(pin: u8) -> Result<PortPin, Error> {
+    match pin {
+        0 | 1 => Err(Error::new(ErrorKind::PinReserved)),
+        2..=7 => Ok(PortPin {
+            port: PIND,
+            bit: pin,
+        }),
+        8..=13 => Ok(PortPin {
+            port: PINB,
+            bit: pin - 8,
+        }),
+        14..=19 => Ok(PortPin {
+            port: PINC,
+            bit: pin - A0,
+        }),
+        _ => Err(Error::new(ErrorKind::InvalidPin)),
+    }
+}
+
+/// Read a register
+pub(crate) fn read_reg(address: usize) -> u8 {
+    unsafe { core::ptr::read_volatile(address as *const u8) }
+}
+
+/// Write a register
+pub(crate) fn write_reg(address: usize, value: u8) {
+    unsafe { core::ptr::write_volatile(address as *mut u8, value) }
+}
+
+/// Set or clear bits in a register
+///
+/// Interrupts are disabled during the read-modify-write so an ISR
+/// can't change the register in between.
+pub(crate) fn update_reg(address: usize, mask: u8, set: bool) {
+    free(|_cs| {
+        let value = read_reg(address);
+        if set {
+            write_reg(address, value | mask);
+        } else {
+            write_reg(address, value & !mask);
+        }
+    });
+}
+
+/// Find the timer control register and COMnx1 bit that connect a pin
+/// to its output compare unit
+///
+/// Returns None for pins without PWM.
+fn compare_output(pin: u8) -> Option<(usize, u8)> {
+    match pin {
+        6 => Some((TCCR0A, 7)),
+        5 => Some((TCCR0A, 5)),
+        9 => Some((TCCR1A, 7)),
+        10 => Some((TCCR1A, 5)),
+        11 => Some((TCCR2A, 7)),
+        3 => Some((TCCR2A, 5)),
+        _ => None,
+    }
+}
+
+/// Disconnect a pin from its output compare unit
+///
+/// While the COMnx bits are set the timer drives the pin and PORTx
+/// has no effect on it.  pwm_write only ever sets COMnx1.
+fn pwm_off(pin: u8) {
+    if let Some((register, bit)) = compare_output(pin) {
+        update_reg(register, 1 << bit, false);
+    }
+}
+
+/// Set the mode of a digital pin
+///
+/// This turns off PWM on the pin.
+pub fn pin_mode(pin: u8, mode: PinMode) -> Result<(), Error> {
+    let p = port_pin(pin)?;
+    let mask = 1 << p.bit;
+
+    pwm_off(pin);
+
+    match mode {
+        PinMode::Input => {
+            update_reg(p.port + DDR_OFFSET, mask, false);
+            update_reg(p.port + PORT_OFFSET, mask, false);
+        }
+        PinMode::InputPullUp => {
+            update_reg(p.port + DDR_OFFSET, mask, false);
+            update_reg(p.port + PORT_OFFSET, mask, true);
+        }
+        PinMode::Output => {
+            update_reg(p.port + DDR_OFFSET, mask, true);
+        }
+    }
+
+    Ok(())
+}
+
+/// Set a digital output pin high or low
+///
+/// On an input pin this enables or disables the pull-up, the same as
+/// the hardware does.  This turns off PWM on the pin, like the
+/// Arduino core.
+pub fn digital_write(pin: u8, high: bool) -> Result<(), Error> {
+    let p = port_pin(pin)?;
+
+    pwm_off(pin);
+
+    update_reg(p.port + PORT_OFFSET, 1 << p.bit, high);
+
+    Ok(())
+}
+
+/// Read the state of a digital pin
+///
+/// This reads the PINx register, so it returns the level on the
+/// physical pin for both inputs and outputs.
+pub fn digital_read(pin: u8) -> Result<bool, Error> {
+    let p = port_pin(pin)?;
+
+    Ok(read_reg(p.port + PIN_OFFSET) & (1 << p.bit) != 0)
+}
+
+/// Read an analog input with the ADC
+///
+/// pin can be an analog channel number, 0 through 5, or an Arduino
+/// pin number, 14 (A0) through 19 (A5).
+///
+/// The reference is AVcc and the ADC clock is the system clock
+/// divided by 128, 125 kHz at 16 MHz.
+///
+/// Returns a 10-bit result.
+pub fn analog_read(pin: u8) -> Result<u16, Error> {
+    let channel = match pin {
+        0..=5 => pin,
+        14..=19 => pin - A0,
+        _ => return Err(Error::new(ErrorKind::NotAnalogPin)),
+    };
+
+    // AVcc reference, right adjusted result
+    write_reg(ADMUX, (1 << REFS0) | channel);
+
+    // Enable the ADC with a prescaler of 128 and start a conversion
+    write_reg(ADCSRA, (1 << ADEN) | (1 << ADSC) | 0x07);
+
+    while read_reg(ADCSRA) & (1 << ADSC) != 0 {}
+
+    // ADCL must be read first, reading it locks ADCH until ADCH is
+    // read.
+    let low = read_reg(ADCL) as u16;
+    let high = read_reg(ADCH) as u16;
+
+    Ok((high << 8) | low)
+}
+
+/// Set the PWM duty cycle on a pin
+///
+/// duty is from 0 (always low) to 255 (always high).  In fast PWM a
+/// compare value of 0 still gives a short pulse every period and 255
+/// a short gap, so like the Arduino core 0 and 255 turn PWM off and
+/// set the pin low or high instead.
+///
+/// The timers are put in 8-bit fast PWM mode with a prescaler of 64,
+/// about 976 Hz at 16 MHz.  This is the same setup the Arduino core
+/// uses, so Timer0 can still be used as a system tick.
+///
+/// PWM is available on these pins:
+///   Timer0: D6 (OC0A), D5 (OC0B)
+///   Timer1: D9 (OC1A), D10 (OC1B)
+///   Timer2: D11 (OC2A), D3 (OC2B)
+pub fn pwm_write(pin: u8, duty: u8) -> Result<(), Error> {
+    // Validate the pin before changing it, a pin without PWM
+    // shouldn't be left as an output
+    port_pin(pin)?;
+    if !PWM_PINS.contains(&pin) {
+        return Err(Error::new(ErrorKind::NotPwmPin));
+    }
+    pin_mode(pin, PinMode::Output)?;
+
+    match duty {
+        0 => return digital_write(pin, false),
+        u8::MAX => return digital_write(pin, true),
+        _ => {}
+    }
+
+    match pin {
+        6 | 5 => {
+            // Fast PWM, mode 3, WGM01 and WGM00
+            update_reg(TCCR0A, 0x03, true);
+            // Prescaler 64, CS01 and CS00
+            write_reg(TCCR0B, 0x03);
+            if pin == 6 {
+                // Non-inverting on OC0A, COM0A1
+                update_reg(TCCR0A, 1 << 7, true);
+                write_reg(OCR0A, duty);
+            } else {
+                // Non-inverting on OC0B, COM0B1
+                update_reg(TCCR0A, 1 << 5, true);
+                write_reg(OCR0B, duty);
+            }
+        }
+        9 | 10 => {
+            // 8-bit fast PWM, mode 5, WGM10 in TCCR1A and WGM12 in TCCR1B
+            update_reg(TCCR1A, 0x01, true);
+            // Prescaler 64, CS11 and CS10
+            write_reg(TCCR1B, (1 << 3) | 0x03);
+            // The high byte has to be written first, it goes into the
+            // temporary register shared by all 16-bit registers.
+            if pin == 9 {
+                update_reg(TCCR1A, 1 << 7, true);
+                write_reg(OCR1AH, 0);
+                write_reg(OCR1AL, duty);
+            } else {
+                update_reg(TCCR1A, 1 << 5, true);
+                write_reg(OCR1BH, 0);
+                write_reg(OCR1BL, duty);
+            }
+        }
+        11 | 3 => {
+            // Fast PWM, mode 3, WGM21 and WGM20
+            update_reg(TCCR2A, 0x03, true);
+            // Prescaler 64 on Timer2 is CS22
+            write_reg(TCCR2B, 0x04);
+            if pin == 11 {
+                update_reg(TCCR2A, 1 << 7, true);
+                write_reg(OCR2A, duty);
+            } else {
+                update_reg(TCCR2A, 1 << 5, true);
+                write_reg(OCR2B, duty);
+            }
+        }
+        _ => return Err(Error::new(ErrorKind::NotPwmPin)),
+    }
+
+    Ok(())
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+///
+/// These check the pin state through the PINx registers, which
+/// simavr updates from the PORTx registers for output pins.
+pub mod tests {
+    use crate::{
+        hardware::{
+            analog_read, digital_read, digital_write, pin_mode, port_pin, pwm_write, read_reg,
+            ErrorKind, PinMode, DDR_OFFSET, OCR0A, PINB, PIND, TCCR1A,
+        },
+        testing::{run_suite, TestCase, TestReporter},
+        tests::write_test_result,
+    };
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("port_pin_works", test_hardware_port_pin_works),
+        TestCase::new("usart_pins_rejected", test_hardware_usart_pins_rejected),
+        TestCase::new("invalid_pin_rejected", test_hardware_invalid_pin_rejected),
+        TestCase::new(
+            "digital_write_high_works",
+            test_hardware_digital_write_high_works,
+        ),
+        TestCase::new(
+            "digital_write_low_works",
+            test_hardware_digital_write_low_works,
+        ),
+        TestCase::new("analog_read_works", test_hardware_analog_read_works),
+        TestCase::new(
+            "analog_read_digital_pin_fails",
+            test_hardware_analog_read_digital_pin_fails,
+        ),
+        TestCase::new("pwm_write_works", test_hardware_pwm_write_works),
+        TestCase::new(
+            "pwm_write_non_pwm_pin_fails",
+            test_hardware_pwm_write_non_pwm_pin_fails,
+        ),
+        TestCase::new(
+            "digital_write_after_pwm_works",
+            test_hardware_digital_write_after_pwm_works,
+        ),
+        TestCase::new(
+            "pin_mode_turns_off_pwm",
+            test_hardware_pin_mode_turns_off_pwm,
+        ),
+        TestCase::new(
+            "pwm_write_zero_and_full_works",
+            test_hardware_pwm_write_zero_and_full_works,
+        ),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "hardware", TEST_CASES);
+    }
+
+    /// Test that pins are mapped to the right port and bit
+    pub fn test_hardware_port_pin_works(reporter: &mut dyn TestReporter) {
+        let p = port_pin(2).unwrap();
+        write_test_result(reporter, p.port == PIND && p.bit == 2, "D2 should be PD2");
+
+        let p = port_pin(13).unwrap();
+        write_test_result(reporter, p.port == PINB && p.bit == 5, "D13 should be PB5");
+    }
+
+    /// Test that the USART0 pins can't be used
+    pub fn test_hardware_usart_pins_rejected(reporter: &mut dyn TestReporter) {
+        for pin in [0, 1] {
+            let res = pin_mode(pin, PinMode::Output);
+            match res {
+                Err(e) => {
+                    write_test_result(
+                        reporter,
+                        e.kind == ErrorKind::PinReserved,
+                        "USART0 pins should be reserved",
+                    );
+                }
+                Ok(_) => {
+                    write_test_result(reporter, false, "USART0 pins should be reserved");
+                }
+            }
+        }
+    }
+
+    /// Test that pins that don't exist are rejected
+    pub fn test_hardware_invalid_pin_rejected(reporter: &mut dyn TestReporter) {
+        let res = digital_read(20);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::InvalidPin,
+                    "pin 20 should be invalid",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "pin 20 should be invalid");
+            }
+        }
+    }
+
+    /// Test that an output pin set high reads back high
+    pub fn test_hardware_digital_write_high_works(reporter: &mut dyn TestReporter) {
+        let res = pin_mode(13, PinMode::Output);
+        write_test_result(reporter, res.is_ok(), "should set D13 to output");
+
+        let res = digital_write(13, true);
+        write_test_result(reporter, res.is_ok(), "should set D13 high");
+
+        let res = digital_read(13);
+        write_test_result(reporter, res == Ok(true), "D13 should read high");
+    }
+
+    /// Test that an output pin set low reads back low
+    pub fn test_hardware_digital_write_low_works(reporter: &mut dyn TestReporter) {
+        let _res = pin_mode(4, PinMode::Output);
+
+        let _res = digital_write(4, true);
+        let res = digital_write(4, false);
+        write_test_result(reporter, res.is_ok(), "should set D4 low");
+
+        let res = digital_read(4);
+        write_test_result(reporter, res == Ok(false), "D4 should read low");
+    }
+
+    /// Test that reading an analog channel returns a 10-bit value
+    pub fn test_hardware_analog_read_works(reporter: &mut dyn TestReporter) {
+        let res = analog_read(0);
+        match res {
+            Ok(v) => {
+                write_test_result(reporter, v <= 0x3FF, "ADC result should be 10 bits");
+            }
+            Err(_e) => {
+                write_test_result(reporter, false, "should read A0");
+            }
+        }
+
+        let res = analog_read(19);
+        write_test_result(reporter, res.is_ok(), "should read A5 by pin number");
+    }
+
+    /// Test that analog reads on digital pins fail
+    pub fn test_hardware_analog_read_digital_pin_fails(reporter: &mut dyn TestReporter) {
+        let res = analog_read(8);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::NotAnalogPin,
+                    "D8 should not be an analog pin",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "D8 should not be an analog pin");
+            }
+        }
+    }
+
+    /// Test that setting a PWM duty cycle works
+    pub fn test_hardware_pwm_write_works(reporter: &mut dyn TestReporter) {
+        let res = pwm_write(6, 0x80);
+        write_test_result(reporter, res.is_ok(), "should set PWM on D6");
+
+        let ocr = unsafe { core::ptr::read_volatile(OCR0A as *const u8) };
+        write_test_result(reporter, ocr == 0x80, "OCR0A should hold the duty cycle");
+    }
+
+    /// Test that PWM on a pin without an output compare unit fails
+    pub fn test_hardware_pwm_write_non_pwm_pin_fails(reporter: &mut dyn TestReporter) {
+        let _ = pin_mode(7, PinMode::Input);
+        let res = pwm_write(7, 0x80);
+        let ddr = unsafe { core::ptr::read_volatile((PIND + DDR_OFFSET) as *const u8) };
+        write_test_result(
+            reporter,
+            ddr & (1 << 7) == 0,
+            "D7 should still be an input after a failed pwm_write",
+        );
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::NotPwmPin,
+                    "D7 should not support PWM",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "D7 should not support PWM");
+            }
+        }
+    }
+
+    /// Test that digital_write takes a pin back from the timer
+    pub fn test_hardware_digital_write_after_pwm_works(reporter: &mut dyn TestReporter) {
+        let _ = pwm_write(9, 0x80);
+        write_test_result(
+            reporter,
+            read_reg(TCCR1A) & (1 << 7) != 0,
+            "pwm_write should connect OC1A to D9",
+        );
+
+        let res = digital_write(9, true);
+        write_test_result(reporter, res.is_ok(), "should set D9 high");
+        write_test_result(
+            reporter,
+            read_reg(TCCR1A) & (1 << 7) == 0,
+            "digital_write should disconnect OC1A from D9",
+        );
+        write_test_result(reporter, digital_read(9) == Ok(true), "D9 should read high");
+
+        let _ = digital_write(9, false);
+    }
+
+    /// Test that pin_mode takes a pin back from the timer
+    pub fn test_hardware_pin_mode_turns_off_pwm(reporter: &mut dyn TestReporter) {
+        let _ = pwm_write(10, 0x80);
+
+        let res = pin_mode(10, PinMode::Input);
+        write_test_result(reporter, res.is_ok(), "should set D10 to input");
+        write_test_result(
+            reporter,
+            read_reg(TCCR1A) & (1 << 5) == 0,
+            "pin_mode should disconnect OC1B from D10",
+        );
+    }
+
+    /// Test that duty cycles 0 and 255 drive the pin low and high
+    /// without the timer
+    pub fn test_hardware_pwm_write_zero_and_full_works(reporter: &mut dyn TestReporter) {
+        let res = pwm_write(9, 0);
+        write_test_result(reporter, res.is_ok(), "should set PWM 0 on D9");
+        write_test_result(
+            reporter,
+            read_reg(TCCR1A) & (1 << 7) == 0,
+            "PWM 0 shouldn't connect OC1A to D9",
+        );
+        write_test_result(
+            reporter,
+            digital_read(9) == Ok(false),
+            "D9 should read low with PWM 0",
+        );
+
+        let res = pwm_write(9, 255);
+        write_test_result(reporter, res.is_ok(), "should set PWM 255 on D9");
+        write_test_result(
+            reporter,
+            read_reg(TCCR1A) & (1 << 7) == 0,
+            "PWM 255 shouldn't connect OC1A to D9",
+        );
+        write_test_result(
+            reporter,
+            digital_read(9) == Ok(true),
+            "D9 should read high with PWM 255",
+        );
+
+        let _ = digital_write(9, false);
+    }
+}
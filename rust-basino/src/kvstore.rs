@@ -0,0 +1,779 @@
+//! Wear-leveled key/value store in EEPROM
+//!
+//! Calibration constants and other small values change more often
+//! than programs do.  Rewriting the same EEPROM cells for every
+//! update would wear them out, so the store is a log.  Updates are
+//! appended and the last record for a key wins.
+//!
+//! The storage is split into two pages.  One page is active, records
+//! are appended to it until it's full.  Then the latest record for
+//! each key is copied to the other page, and that page becomes the
+//! active one.  Every cell is written about once per pass through a
+//! page, so the writes are spread over the whole storage.
+//!
+//! Page layout:
+//!
+//!   offset  size  field
+//!   0       1     sequence number
+//!   1       1     complement of the sequence number
+//!   2       ...   records
+//!
+//! Record layout:
+//!
+//!   offset  size  field
+//!   0       1     key, 0xFF marks the end of the log
+//!   1       1     value length
+//!   2       n     value
+//!   2 + n   1     CRC-8 of the key, length and value
+//!
+//! Updates are atomic, a power loss in the middle of a write leaves
+//! the old value in place:
+//!
+//! * The key byte of a record is written last.  Until it is written,
+//!   the record looks like the end of the log.  The CRC catches a
+//!   key byte that was only partly written.
+//! * A page only becomes active when the complement of its sequence
+//!   number is written, after all the records have been copied.  If
+//!   both pages are valid after a power loss, the one with the newer
+//!   sequence number is used.
+#![warn(missing_docs)]
+
+use crate::eeprom::{self, Storage};
+
+use core::fmt::{Debug, Display, Formatter};
+use ufmt::{uDebug, uWrite};
+
+/// The kinds of errors that can occur working with the key/value store
+#[derive(Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The key isn't in the store
+    KeyNotFound,
+    /// The key is reserved, 0xFF marks the end of the log
+    InvalidKey,
+    /// The value is longer than MAX_VALUE_LEN
+    ValueTooLarge,
+    /// The buffer passed in is too small for the value
+    BufferTooSmall,
+    /// There isn't room for the record even after compacting
+    StoreFull,
+    /// The storage is too small to hold two pages
+    StorageTooSmall,
+    /// The underlying storage returned an error
+    StorageError,
+    /// An unknown error type
+    Unknown,
+}
+
+impl uDebug for ErrorKind {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        match self {
+            ErrorKind::KeyNotFound => f.write_str("The key was not found"),
+            ErrorKind::InvalidKey => f.write_str("The key is reserved"),
+            ErrorKind::ValueTooLarge => f.write_str("The value is too large"),
+            ErrorKind::BufferTooSmall => f.write_str("The buffer is too small"),
+            ErrorKind::StoreFull => f.write_str("The store is full"),
+            ErrorKind::StorageTooSmall => f.write_str("The storage is too small"),
+            ErrorKind::StorageError => f.write_str("A storage error occurred"),
+            ErrorKind::Unknown => f.write_str("An unknown error occurred"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ErrorKind::KeyNotFound => write!(f, "The key was not found"),
+            ErrorKind::InvalidKey => write!(f, "The key is reserved"),
+            ErrorKind::ValueTooLarge => write!(f, "The value is too large"),
+            ErrorKind::BufferTooSmall => write!(f, "The buffer is too small"),
+            ErrorKind::StoreFull => write!(f, "The store is full"),
+            ErrorKind::StorageTooSmall => write!(f, "The storage is too small"),
+            ErrorKind::StorageError => write!(f, "A storage error occurred"),
+            ErrorKind::Unknown => write!(f, "An unknown error occurred"),
+        }
+    }
+}
+
+/// An error that can occur when working with the key/value store
+#[derive(PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl From<eeprom::Error> for Error {
+    fn from(_e: eeprom::Error) -> Error {
+        Error::new(ErrorKind::StorageError)
+    }
+}
+
+/// The start of the key/value partition in the ATmega328P EEPROM
+pub const KV_PARTITION_START: u16 = eeprom::PROGRAM_PARTITION_START + eeprom::PROGRAM_PARTITION_LEN;
+
+/// The length of the key/value partition in the ATmega328P EEPROM
+pub const KV_PARTITION_LEN: u16 = 256;
+
+/// The longest value that can be stored
+pub const MAX_VALUE_LEN: usize = 8;
+
+/// The key that marks the end of the log, the erased EEPROM value
+pub const END_KEY: u8 = 0xFF;
+
+/// The size of a page header
+const PAGE_HEADER_SIZE: u16 = 2;
+
+/// The size of a record without its value
+const RECORD_OVERHEAD: u16 = 3;
+
+/// Update a CRC-8 with one byte
+///
+/// Polynomial 0x07, initial value 0x00.
+fn crc8_update(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+
+    for _ in 0..8 {
+        if crc & 0x80 != 0 {
+            crc = (crc << 1) ^ 0x07;
+        } else {
+            crc <<= 1;
+        }
+    }
+
+    crc
+}
+
+/// A key/value store in a storage backend
+pub struct KvStore<'a, S: Storage> {
+    /// The storage the store lives in
+    storage: &'a mut S,
+    /// The size of each of the two pages
+    page_size: u16,
+    /// The index of the active page, zero or one
+    active: u16,
+    /// The sequence number of the active page
+    sequence: u8,
+    /// The offset of the end of the log in the active page
+    end: u16,
+}
+
+impl<'a, S: Storage> KvStore<'a, S> {
+    /// Open the store in storage
+    ///
+    /// If neither page has a valid header, the store is formatted.
+    pub fn new(storage: &'a mut S) -> Result<Self, Error> {
+        let page_size = storage.capacity() / 2;
+
+        if page_size < PAGE_HEADER_SIZE + RECORD_OVERHEAD + MAX_VALUE_LEN as u16 {
+            return Err(Error::new(ErrorKind::StorageTooSmall));
+        }
+
+        let mut store = KvStore {
+            storage,
+            page_size,
+            active: 0,
+            sequence: 0,
+            end: PAGE_HEADER_SIZE,
+        };
+
+        let page0 = store.read_page_sequence(0)?;
+        let page1 = store.read_page_sequence(1)?;
+
+        match (page0, page1) {
+            (Some(s0), Some(s1)) => {
+                // Both pages are valid if the power was lost after a
+                // compaction, before the old page was cleared.
+                if (s1.wrapping_sub(s0) as i8) > 0 {
+                    store.active = 1;
+                    store.sequence = s1;
+                } else {
+                    store.active = 0;
+                    store.sequence = s0;
+                }
+            }
+            (Some(s0), None) => {
+                store.active = 0;
+                store.sequence = s0;
+            }
+            (None, Some(s1)) => {
+                store.active = 1;
+                store.sequence = s1;
+            }
+            (None, None) => {
+                store.format()?;
+            }
+        }
+
+        store.end = store.find_end()?;
+
+        Ok(store)
+    }
+
+    /// Erase the store
+    pub fn format(&mut self) -> Result<(), Error> {
+        self.erase_page(1)?;
+        self.erase_page(0)?;
+        self.write_page_header(0, 0)?;
+
+        self.active = 0;
+        self.sequence = 0;
+        self.end = PAGE_HEADER_SIZE;
+
+        Ok(())
+    }
+
+    /// Get the value for a key
+    ///
+    /// The value is copied into buffer, the length of the value is
+    /// returned.
+    pub fn get(&mut self, key: u8, buffer: &mut [u8]) -> Result<usize, Error> {
+        let pos = self.find_latest(key)?;
+        let base = self.page_base(self.active);
+        let len = self.storage.read_byte(base + pos + 1)? as usize;
+
+        if len > buffer.len() {
+            return Err(Error::new(ErrorKind::BufferTooSmall));
+        }
+
+        for (i, b) in buffer.iter_mut().enumerate().take(len) {
+            *b = self.storage.read_byte(base + pos + 2 + i as u16)?;
+        }
+
+        Ok(len)
+    }
+
+    /// Set the value for a key
+    ///
+    /// If the active page is full, it's compacted first.  Setting a
+    /// key to the value it already has doesn't write anything.
+    pub fn put(&mut self, key: u8, value: &[u8]) -> Result<(), Error> {
+        if key == END_KEY {
+            return Err(Error::new(ErrorKind::InvalidKey));
+        }
+
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::new(ErrorKind::ValueTooLarge));
+        }
+
+        let mut current: [u8; MAX_VALUE_LEN] = [0; MAX_VALUE_LEN];
+        if let Ok(len) = self.get(key, &mut current) {
+            if current[..len] == *value {
+                return Ok(());
+            }
+        }
+
+        let needed = RECORD_OVERHEAD + value.len() as u16;
+
+        if self.end + needed > self.page_size {
+            self.compact()?;
+
+            if self.end + needed > self.page_size {
+                return Err(Error::new(ErrorKind::StoreFull));
+            }
+        }
+
+        let base = self.page_base(self.active);
+        let pos = self.end;
+
+        // Make sure the record reads as the end of the log until it's
+        // complete
+        self.storage.update_byte(base + pos, END_KEY)?;
+
+        let mut crc = crc8_update(0, key);
+        crc = crc8_update(crc, value.len() as u8);
+        self.storage
+            .update_byte(base + pos + 1, value.len() as u8)?;
+
+        for (i, b) in value.iter().enumerate() {
+            self.storage.update_byte(base + pos + 2 + i as u16, *b)?;
+            crc = crc8_update(crc, *b);
+        }
+
+        self.storage
+            .update_byte(base + pos + 2 + value.len() as u16, crc)?;
+
+        // Commit the record
+        self.storage.update_byte(base + pos, key)?;
+
+        self.end += needed;
+
+        Ok(())
+    }
+
+    /// Get the number of free bytes in the active page
+    pub fn free(&self) -> u16 {
+        self.page_size - self.end
+    }
+
+    /// Get the index of the active page, zero or one
+    pub fn active_page(&self) -> u16 {
+        self.active
+    }
+
+    /// Copy the latest record for each key to the other page and make
+    /// it the active page
+    pub fn compact(&mut self) -> Result<(), Error> {
+        let other = 1 - self.active;
+        let from = self.page_base(self.active);
+        let to = self.page_base(other);
+
+        self.erase_page(other)?;
+
+        let mut dest = PAGE_HEADER_SIZE;
+        let mut pos = PAGE_HEADER_SIZE;
+
+        while pos < self.end {
+            let key = self.storage.read_byte(from + pos)?;
+            let len = self.record_len(from + pos)?;
+
+            if self.is_latest(pos, key)? {
+                // Key last, the same as a normal put
+                for i in 1..len {
+                    let b = self.storage.read_byte(from + pos + i)?;
+                    self.storage.update_byte(to + dest + i, b)?;
+                }
+                self.storage.update_byte(to + dest, key)?;
+                dest += len;
+            }
+
+            pos += len;
+        }
+
+        // The new page is only valid once the header is complete
+        let sequence = self.sequence.wrapping_add(1);
+        self.write_page_header(other, sequence)?;
+
+        // Invalidate the old page.  If this doesn't happen the newer
+        // sequence number still wins.
+        self.storage.update_byte(from, 0xFF)?;
+        self.storage.update_byte(from + 1, 0xFF)?;
+
+        self.active = other;
+        self.sequence = sequence;
+        self.end = dest;
+
+        Ok(())
+    }
+
+    /// Get the address of a page in the storage
+    fn page_base(&self, page: u16) -> u16 {
+        page * self.page_size
+    }
+
+    /// Read the sequence number of a page
+    ///
+    /// Returns None if the page header isn't valid.
+    fn read_page_sequence(&mut self, page: u16) -> Result<Option<u8>, Error> {
+        let base = self.page_base(page);
+        let sequence = self.storage.read_byte(base)?;
+        let check = self.storage.read_byte(base + 1)?;
+
+        if check == !sequence {
+            Ok(Some(sequence))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Write a page header, the complement is written last
+    fn write_page_header(&mut self, page: u16, sequence: u8) -> Result<(), Error> {
+        let base = self.page_base(page);
+
+        self.storage.update_byte(base, sequence)?;
+        self.storage.update_byte(base + 1, !sequence)?;
+
+        Ok(())
+    }
+
+    /// Erase a page to 0xFF
+    fn erase_page(&mut self, page: u16) -> Result<(), Error> {
+        let base = self.page_base(page);
+
+        for i in 0..self.page_size {
+            self.storage.update_byte(base + i, 0xFF)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the total length of a record at an address
+    fn record_len(&mut self, address: u16) -> Result<u16, Error> {
+        Ok(RECORD_OVERHEAD + self.storage.read_byte(address + 1)? as u16)
+    }
+
+    /// Test if the record at pos in the active page is complete and
+    /// passes the CRC check
+    fn record_valid(&mut self, pos: u16) -> Result<bool, Error> {
+        let base = self.page_base(self.active);
+
+        if pos + RECORD_OVERHEAD > self.page_size {
+            return Ok(false);
+        }
+
+        let key = self.storage.read_byte(base + pos)?;
+        if key == END_KEY {
+            return Ok(false);
+        }
+
+        let len = self.storage.read_byte(base + pos + 1)?;
+        if len as usize > MAX_VALUE_LEN || pos + RECORD_OVERHEAD + len as u16 > self.page_size {
+            return Ok(false);
+        }
+
+        let mut crc = crc8_update(0, key);
+        crc = crc8_update(crc, len);
+        for i in 0..len as u16 {
+            crc = crc8_update(crc, self.storage.read_byte(base + pos + 2 + i)?);
+        }
+
+        Ok(crc == self.storage.read_byte(base + pos + 2 + len as u16)?)
+    }
+
+    /// Find the end of the log in the active page
+    ///
+    /// The log ends at the first record that is erased, incomplete or
+    /// fails the CRC check.
+    fn find_end(&mut self) -> Result<u16, Error> {
+        let base = self.page_base(self.active);
+        let mut pos = PAGE_HEADER_SIZE;
+
+        while self.record_valid(pos)? {
+            pos += self.record_len(base + pos)?;
+        }
+
+        Ok(pos)
+    }
+
+    /// Test if the record at pos is the last one for key
+    fn is_latest(&mut self, pos: u16, key: u8) -> Result<bool, Error> {
+        let base = self.page_base(self.active);
+        let mut next = pos + self.record_len(base + pos)?;
+
+        while next < self.end {
+            if self.storage.read_byte(base + next)? == key {
+                return Ok(false);
+            }
+            next += self.record_len(base + next)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Find the position of the last record for key in the active
+    /// page
+    fn find_latest(&mut self, key: u8) -> Result<u16, Error> {
+        let base = self.page_base(self.active);
+        let mut pos = PAGE_HEADER_SIZE;
+        let mut found = None;
+
+        while pos < self.end {
+            if self.storage.read_byte(base + pos)? == key {
+                found = Some(pos);
+            }
+            pos += self.record_len(base + pos)?;
+        }
+
+        found.ok_or(Error::new(ErrorKind::KeyNotFound))
+    }
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+///
+/// Power loss is simulated with InterruptedStorage, which drops every
+/// write after a set number of writes.  The store is then reopened on
+/// the same bytes, the same as a reset.
+pub mod tests {
+    use crate::{
+        eeprom::{self, RamStorage, Storage},
+        kvstore::{ErrorKind, KvStore},
+        testing::{run_suite, TestCase, TestReporter},
+        tests::write_test_result,
+    };
+
+    /// A storage that loses power after a number of writes
+    struct InterruptedStorage<'a, S: Storage> {
+        storage: &'a mut S,
+        writes_left: u16,
+    }
+
+    impl<'a, S: Storage> Storage for InterruptedStorage<'a, S> {
+        fn capacity(&self) -> u16 {
+            self.storage.capacity()
+        }
+
+        fn read_byte(&mut self, address: u16) -> Result<u8, eeprom::Error> {
+            self.storage.read_byte(address)
+        }
+
+        fn write_byte(&mut self, address: u16, value: u8) -> Result<(), eeprom::Error> {
+            if self.writes_left == 0 {
+                return Err(eeprom::Error::new(eeprom::ErrorKind::Unknown));
+            }
+            self.writes_left -= 1;
+
+            self.storage.write_byte(address, value)
+        }
+    }
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("put_get_works", test_kvstore_put_get_works),
+        TestCase::new("get_missing_key_fails", test_kvstore_get_missing_key_fails),
+        TestCase::new("update_works", test_kvstore_update_works),
+        TestCase::new("reopen_works", test_kvstore_reopen_works),
+        TestCase::new("compact_works", test_kvstore_compact_works),
+        TestCase::new("wear_leveling_works", test_kvstore_wear_leveling_works),
+        TestCase::new("invalid_put_fails", test_kvstore_invalid_put_fails),
+        TestCase::new(
+            "interrupted_put_is_atomic",
+            test_kvstore_interrupted_put_is_atomic,
+        ),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "kvstore", TEST_CASES);
+    }
+
+    /// Test that a value that was put can be read back
+    pub fn test_kvstore_put_get_works(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 64] = [0; 64];
+        let mut storage = RamStorage::new(&mut data);
+        let mut store = KvStore::new(&mut storage).unwrap();
+
+        let res = store.put(1, &[0x12, 0x34]);
+        write_test_result(reporter, res.is_ok(), "should put value");
+
+        let mut buffer: [u8; 8] = [0; 8];
+        let res = store.get(1, &mut buffer);
+        write_test_result(reporter, res == Ok(2), "should get value of length 2");
+        write_test_result(
+            reporter,
+            buffer[..2] == [0x12, 0x34],
+            "value should equal the value put",
+        );
+    }
+
+    /// Test that getting a key that was never put fails
+    pub fn test_kvstore_get_missing_key_fails(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 64] = [0; 64];
+        let mut storage = RamStorage::new(&mut data);
+        let mut store = KvStore::new(&mut storage).unwrap();
+
+        let mut buffer: [u8; 8] = [0; 8];
+        let res = store.get(7, &mut buffer);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::KeyNotFound,
+                    "get of missing key should fail with KeyNotFound",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "get of missing key should fail");
+            }
+        }
+    }
+
+    /// Test that the latest value for a key wins
+    pub fn test_kvstore_update_works(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 64] = [0; 64];
+        let mut storage = RamStorage::new(&mut data);
+        let mut store = KvStore::new(&mut storage).unwrap();
+
+        let _res = store.put(1, &[1]);
+        let _res = store.put(2, &[2]);
+        let _res = store.put(1, &[3, 4, 5]);
+
+        let mut buffer: [u8; 8] = [0; 8];
+        let res = store.get(1, &mut buffer);
+        write_test_result(
+            reporter,
+            res == Ok(3) && buffer[..3] == [3, 4, 5],
+            "get should return the latest value",
+        );
+
+        let res = store.get(2, &mut buffer);
+        write_test_result(
+            reporter,
+            res == Ok(1) && buffer[0] == 2,
+            "other keys should be unchanged",
+        );
+    }
+
+    /// Test that values survive reopening the store
+    pub fn test_kvstore_reopen_works(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 64] = [0; 64];
+        let mut storage = RamStorage::new(&mut data);
+
+        {
+            let mut store = KvStore::new(&mut storage).unwrap();
+            let _res = store.put(5, &[0xAA]);
+            let _res = store.put(5, &[0xBB]);
+        }
+
+        let mut store = KvStore::new(&mut storage).unwrap();
+        let mut buffer: [u8; 8] = [0; 8];
+        let res = store.get(5, &mut buffer);
+        write_test_result(
+            reporter,
+            res == Ok(1) && buffer[0] == 0xBB,
+            "reopened store should have the latest value",
+        );
+    }
+
+    /// Test that filling a page compacts it and keeps every key
+    pub fn test_kvstore_compact_works(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 64] = [0; 64];
+        let mut storage = RamStorage::new(&mut data);
+        let mut store = KvStore::new(&mut storage).unwrap();
+
+        // Each record is four bytes, a page holds seven of them, so
+        // this compacts several times
+        for i in 0..40_u8 {
+            let res = store.put(i % 3, &[i]);
+            write_test_result(
+                reporter,
+                res.is_ok(),
+                "put should compact when page is full",
+            );
+        }
+
+        let mut buffer: [u8; 8] = [0; 8];
+        for (key, expected) in [(0, 39), (1, 37), (2, 38)] {
+            let res = store.get(key, &mut buffer);
+            write_test_result(
+                reporter,
+                res == Ok(1) && buffer[0] == expected,
+                "compacted store should have the latest values",
+            );
+        }
+    }
+
+    /// Test that the store moves between both pages as it's updated
+    pub fn test_kvstore_wear_leveling_works(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 64] = [0; 64];
+        let mut storage = RamStorage::new(&mut data);
+        let mut store = KvStore::new(&mut storage).unwrap();
+
+        let mut page = store.active_page();
+        let mut switches = 0;
+
+        for i in 0..20_u8 {
+            let _res = store.put(1, &[i]);
+            if store.active_page() != page {
+                page = store.active_page();
+                switches += 1;
+            }
+        }
+
+        // Seven records fit in a page, so 20 updates should switch
+        // pages at least twice
+        write_test_result(
+            reporter,
+            switches >= 2,
+            "updates should be spread over both pages",
+        );
+    }
+
+    /// Test that invalid keys and large values are refused
+    pub fn test_kvstore_invalid_put_fails(reporter: &mut dyn TestReporter) {
+        let mut data: [u8; 64] = [0; 64];
+        let mut storage = RamStorage::new(&mut data);
+        let mut store = KvStore::new(&mut storage).unwrap();
+
+        let res = store.put(0xFF, &[1]);
+        write_test_result(reporter, res.is_err(), "put with key 0xFF should fail");
+
+        let res = store.put(1, &[0; 9]);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::ValueTooLarge,
+                    "put of large value should fail with ValueTooLarge",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "put of large value should fail");
+            }
+        }
+    }
+
+    /// Test that a put interrupted at any write leaves either the old
+    /// or the new value, and never loses other keys
+    ///
+    /// The store is nearly full before the put, so the interrupted
+    /// writes include a compaction.
+    pub fn test_kvstore_interrupted_put_is_atomic(reporter: &mut dyn TestReporter) {
+        let mut all_passed = true;
+
+        for writes in 0..80 {
+            let mut data: [u8; 64] = [0; 64];
+            let mut storage = RamStorage::new(&mut data);
+
+            {
+                let mut store = KvStore::new(&mut storage).unwrap();
+                let _res = store.put(9, &[0x99]);
+                for i in 0..6_u8 {
+                    let _res = store.put(1, &[i]);
+                }
+            }
+
+            let completed = {
+                let mut interrupted = InterruptedStorage {
+                    storage: &mut storage,
+                    writes_left: writes,
+                };
+                match KvStore::new(&mut interrupted) {
+                    Ok(mut store) => store.put(1, &[0x42, 0x43]).is_ok(),
+                    Err(_e) => false,
+                }
+            };
+
+            let mut store = KvStore::new(&mut storage).unwrap();
+            let mut buffer: [u8; 8] = [0; 8];
+
+            let res = store.get(1, &mut buffer);
+            let new_value = res == Ok(2) && buffer[..2] == [0x42, 0x43];
+            let old_value = res == Ok(1) && buffer[0] == 5;
+            if !(new_value || (old_value && !completed)) {
+                all_passed = false;
+            }
+
+            let res = store.get(9, &mut buffer);
+            if !(res == Ok(1) && buffer[0] == 0x99) {
+                all_passed = false;
+            }
+        }
+
+        write_test_result(
+            reporter,
+            all_passed,
+            "interrupted put should leave the old or new value",
+        );
+    }
+}
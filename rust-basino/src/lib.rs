@@ -1,22 +1,70 @@
 //! A crate to work with custom user stacks on AVR
+//!
+//! On AVR the stack and queue operations call the assembly routines in
+//! libbasino.  On other targets they use the Rust versions in
+//! portable, and the modules that need the hardware aren't built.
 #![warn(missing_docs)]
 #![no_std]
-#![no_main]
-#![feature(abi_avr_interrupt)]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(target_arch = "avr", feature(abi_avr_interrupt))]
 
+#[cfg(target_arch = "avr")]
 use avr_device::interrupt::Mutex;
-use core::{cell::RefCell, marker::PhantomData};
+#[cfg(target_arch = "avr")]
+use core::cell::RefCell;
+use core::marker::PhantomData;
 use ufmt::{uDebug, uWrite};
 
+/// Cycle counts for the libbasino routines
+#[cfg(target_arch = "avr")]
+pub mod bench;
+
+/// EEPROM storage and program persistence
+pub mod eeprom;
+
 /// Error data types
 pub mod error;
 
+/// Interrupt driven events
+#[cfg(target_arch = "avr")]
+pub mod events;
+
+/// Hardware access functions
+#[cfg(target_arch = "avr")]
+pub mod hardware;
+
+/// Wear-leveled key/value store in EEPROM
+pub mod kvstore;
+
+/// Direct memory access functions
+#[cfg(target_arch = "avr")]
+pub mod memory;
+
+/// A serial memory monitor
+#[cfg(target_arch = "avr")]
+pub mod monitor;
+
+/// Pure-Rust versions of the assembly routines for other targets
+#[cfg(not(target_arch = "avr"))]
+pub mod portable;
+
 /// Queue functions and data structures
 pub mod queue;
 
+/// Interrupt driven serial input and output
+#[cfg(target_arch = "avr")]
+pub mod serial;
+
 /// Stack functions and data structures
 pub mod stack;
 
+/// An on-device test framework
+pub mod testing;
+
+/// The system tick
+#[cfg(target_arch = "avr")]
+pub mod tick;
+
 /// A handle to an array to manage lifetimes and concurrency
 pub struct ArrayHandle<'a, T> {
     /// Pointer to the array
@@ -55,7 +103,7 @@ impl<'a, T> ArrayHandle<'a, T> {
     /// # Examples
     ///
     /// ```
-    /// use crate::ArrayHandle;
+    /// use rust_basino::ArrayHandle;
     ///
     /// let mut arr: [u8; 4] = [0; 4];
     /// let _handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
@@ -204,17 +252,28 @@ pub static mut DEVICE_PERIPHERALS_SPACE: u8 = 0;
 // it has a link_section attribute.
 // Is every component including the Mutex and structures under it put
 // in sequential memory?
+#[cfg(target_arch = "avr")]
 static BASINO_STACK_BUFFER: Mutex<RefCell<[u8; 33]>> = Mutex::new(RefCell::new([0; 33]));
 
 /// The queue object we pass into the C / assembly code to store data
 #[link_section = ".ram2bss"]
+#[cfg(target_arch = "avr")]
 static BASINO_QUEUE_DATA: Mutex<RefCell<[u8; 4]>> = Mutex::new(RefCell::new([0; 4]));
 
+#[cfg(target_arch = "avr")]
 #[link(name = "basino")]
 extern "C" {
     /// Add two 8-bit unsigned integers together
     pub fn basino_add(a: u8, b: u8) -> u16;
 
+    /// Add two 16-bit addresses together
+    /// Returns the sum and writes the carry, zero or one, to carry
+    ///
+    /// # Safety
+    ///
+    /// carry must point to valid memory.
+    pub fn basino_address_add(a: u16, b: u16, carry: *mut u8) -> u16;
+
     /// Test whether a is greater than b
     /// Returns one if a is greater than b
     /// Return zero if it isn't
@@ -225,6 +284,17 @@ extern "C" {
     /// Return zero if it isn't
     pub fn basino_gt_eq(a: u16, b: u16) -> u8;
 
+    /// Read a byte from program memory with lpm
+    /// address is a byte address in flash, not a word address
+    pub fn basino_progmem_read(address: u16) -> u8;
+
+    /// Sleep in Idle mode until the next interrupt
+    ///
+    /// # Safety
+    ///
+    /// Interrupts must be enabled or this never returns.
+    pub fn basino_sleep_idle();
+
     // Stack functions
 
     /// Initialize the stack.
@@ -322,90 +392,116 @@ extern "C" {
     pub fn basino_queue_get_tail(queue: *mut QueueObj, result: *mut u8) -> *const u8;
 }
 
+#[cfg(not(target_arch = "avr"))]
+pub use portable::{
+    basino_add, basino_address_add, basino_get_basino_stack_bottom, basino_get_basino_stack_top,
+    basino_get_basino_stack_top_sentinel, basino_gt, basino_gt_eq, basino_queue_get,
+    basino_queue_get_head, basino_queue_get_last_head, basino_queue_get_queue_end,
+    basino_queue_get_queue_start, basino_queue_get_tail, basino_queue_init, basino_queue_put,
+    basino_stack_init, basino_stack_pop, basino_stack_push,
+};
+
 /// Test module for the top-level Tiny BASIC system
 #[allow(unused_imports)]
 pub mod tests {
+    use crate::testing::{run_suite, TestCase, TestReporter};
     use crate::{basino_gt, basino_gt_eq};
-    use arduino_hal::{
-        hal::port::{PD0, PD1},
-        pac::USART0,
-        port::{
-            mode::{Input, Output},
-            Pin,
-        },
-        Usart,
-    };
-    use core::{arch::asm, fmt::Write};
+
+    #[cfg(target_arch = "avr")]
+    use crate::basino_progmem_read;
 
     /// Write a test result status and message about the test
     ///
-    /// writer is the Usart object to write to
+    /// reporter is the TestReporter to report the result to
     /// test_result is the result of the test:
     ///   if it was true the test was successful
     ///   if it was false the test was a failure
     /// status_msg is a string describing the test
-    pub fn write_test_result(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-        test_result: bool,
-        status_msg: &str,
-    ) {
-        if test_result {
-            ufmt::uwrite!(writer, "SUCCESS").unwrap();
-        } else {
-            ufmt::uwrite!(writer, "FAILURE").unwrap();
-        }
-        ufmt::uwriteln!(writer, " {}\r", status_msg).unwrap();
+    pub fn write_test_result(reporter: &mut dyn TestReporter, test_result: bool, status_msg: &str) {
+        reporter.check(test_result, status_msg);
     }
 
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("gt_gt_works", test_basino_gt_gt_works),
+        TestCase::new("gt_eq_works", test_basino_gt_eq_works),
+        TestCase::new("gt_lt_works", test_basino_gt_lt_works),
+        TestCase::new("gt_eq_gt_works", test_basino_gt_eq_gt_works),
+        TestCase::new("gt_eq_eq_works", test_basino_gt_eq_eq_works),
+        TestCase::new("gt_eq_lt_works", test_basino_gt_eq_lt_works),
+        TestCase::new("progmem_read_works", test_basino_progmem_read_works),
+    ];
+
     /// Run all the tests in this module
-    pub fn run_tests(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
-        test_basino_gt_gt_works(writer);
-        test_basino_gt_eq_works(writer);
-        test_basino_gt_lt_works(writer);
-        test_basino_gt_eq_gt_works(writer);
-        test_basino_gt_eq_eq_works(writer);
-        test_basino_gt_eq_lt_works(writer);
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "base", TEST_CASES);
     }
 
     /// Test that basino_gt works for greater than
-    pub fn test_basino_gt_gt_works(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
+    pub fn test_basino_gt_gt_works(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_gt(0x1000, 0x0010) };
-        write_test_result(writer, res == 1, "0x1000 should be > 0x0010");
+        write_test_result(reporter, res == 1, "0x1000 should be > 0x0010");
     }
 
     /// Test that basino_gt works for equal
-    pub fn test_basino_gt_eq_works(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
+    pub fn test_basino_gt_eq_works(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_gt(0x1111, 0x1111) };
-        write_test_result(writer, res == 0, "0x1111 should not be > 0x1111");
+        write_test_result(reporter, res == 0, "0x1111 should not be > 0x1111");
     }
 
     /// Test that basino_gt works for less than
-    pub fn test_basino_gt_lt_works(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
+    pub fn test_basino_gt_lt_works(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_gt(0x0010, 0x1000) };
-        write_test_result(writer, res == 0, "0x0010 should not be > 0x1000");
+        write_test_result(reporter, res == 0, "0x0010 should not be > 0x1000");
     }
 
     /// Test that basino_gt_eq works for greater than
-    pub fn test_basino_gt_eq_gt_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_basino_gt_eq_gt_works(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_gt_eq(0x1000, 0x0010) };
-        write_test_result(writer, res == 1, "0x1000 should be >= 0x0010");
+        write_test_result(reporter, res == 1, "0x1000 should be >= 0x0010");
     }
 
     /// Test that basino_gt_eq works for equal
-    pub fn test_basino_gt_eq_eq_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_basino_gt_eq_eq_works(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_gt_eq(0x1111, 0x1111) };
-        write_test_result(writer, res == 1, "0x1111 should be >= 0x1111");
+        write_test_result(reporter, res == 1, "0x1111 should be >= 0x1111");
     }
 
     /// Test that basino_gt_eq works for less than
-    pub fn test_basino_gt_eq_lt_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_basino_gt_eq_lt_works(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_gt_eq(0x0010, 0x1000) };
-        write_test_result(writer, res == 0, "0x0010 should not be >= 0x1000");
+        write_test_result(reporter, res == 0, "0x0010 should not be >= 0x1000");
+    }
+
+    /// A small table stored in program memory instead of SRAM
+    #[cfg(target_arch = "avr")]
+    #[link_section = ".progmem.data"]
+    static PROGMEM_TABLE: [u8; 4] = [0x12, 0x34, 0xAB, 0xCD];
+
+    /// Test that basino_progmem_read reads bytes from flash
+    ///
+    /// The table can't be read directly from Rust, the address of a
+    /// static in .progmem.data is a program memory address.
+    #[cfg(target_arch = "avr")]
+    pub fn test_basino_progmem_read_works(reporter: &mut dyn TestReporter) {
+        let address = core::ptr::addr_of!(PROGMEM_TABLE) as u16;
+        let expected: [u8; 4] = [0x12, 0x34, 0xAB, 0xCD];
+
+        for (i, e) in expected.iter().enumerate() {
+            let res = unsafe { basino_progmem_read(address + i as u16) };
+            write_test_result(
+                reporter,
+                res == *e,
+                "progmem read should return the byte stored in flash",
+            );
+        }
+    }
+
+    /// Test that basino_progmem_read reads bytes from flash
+    ///
+    /// Other targets don't have a separate program memory.
+    #[cfg(not(target_arch = "avr"))]
+    pub fn test_basino_progmem_read_works(reporter: &mut dyn TestReporter) {
+        reporter.skip("there's no program memory on this target");
     }
 }
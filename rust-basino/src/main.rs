@@ -1,13 +1,18 @@
 //! A simple stack implementation for Arduino devices
 #![warn(missing_docs)]
-#![no_std]
-#![no_main]
+#![cfg_attr(target_arch = "avr", no_std)]
+#![cfg_attr(target_arch = "avr", no_main)]
 
 // use ufmt::UnstableDoAsFormatter;
 // use ufmt_utils::WriteAdapter;
 
+#[cfg(target_arch = "avr")]
 use panic_halt as _;
 
+#[cfg(target_arch = "avr")]
+use rust_basino::testing::{Format, Reporter, TestReporter};
+
+#[cfg(target_arch = "avr")]
 #[arduino_hal::entry]
 fn main() -> ! {
     let dp = arduino_hal::Peripherals::take().unwrap();
@@ -31,14 +36,54 @@ fn main() -> ! {
     // },)
     // .unwrap();
 
-    #[cfg(feature = "test-base")]
-    rust_basino::tests::run_tests(&mut serial);
-    #[cfg(feature = "test-queue")]
-    rust_basino::queue::tests::run_tests(&mut serial);
-    #[cfg(feature = "test-stack")]
-    rust_basino::stack::tests::run_tests(&mut serial);
+    {
+        let mut reporter = Reporter::new(&mut serial, Format::default());
+
+        #[cfg(feature = "test-base")]
+        rust_basino::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-queue")]
+        rust_basino::queue::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-stack")]
+        rust_basino::stack::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-hardware")]
+        rust_basino::hardware::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-memory")]
+        rust_basino::memory::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-events")]
+        rust_basino::events::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-tick")]
+        rust_basino::tick::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-serial")]
+        rust_basino::serial::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-monitor")]
+        rust_basino::monitor::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-eeprom")]
+        rust_basino::eeprom::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-kvstore")]
+        rust_basino::kvstore::tests::run_tests(&mut reporter);
+        #[cfg(feature = "test-testing")]
+        rust_basino::testing::tests::run_tests(&mut reporter);
+
+        reporter.finish();
+    }
+
+    #[cfg(feature = "monitor")]
+    {
+        let mut eeprom = arduino_hal::Eeprom::new(dp.EEPROM);
+
+        rust_basino::serial::init().unwrap();
+        unsafe { avr_device::interrupt::enable() };
+
+        rust_basino::monitor::Monitor::new(&mut eeprom).run(&mut serial, rust_basino::serial::read);
+
+        avr_device::interrupt::disable();
+    }
 
     loop {
         avr_device::asm::sleep();
     }
 }
+
+/// The firmware only runs on AVR
+#[cfg(not(target_arch = "avr"))]
+fn main() {}
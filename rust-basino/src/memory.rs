@@ -0,0 +1,307 @@
+//! Direct memory access functions
+//!
+//! These are the building blocks for the BASIC PEEK, POKE and USR
+//! statements.  They let a program read and write data memory,
+//! including the memory-mapped I/O registers, and call assembly
+//! routines in program memory.
+//!
+//! POKE refuses to write over the structures basino keeps in the
+//! .ram2bss section unless the unsafe override is set.  Writing over
+//! the stack or queue buffers while they are in use corrupts them in
+//! ways that are hard to debug.
+#![warn(missing_docs)]
+
+use crate::{
+    events::{BASINO_EVENT_QUEUE, BASINO_EVENT_QUEUE_DATA},
+    serial::{BASINO_RX_QUEUE, BASINO_RX_QUEUE_DATA, BASINO_TX_QUEUE, BASINO_TX_QUEUE_DATA},
+    BASINO_QUEUE_DATA, BASINO_STACK_BUFFER, BASINO_STACK_FILLER, DEVICE_PERIPHERALS_SPACE,
+};
+
+use core::fmt::{Debug, Display, Formatter};
+use ufmt::{uDebug, uWrite};
+
+/// The kinds of errors that can occur working with memory
+#[derive(Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The address is in a region used by basino
+    ProtectedAddress,
+    /// An unknown error type
+    Unknown,
+}
+
+impl uDebug for ErrorKind {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        match self {
+            ErrorKind::ProtectedAddress => f.write_str("The address is protected"),
+            ErrorKind::Unknown => f.write_str("An unknown error occurred"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ErrorKind::ProtectedAddress => write!(f, "The address is protected"),
+            ErrorKind::Unknown => write!(f, "An unknown error occurred"),
+        }
+    }
+}
+
+/// An error that can occur when working with memory
+#[derive(PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// The number of protected memory regions
+pub const NUM_PROTECTED_REGIONS: usize = 10;
+
+/// Get the start address and length of a static
+///
+/// This takes a raw pointer so it works for static mut items without
+/// making a reference to them.
+fn region<T>(ptr: *const T) -> (usize, usize) {
+    (ptr as usize, core::mem::size_of::<T>())
+}
+
+/// Get the regions of data memory that POKE won't write to.
+///
+/// Each region is a start address and a length in bytes.
+///
+/// These are the statics basino keeps in the .ram2bss section.  The
+/// addresses are taken from the statics themselves instead of the
+/// linker script, the linker script placement isn't reliable yet.
+pub fn protected_regions() -> [(usize, usize); NUM_PROTECTED_REGIONS] {
+    [
+        (
+            core::ptr::addr_of!(BASINO_STACK_FILLER) as usize,
+            core::mem::size_of::<u8>(),
+        ),
+        (
+            core::ptr::addr_of!(DEVICE_PERIPHERALS_SPACE) as usize,
+            core::mem::size_of::<u8>(),
+        ),
+        (
+            core::ptr::addr_of!(BASINO_STACK_BUFFER) as usize,
+            core::mem::size_of_val(&BASINO_STACK_BUFFER),
+        ),
+        (
+            core::ptr::addr_of!(BASINO_QUEUE_DATA) as usize,
+            core::mem::size_of_val(&BASINO_QUEUE_DATA),
+        ),
+        region(core::ptr::addr_of!(BASINO_EVENT_QUEUE_DATA)),
+        region(core::ptr::addr_of!(BASINO_EVENT_QUEUE)),
+        region(core::ptr::addr_of!(BASINO_RX_QUEUE_DATA)),
+        region(core::ptr::addr_of!(BASINO_RX_QUEUE)),
+        region(core::ptr::addr_of!(BASINO_TX_QUEUE_DATA)),
+        region(core::ptr::addr_of!(BASINO_TX_QUEUE)),
+    ]
+}
+
+/// Test if an address is in one of the protected regions
+pub fn is_protected(address: u16) -> bool {
+    let address = address as usize;
+
+    protected_regions()
+        .iter()
+        .any(|(start, len)| address >= *start && address < start + len)
+}
+
+/// Read a byte from data memory
+///
+/// Any address can be read, this includes the registers in the first
+/// 32 bytes and the I/O registers after them.
+pub fn peek(address: u16) -> u8 {
+    unsafe { core::ptr::read_volatile(address as usize as *const u8) }
+}
+
+/// Write a byte to data memory
+///
+/// Returns a ProtectedAddress error if the address is in one of the
+/// basino structures and unsafe_override is false.
+pub fn poke(address: u16, value: u8, unsafe_override: bool) -> Result<(), Error> {
+    if !unsafe_override && is_protected(address) {
+        return Err(Error::new(ErrorKind::ProtectedAddress));
+    }
+
+    unsafe { core::ptr::write_volatile(address as usize as *mut u8, value) };
+
+    Ok(())
+}
+
+/// Call a routine in program memory
+///
+/// address is the byte address of the routine, the address avr-nm
+/// shows for a symbol.  Function pointers on AVR are word addresses,
+/// so it's divided by two before the call.
+///
+/// The routine is called with the calling convention in basino.S:
+/// arg is passed in r25:r24 and the result is returned in r25:r24.
+///
+/// # Safety
+///
+/// address must be the start of a routine that follows the avr-gcc
+/// calling convention.  Calling anything else will crash or corrupt
+/// the system.
+pub unsafe fn usr(address: u16, arg: u16) -> u16 {
+    let routine: extern "C" fn(u16) -> u16 = core::mem::transmute(address >> 1);
+
+    routine(arg)
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+pub mod tests {
+    use crate::{
+        memory::{is_protected, peek, poke, usr, ErrorKind},
+        testing::{run_suite, TestCase, TestReporter},
+        tests::write_test_result,
+        BASINO_QUEUE_DATA, BASINO_STACK_BUFFER,
+    };
+
+    /// A byte that can be safely written to
+    static mut SCRATCH: u8 = 0x5A;
+
+    /// A routine to call with usr
+    extern "C" fn usr_double(arg: u16) -> u16 {
+        arg.wrapping_mul(2)
+    }
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("peek_works", test_memory_peek_works),
+        TestCase::new("poke_works", test_memory_poke_works),
+        TestCase::new(
+            "poke_stack_buffer_fails",
+            test_memory_poke_stack_buffer_fails,
+        ),
+        TestCase::new(
+            "poke_queue_buffer_fails",
+            test_memory_poke_queue_buffer_fails,
+        ),
+        TestCase::new(
+            "poke_unsafe_override_works",
+            test_memory_poke_unsafe_override_works,
+        ),
+        TestCase::new("usr_works", test_memory_usr_works),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "memory", TEST_CASES);
+    }
+
+    /// Test that peek reads a value in memory
+    pub fn test_memory_peek_works(reporter: &mut dyn TestReporter) {
+        let address = core::ptr::addr_of!(SCRATCH) as u16;
+
+        unsafe { core::ptr::write_volatile(core::ptr::addr_of_mut!(SCRATCH), 0x5A) };
+
+        let res = peek(address);
+        write_test_result(
+            reporter,
+            res == 0x5A,
+            "peek should read the value in memory",
+        );
+    }
+
+    /// Test that poke writes a value to memory
+    pub fn test_memory_poke_works(reporter: &mut dyn TestReporter) {
+        let address = core::ptr::addr_of!(SCRATCH) as u16;
+
+        let res = poke(address, 0xA5, false);
+        write_test_result(
+            reporter,
+            res.is_ok(),
+            "poke to unprotected memory should work",
+        );
+
+        let value = unsafe { core::ptr::read_volatile(core::ptr::addr_of!(SCRATCH)) };
+        write_test_result(reporter, value == 0xA5, "poke should write the value");
+    }
+
+    /// Test that poke into the stack buffer fails
+    pub fn test_memory_poke_stack_buffer_fails(reporter: &mut dyn TestReporter) {
+        let address = core::ptr::addr_of!(BASINO_STACK_BUFFER) as u16;
+
+        write_test_result(
+            reporter,
+            is_protected(address),
+            "stack buffer should be protected",
+        );
+
+        let res = poke(address, 0, false);
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::ProtectedAddress,
+                    "poke to stack buffer should fail",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "poke to stack buffer should fail");
+            }
+        }
+    }
+
+    /// Test that poke into the last byte of the queue buffer fails
+    pub fn test_memory_poke_queue_buffer_fails(reporter: &mut dyn TestReporter) {
+        let address = core::ptr::addr_of!(BASINO_QUEUE_DATA) as usize
+            + core::mem::size_of_val(&BASINO_QUEUE_DATA)
+            - 1;
+
+        let res = poke(address as u16, 0, false);
+        write_test_result(reporter, res.is_err(), "poke to queue buffer should fail");
+    }
+
+    /// Test that poke into a protected region works with the override
+    pub fn test_memory_poke_unsafe_override_works(reporter: &mut dyn TestReporter) {
+        let address = core::ptr::addr_of!(BASINO_STACK_BUFFER) as u16;
+
+        // Write back the same value so the buffer isn't changed
+        let value = peek(address);
+        let res = poke(address, value, true);
+        write_test_result(
+            reporter,
+            res.is_ok(),
+            "poke to protected memory should work with the override",
+        );
+    }
+
+    /// Test that usr calls a routine and returns its result
+    pub fn test_memory_usr_works(reporter: &mut dyn TestReporter) {
+        // Function pointers are word addresses, usr takes a byte address
+        let address = (usr_double as extern "C" fn(u16) -> u16 as usize as u16) << 1;
+
+        let res = unsafe { usr(address, 0x1234) };
+        write_test_result(
+            reporter,
+            res == 0x2468,
+            "usr should return the routine result",
+        );
+    }
+}
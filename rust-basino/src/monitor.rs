@@ -0,0 +1,713 @@
+//! A serial memory monitor
+//!
+//! The monitor is a small command line for looking at memory without
+//! gdb.  It reads a line at a time from the serial console and prints
+//! the results back.  The commands are:
+//!
+//!   d addr [len]      dump data memory (SRAM and registers)
+//!   f addr [len]      dump program memory (flash, read with lpm)
+//!   e addr [len]      dump EEPROM
+//!   i                 dump the I/O registers, 0x20 to 0xFF
+//!   w addr b [b ...]  write bytes to data memory
+//!   w! addr b [b ...] write bytes, even to protected memory
+//!   ew addr b [b ...] write bytes to EEPROM
+//!   s addr            decode a Stack structure
+//!   q addr            decode a QueueObj structure
+//!   h                 print the commands
+//!   x                 leave the monitor
+//!
+//! All numbers are hexadecimal, with or without a 0x prefix.  Dumps
+//! print sixteen bytes per line as hex followed by ASCII.  Registers
+//! that change state when they're read, like UDR0, aren't read by a
+//! data dump and show as --.
+//!
+//! Writes to data memory go through memory::poke, so the basino
+//! structures in .ram2bss are protected unless w! is used.
+#![warn(missing_docs)]
+
+use crate::{
+    basino_progmem_read,
+    eeprom::Storage,
+    memory::{peek, poke},
+};
+
+use core::fmt::{Debug, Display, Formatter};
+use ufmt::{uDebug, uWrite};
+
+/// The kinds of errors that can occur in the monitor
+#[derive(Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The command doesn't exist
+    UnknownCommand,
+    /// A required argument is missing
+    MissingArgument,
+    /// An argument isn't a valid hexadecimal number
+    InvalidArgument,
+    /// The address is protected, use w! to write it
+    ProtectedAddress,
+    /// The EEPROM couldn't be read or written
+    StorageError,
+    /// The output couldn't be written
+    WriteError,
+    /// An unknown error type
+    Unknown,
+}
+
+impl uDebug for ErrorKind {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        match self {
+            ErrorKind::UnknownCommand => f.write_str("Unknown command"),
+            ErrorKind::MissingArgument => f.write_str("Missing argument"),
+            ErrorKind::InvalidArgument => f.write_str("Invalid argument"),
+            ErrorKind::ProtectedAddress => f.write_str("The address is protected"),
+            ErrorKind::StorageError => f.write_str("EEPROM access failed"),
+            ErrorKind::WriteError => f.write_str("Output failed"),
+            ErrorKind::Unknown => f.write_str("An unknown error occurred"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ErrorKind::UnknownCommand => write!(f, "Unknown command"),
+            ErrorKind::MissingArgument => write!(f, "Missing argument"),
+            ErrorKind::InvalidArgument => write!(f, "Invalid argument"),
+            ErrorKind::ProtectedAddress => write!(f, "The address is protected"),
+            ErrorKind::StorageError => write!(f, "EEPROM access failed"),
+            ErrorKind::WriteError => write!(f, "Output failed"),
+            ErrorKind::Unknown => write!(f, "An unknown error occurred"),
+        }
+    }
+}
+
+/// An error that can occur in the monitor
+#[derive(PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl uDebug for Error {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        uDebug::fmt(&self.kind, f)
+    }
+}
+
+/// The number of bytes dumped when no length is given
+pub const DEFAULT_DUMP_LEN: u16 = 64;
+
+/// The longest command line the monitor reads
+pub const LINE_LEN: usize = 64;
+
+/// The first I/O register address in data memory
+const IO_START: u16 = 0x20;
+/// The number of I/O and extended I/O registers
+const IO_LEN: u16 = 0xE0;
+
+/// The registers a data dump skips because reading them has side
+/// effects
+///
+/// Reading UDR0 takes a byte out of the USART receive buffer.
+/// Reading SPDR right after SPSR, which a dump does, clears SPIF.
+pub const UNREADABLE_REGISTERS: [u16; 2] = [0x4E, 0xC6];
+
+/// What the monitor should do after a command
+#[derive(Eq, PartialEq)]
+pub enum Action {
+    /// Read the next command
+    Continue,
+    /// Leave the monitor
+    Exit,
+}
+
+/// The memory spaces the monitor can dump
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Space {
+    Data,
+    Program,
+    Eeprom,
+}
+
+/// Write a string, mapping writer errors to monitor errors
+fn out<W: uWrite + ?Sized>(writer: &mut W, s: &str) -> Result<(), Error> {
+    writer
+        .write_str(s)
+        .map_err(|_| Error::new(ErrorKind::WriteError))
+}
+
+/// Write a byte as two hexadecimal digits
+fn out_hex8<W: uWrite + ?Sized>(writer: &mut W, value: u8) -> Result<(), Error> {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    writer
+        .write_char(DIGITS[(value >> 4) as usize] as char)
+        .and_then(|_| writer.write_char(DIGITS[(value & 0x0F) as usize] as char))
+        .map_err(|_| Error::new(ErrorKind::WriteError))
+}
+
+/// Write a word as four hexadecimal digits
+fn out_hex16<W: uWrite + ?Sized>(writer: &mut W, value: u16) -> Result<(), Error> {
+    out_hex8(writer, (value >> 8) as u8)?;
+    out_hex8(writer, value as u8)
+}
+
+/// Parse a hexadecimal number with an optional 0x prefix
+pub fn parse_hex(s: &str) -> Result<u16, Error> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+
+    u16::from_str_radix(digits, 16).map_err(|_| Error::new(ErrorKind::InvalidArgument))
+}
+
+/// Parse a hexadecimal byte
+fn parse_byte(s: &str) -> Result<u8, Error> {
+    let value = parse_hex(s)?;
+
+    if value > 0xFF {
+        return Err(Error::new(ErrorKind::InvalidArgument));
+    }
+
+    Ok(value as u8)
+}
+
+/// Read a little-endian word from data memory
+fn peek16(address: u16) -> u16 {
+    peek(address) as u16 | ((peek(address.wrapping_add(1)) as u16) << 8)
+}
+
+/// The serial memory monitor
+///
+/// The monitor borrows the EEPROM storage so it can dump and write
+/// it.  Any Storage works, tests use a RamStorage.
+pub struct Monitor<'a, S: Storage> {
+    eeprom: &'a mut S,
+}
+
+impl<'a, S: Storage> Monitor<'a, S> {
+    /// Create a new monitor
+    pub fn new(eeprom: &'a mut S) -> Self {
+        Monitor { eeprom }
+    }
+
+    /// Read a byte from one of the memory spaces
+    fn read(&mut self, space: Space, address: u16) -> Result<u8, Error> {
+        match space {
+            Space::Data => Ok(peek(address)),
+            Space::Program => Ok(unsafe { basino_progmem_read(address) }),
+            Space::Eeprom => self
+                .eeprom
+                .read_byte(address)
+                .map_err(|_| Error::new(ErrorKind::StorageError)),
+        }
+    }
+
+    /// Dump len bytes from a memory space starting at address
+    fn dump<W: uWrite + ?Sized>(
+        &mut self,
+        writer: &mut W,
+        space: Space,
+        address: u16,
+        len: u16,
+    ) -> Result<(), Error> {
+        let mut line = [0u8; 16];
+        let mut offset: u16 = 0;
+
+        while offset < len {
+            let line_address = address.wrapping_add(offset);
+            let count = core::cmp::min(16, len - offset) as usize;
+
+            out_hex16(writer, line_address)?;
+            out(writer, ":")?;
+
+            for (i, byte) in line.iter_mut().enumerate().take(count) {
+                let byte_address = line_address.wrapping_add(i as u16);
+                out(writer, " ")?;
+                if space == Space::Data && UNREADABLE_REGISTERS.contains(&byte_address) {
+                    *byte = 0;
+                    out(writer, "--")?;
+                } else {
+                    *byte = self.read(space, byte_address)?;
+                    out_hex8(writer, *byte)?;
+                }
+            }
+            for _ in count..16 {
+                out(writer, "   ")?;
+            }
+
+            out(writer, " |")?;
+            for byte in line.iter().take(count) {
+                let c = if (0x20..0x7F).contains(byte) {
+                    *byte as char
+                } else {
+                    '.'
+                };
+                writer
+                    .write_char(c)
+                    .map_err(|_| Error::new(ErrorKind::WriteError))?;
+            }
+            out(writer, "|\r\n")?;
+
+            offset += count as u16;
+        }
+
+        Ok(())
+    }
+
+    /// Decode a Stack structure in data memory
+    fn decode_stack<W: uWrite + ?Sized>(
+        &mut self,
+        writer: &mut W,
+        address: u16,
+    ) -> Result<(), Error> {
+        let top_sentinel = peek16(address.wrapping_add(2));
+        let bottom = peek16(address.wrapping_add(4));
+        let top = peek16(address.wrapping_add(6));
+
+        out(writer, "stack data 0x")?;
+        out_hex16(writer, peek16(address))?;
+        out(writer, ", top sentinel: 0x")?;
+        out_hex16(writer, top_sentinel)?;
+        out(writer, ", bottom: 0x")?;
+        out_hex16(writer, bottom)?;
+        out(writer, ", top: 0x")?;
+        out_hex16(writer, top)?;
+        out(writer, ", depth: 0x")?;
+        out_hex16(writer, top_sentinel.wrapping_sub(top))?;
+        out(writer, "\r\n")
+    }
+
+    /// Decode a QueueObj structure in data memory
+    fn decode_queue<W: uWrite + ?Sized>(
+        &mut self,
+        writer: &mut W,
+        address: u16,
+    ) -> Result<(), Error> {
+        out(writer, "queue array 0x")?;
+        out_hex16(writer, peek16(address))?;
+        out(writer, ", start: 0x")?;
+        out_hex16(writer, peek16(address.wrapping_add(2)))?;
+        out(writer, ", end: 0x")?;
+        out_hex16(writer, peek16(address.wrapping_add(4)))?;
+        out(writer, ", head: 0x")?;
+        out_hex16(writer, peek16(address.wrapping_add(6)))?;
+        out(writer, ", last head: 0x")?;
+        out_hex16(writer, peek16(address.wrapping_add(8)))?;
+        out(writer, ", tail: 0x")?;
+        out_hex16(writer, peek16(address.wrapping_add(10)))?;
+        out(writer, "\r\n")
+    }
+
+    /// Execute one command line
+    ///
+    /// Returns the action the monitor should take next.  Errors are
+    /// returned to the caller, run prints them and carries on.
+    pub fn execute<W: uWrite + ?Sized>(
+        &mut self,
+        writer: &mut W,
+        line: &str,
+    ) -> Result<Action, Error> {
+        let mut args = line.split_whitespace();
+
+        let command = match args.next() {
+            Some(c) => c,
+            None => return Ok(Action::Continue),
+        };
+
+        match command {
+            "d" | "f" | "e" => {
+                let space = match command {
+                    "d" => Space::Data,
+                    "f" => Space::Program,
+                    _ => Space::Eeprom,
+                };
+                let address =
+                    parse_hex(args.next().ok_or(Error::new(ErrorKind::MissingArgument))?)?;
+                let len = match args.next() {
+                    Some(l) => parse_hex(l)?,
+                    None => DEFAULT_DUMP_LEN,
+                };
+                self.dump(writer, space, address, len)?;
+            }
+            "i" => self.dump(writer, Space::Data, IO_START, IO_LEN)?,
+            "w" | "w!" => {
+                let address =
+                    parse_hex(args.next().ok_or(Error::new(ErrorKind::MissingArgument))?)?;
+                let mut offset: u16 = 0;
+                for arg in args {
+                    let value = parse_byte(arg)?;
+                    poke(address.wrapping_add(offset), value, command == "w!")
+                        .map_err(|_| Error::new(ErrorKind::ProtectedAddress))?;
+                    offset += 1;
+                }
+                if offset == 0 {
+                    return Err(Error::new(ErrorKind::MissingArgument));
+                }
+            }
+            "ew" => {
+                let address =
+                    parse_hex(args.next().ok_or(Error::new(ErrorKind::MissingArgument))?)?;
+                let mut offset: u16 = 0;
+                for arg in args {
+                    let value = parse_byte(arg)?;
+                    self.eeprom
+                        .update_byte(address.wrapping_add(offset), value)
+                        .map_err(|_| Error::new(ErrorKind::StorageError))?;
+                    offset += 1;
+                }
+                if offset == 0 {
+                    return Err(Error::new(ErrorKind::MissingArgument));
+                }
+            }
+            "s" => {
+                let address =
+                    parse_hex(args.next().ok_or(Error::new(ErrorKind::MissingArgument))?)?;
+                self.decode_stack(writer, address)?;
+            }
+            "q" => {
+                let address =
+                    parse_hex(args.next().ok_or(Error::new(ErrorKind::MissingArgument))?)?;
+                self.decode_queue(writer, address)?;
+            }
+            "h" | "?" => {
+                out(writer, "d addr [len]      dump data memory\r\n")?;
+                out(writer, "f addr [len]      dump program memory\r\n")?;
+                out(writer, "e addr [len]      dump EEPROM\r\n")?;
+                out(writer, "i                 dump the I/O registers\r\n")?;
+                out(writer, "w addr b [b ...]  write data memory\r\n")?;
+                out(writer, "w! addr b [b ...] write protected data memory\r\n")?;
+                out(writer, "ew addr b [b ...] write EEPROM\r\n")?;
+                out(writer, "s addr            decode a Stack\r\n")?;
+                out(writer, "q addr            decode a QueueObj\r\n")?;
+                out(writer, "x                 exit\r\n")?;
+            }
+            "x" => return Ok(Action::Exit),
+            _ => return Err(Error::new(ErrorKind::UnknownCommand)),
+        }
+
+        Ok(Action::Continue)
+    }
+
+    /// Run the monitor until the x command
+    ///
+    /// read returns the next byte from the serial console, for example
+    /// serial::read.  Characters are echoed, backspace and delete
+    /// remove the last character and carriage return or line feed end
+    /// the line.
+    pub fn run<W: uWrite + ?Sized, R: FnMut() -> u8>(&mut self, writer: &mut W, mut read: R) {
+        let mut buffer = [0u8; LINE_LEN];
+
+        loop {
+            let _ = out(writer, "> ");
+
+            let mut len = 0;
+            loop {
+                let byte = read();
+                match byte {
+                    b'\r' | b'\n' => break,
+                    0x08 | 0x7F => {
+                        if len > 0 {
+                            len -= 1;
+                            let _ = out(writer, "\x08 \x08");
+                        }
+                    }
+                    0x20..=0x7E if len < LINE_LEN => {
+                        buffer[len] = byte;
+                        len += 1;
+                        let _ = writer.write_char(byte as char);
+                    }
+                    _ => {}
+                }
+            }
+            let _ = out(writer, "\r\n");
+
+            // Only printable ASCII is stored, so this is always valid
+            let line = core::str::from_utf8(&buffer[..len]).unwrap_or("");
+
+            match self.execute(writer, line) {
+                Ok(Action::Exit) => return,
+                Ok(Action::Continue) => {}
+                Err(e) => {
+                    let _ = ufmt::uwrite!(writer, "? {:?}\r\n", e);
+                }
+            }
+        }
+    }
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+pub mod tests {
+    use crate::{
+        eeprom::RamStorage,
+        monitor::{out_hex16, parse_hex, Action, ErrorKind, Monitor},
+        testing::{run_suite, BufferWriter, TestCase, TestReporter},
+        tests::write_test_result,
+        ArrayHandle, Stack, BASINO_STACK_BUFFER,
+    };
+
+    use crate::stack::StackImpl;
+    use avr_device::interrupt::free;
+    use ufmt::uWrite;
+
+    /// Build a command line with an address in hexadecimal
+    fn command(prefix: &str, address: u16, suffix: &str) -> BufferWriter {
+        let mut line = BufferWriter::new();
+        line.write_str(prefix).unwrap();
+        out_hex16(&mut line, address).unwrap();
+        line.write_str(suffix).unwrap();
+        line
+    }
+
+    /// Bytes to dump
+    static mut DUMP_DATA: [u8; 4] = [0x41, 0x42, 0x00, 0x7F];
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("parse_hex_works", test_monitor_parse_hex_works),
+        TestCase::new("dump_works", test_monitor_dump_works),
+        TestCase::new(
+            "dump_skips_unreadable_registers",
+            test_monitor_dump_skips_unreadable_registers,
+        ),
+        TestCase::new(
+            "eeprom_write_and_dump_works",
+            test_monitor_eeprom_write_and_dump_works,
+        ),
+        TestCase::new("write_works", test_monitor_write_works),
+        TestCase::new("write_protected_fails", test_monitor_write_protected_fails),
+        TestCase::new("decode_stack_works", test_monitor_decode_stack_works),
+        TestCase::new("unknown_command_fails", test_monitor_unknown_command_fails),
+        TestCase::new("exit_works", test_monitor_exit_works),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "monitor", TEST_CASES);
+    }
+
+    /// Test parsing hexadecimal numbers
+    pub fn test_monitor_parse_hex_works(reporter: &mut dyn TestReporter) {
+        write_test_result(
+            reporter,
+            matches!(parse_hex("0x1f"), Ok(0x1F)),
+            "parse_hex should parse a 0x prefixed number",
+        );
+        write_test_result(
+            reporter,
+            matches!(parse_hex("ABCD"), Ok(0xABCD)),
+            "parse_hex should parse a number without a prefix",
+        );
+        write_test_result(
+            reporter,
+            parse_hex("xyz").is_err(),
+            "parse_hex should fail on a non-hex number",
+        );
+    }
+
+    /// Test dumping data memory as hex and ASCII
+    pub fn test_monitor_dump_works(reporter: &mut dyn TestReporter) {
+        let mut data = [0u8; 16];
+        let mut storage = RamStorage::new(&mut data);
+        let mut monitor = Monitor::new(&mut storage);
+        let mut output = BufferWriter::new();
+
+        let address = core::ptr::addr_of!(DUMP_DATA) as u16;
+        let command = command("d ", address, " 4");
+
+        let res = monitor.execute(&mut output, command.as_str());
+        write_test_result(reporter, res.is_ok(), "dump should work");
+
+        let s = output.as_str();
+        write_test_result(
+            reporter,
+            s.contains(": 41 42 00 7F") && s.contains("|AB..|"),
+            "dump should print hex and ASCII",
+        );
+    }
+
+    /// Test that a dump doesn't read UDR0
+    pub fn test_monitor_dump_skips_unreadable_registers(reporter: &mut dyn TestReporter) {
+        let mut data = [0u8; 16];
+        let mut storage = RamStorage::new(&mut data);
+        let mut monitor = Monitor::new(&mut storage);
+        let mut output = BufferWriter::new();
+
+        let res = monitor.execute(&mut output, "d c4 4");
+        write_test_result(reporter, res.is_ok(), "dump should work");
+        write_test_result(
+            reporter,
+            output.as_str().contains(" -- "),
+            "dump should print -- for UDR0",
+        );
+    }
+
+    /// Test writing and dumping EEPROM
+    pub fn test_monitor_eeprom_write_and_dump_works(reporter: &mut dyn TestReporter) {
+        let mut data = [0u8; 16];
+        let mut storage = RamStorage::new(&mut data);
+        let mut monitor = Monitor::new(&mut storage);
+        let mut output = BufferWriter::new();
+
+        let res = monitor.execute(&mut output, "ew 2 48 69");
+        write_test_result(reporter, res.is_ok(), "EEPROM write should work");
+
+        let res = monitor.execute(&mut output, "e 0 4");
+        write_test_result(reporter, res.is_ok(), "EEPROM dump should work");
+        write_test_result(
+            reporter,
+            output.as_str().starts_with("0000: FF FF 48 69"),
+            "EEPROM dump should show the written bytes",
+        );
+    }
+
+    /// Test writing data memory
+    pub fn test_monitor_write_works(reporter: &mut dyn TestReporter) {
+        let mut data = [0u8; 16];
+        let mut storage = RamStorage::new(&mut data);
+        let mut monitor = Monitor::new(&mut storage);
+        let mut output = BufferWriter::new();
+
+        let address = core::ptr::addr_of!(DUMP_DATA) as u16;
+        let command = command("w ", address, " 11 22");
+
+        let res = monitor.execute(&mut output, command.as_str());
+        write_test_result(reporter, res.is_ok(), "write should work");
+
+        let value = unsafe { core::ptr::read_volatile(core::ptr::addr_of!(DUMP_DATA)) };
+        write_test_result(
+            reporter,
+            value[0] == 0x11 && value[1] == 0x22,
+            "write should change memory",
+        );
+
+        unsafe {
+            core::ptr::write_volatile(core::ptr::addr_of_mut!(DUMP_DATA), [0x41, 0x42, 0x00, 0x7F])
+        };
+    }
+
+    /// Test that writing protected memory fails without the override
+    pub fn test_monitor_write_protected_fails(reporter: &mut dyn TestReporter) {
+        let mut data = [0u8; 16];
+        let mut storage = RamStorage::new(&mut data);
+        let mut monitor = Monitor::new(&mut storage);
+        let mut output = BufferWriter::new();
+
+        let address = core::ptr::addr_of!(BASINO_STACK_BUFFER) as u16;
+        let command = command("w ", address, " 0");
+
+        let res = monitor.execute(&mut output, command.as_str());
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::ProtectedAddress,
+                    "write to protected memory should fail",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "write to protected memory should fail");
+            }
+        }
+    }
+
+    /// Test decoding a Stack structure
+    pub fn test_monitor_decode_stack_works(reporter: &mut dyn TestReporter) {
+        free(|cs| {
+            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
+            let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
+            let mut stack = Stack::new(&ah).unwrap();
+            stack.push(1).unwrap();
+
+            let mut data = [0u8; 16];
+            let mut storage = RamStorage::new(&mut data);
+            let mut monitor = Monitor::new(&mut storage);
+            let mut output = BufferWriter::new();
+
+            let address = core::ptr::addr_of!(stack) as u16;
+            let command = command("s ", address, "");
+
+            let res = monitor.execute(&mut output, command.as_str());
+            write_test_result(reporter, res.is_ok(), "decoding a stack should work");
+            write_test_result(
+                reporter,
+                output.as_str().contains("depth: 0x0001"),
+                "the decoded stack should show the depth",
+            );
+        });
+    }
+
+    /// Test that an unknown command fails
+    pub fn test_monitor_unknown_command_fails(reporter: &mut dyn TestReporter) {
+        let mut data = [0u8; 16];
+        let mut storage = RamStorage::new(&mut data);
+        let mut monitor = Monitor::new(&mut storage);
+        let mut output = BufferWriter::new();
+
+        let res = monitor.execute(&mut output, "z 1");
+        match res {
+            Err(e) => {
+                write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::UnknownCommand,
+                    "an unknown command should fail",
+                );
+            }
+            Ok(_) => {
+                write_test_result(reporter, false, "an unknown command should fail");
+            }
+        }
+    }
+
+    /// Test that the exit command ends the monitor
+    pub fn test_monitor_exit_works(reporter: &mut dyn TestReporter) {
+        let mut data = [0u8; 16];
+        let mut storage = RamStorage::new(&mut data);
+        let mut monitor = Monitor::new(&mut storage);
+        let mut output = BufferWriter::new();
+
+        let res = monitor.execute(&mut output, "x");
+        write_test_result(
+            reporter,
+            matches!(res, Ok(Action::Exit)),
+            "x should exit the monitor",
+        );
+
+        // run should also return after reading an x command
+        let input = b"h\rx\r";
+        let mut pos = 0;
+        monitor.run(&mut output, || {
+            let byte = input[pos % input.len()];
+            pos += 1;
+            byte
+        });
+        write_test_result(reporter, pos == input.len(), "run should stop after x");
+    }
+}
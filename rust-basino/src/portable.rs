@@ -0,0 +1,549 @@
+//! Pure-Rust versions of the assembly routines
+//!
+//! The stack and queue call the routines in libbasino, which only
+//! runs on AVR.  On other targets lib.rs uses these instead, so the
+//! library builds on the host and the code built on the stack and
+//! queue can be tested with cargo test.
+//!
+//! Each function takes the same arguments and returns the same codes
+//! as the assembly routine with the same name, and changes the same
+//! fields of the Stack or QueueObj.  That includes the quirks: pop and
+//! get return the status through the result pointer and the value as
+//! the return value, init doesn't set the data field, and queue init
+//! doesn't check the start and end.  Where the assembly returns
+//! whatever was left in a register, these return zero.
+#![warn(missing_docs)]
+
+use crate::{QueueObj, Stack};
+
+use core::ptr;
+
+/// Add two 8-bit unsigned integers together
+///
+/// The carry is in the high byte of the result.
+///
+/// # Safety
+///
+/// This is always safe, it's unsafe to match the assembly version.
+pub unsafe fn basino_add(a: u8, b: u8) -> u16 {
+    a as u16 + b as u16
+}
+
+/// Add two 16-bit addresses together
+/// Returns the sum and writes the carry, zero or one, to carry
+///
+/// # Safety
+///
+/// carry must point to valid memory.
+pub unsafe fn basino_address_add(a: u16, b: u16, carry: *mut u8) -> u16 {
+    let (sum, overflow) = a.overflowing_add(b);
+    *carry = overflow as u8;
+    sum
+}
+
+/// Test whether a is greater than b
+/// Returns one if a is greater than b
+/// Return zero if it isn't
+///
+/// # Safety
+///
+/// This is always safe, it's unsafe to match the assembly version.
+pub unsafe fn basino_gt(a: u16, b: u16) -> u8 {
+    (a > b) as u8
+}
+
+/// Test whether a is greater than or equal to b
+/// Returns one if a is greater than or equal to b
+/// Return zero if it isn't
+///
+/// # Safety
+///
+/// This is always safe, it's unsafe to match the assembly version.
+pub unsafe fn basino_gt_eq(a: u16, b: u16) -> u8 {
+    (a >= b) as u8
+}
+
+/// Initialize the stack.
+/// This initializes with the permanent bottom and maximum top.
+/// It sets the current top and bottom to those values.
+/// The top is a top sentinel, it should be one above the stack
+/// size.
+///
+/// Returns 1 if stack is null and 2 if top isn't greater than bottom.
+///
+/// # Safety
+///
+/// stack must be null or point to a valid stack structure.  top and
+/// bottom must point into the same array.
+pub unsafe fn basino_stack_init(stack: *mut Stack, top: *mut u8, bottom: *mut u8) -> u8 {
+    if stack.is_null() {
+        return 1;
+    }
+    if top <= bottom {
+        return 2;
+    }
+
+    (*stack).top = top;
+    (*stack).bottom = bottom;
+    (*stack).top_sentinel = top;
+
+    0
+}
+
+/// Push a value onto the stack
+///
+/// The top moves down and the value is stored at the new top.
+/// Returns 1 if stack is null and 2 if the stack is full.
+///
+/// # Safety
+///
+/// stack must be null or point to a valid stack structure that was
+/// initialized with basino_stack_init.
+pub unsafe fn basino_stack_push(stack: *const Stack, value: u8) -> u8 {
+    if stack.is_null() {
+        return 1;
+    }
+    let stack = stack.cast_mut();
+    if ptr::eq((*stack).top, (*stack).bottom) {
+        return 2;
+    }
+
+    (*stack).top = (*stack).top.sub(1);
+    *(*stack).top = value;
+
+    0
+}
+
+/// Pop a value from the stack
+///
+/// Returns the value at the top and moves the top up.  The status is
+/// written to result: 0 on success, 1 if stack is null and 2 if the
+/// stack is empty.
+///
+/// # Safety
+///
+/// stack must be null or point to a valid stack structure that was
+/// initialized with basino_stack_init.  result must point to valid
+/// memory.
+pub unsafe fn basino_stack_pop(stack: *const Stack, result: *mut u8) -> u8 {
+    if stack.is_null() {
+        *result = 1;
+        return 0;
+    }
+    let stack = stack.cast_mut();
+    if ptr::eq((*stack).top, (*stack).top_sentinel) {
+        *result = 2;
+        return 0;
+    }
+
+    let value = *(*stack).top;
+    (*stack).top = (*stack).top.add(1);
+    *result = 0;
+
+    value
+}
+
+/// Get the address of the bottom of the stack
+///
+/// # Safety
+///
+/// stack must point to a valid stack structure.
+pub unsafe fn basino_get_basino_stack_bottom(stack: *const Stack) -> *const u8 {
+    (*stack).bottom
+}
+
+/// Get the address of the top of the stack
+///
+/// # Safety
+///
+/// stack must point to a valid stack structure.
+pub unsafe fn basino_get_basino_stack_top(stack: *const Stack) -> *const u8 {
+    (*stack).top
+}
+
+/// Get the address of the top of the stack sentinel
+///
+/// # Safety
+///
+/// stack must point to a valid stack structure.
+pub unsafe fn basino_get_basino_stack_top_sentinel(stack: *const Stack) -> *const u8 {
+    (*stack).top_sentinel
+}
+
+/// Initialize the queue
+///
+/// start is the first element of the queue array and end is the last
+/// one.  Returns 1 if queue is null.
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure.  start and
+/// end must point into the same array.
+pub unsafe fn basino_queue_init(queue: *mut QueueObj, start: *mut u8, end: *mut u8) -> u8 {
+    if queue.is_null() {
+        return 1;
+    }
+
+    (*queue).start = start;
+    (*queue).end = end;
+    (*queue).head = start;
+    (*queue).last_head = end;
+    (*queue).tail = start;
+
+    0
+}
+
+/// Put an item into the queue
+///
+/// The queue is full when the tail reaches the last head.  Returns 1
+/// if queue is null and 2 if the queue is full.
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure that was
+/// initialized with basino_queue_init.
+pub unsafe fn basino_queue_put(queue: *const QueueObj, value: u8) -> u8 {
+    if queue.is_null() {
+        return 1;
+    }
+    let queue = queue.cast_mut();
+    let tail = (*queue).tail;
+    if ptr::eq(tail, (*queue).last_head) {
+        return 2;
+    }
+
+    *tail = value;
+    (*queue).tail = if ptr::eq(tail, (*queue).end) {
+        (*queue).start.cast_mut()
+    } else {
+        tail.add(1)
+    };
+
+    0
+}
+
+/// Get an item from the queue
+///
+/// Returns the item at the head.  The status is written to result: 0
+/// on success, 1 if queue is null and 2 if the queue is empty.
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure that was
+/// initialized with basino_queue_init.  result must point to valid
+/// memory.
+pub unsafe fn basino_queue_get(queue: *const QueueObj, result: *mut u8) -> u8 {
+    if queue.is_null() {
+        *result = 1;
+        return 0;
+    }
+    let queue = queue.cast_mut();
+    let head = (*queue).head;
+    if ptr::eq(head, (*queue).tail) {
+        *result = 2;
+        return 0;
+    }
+
+    let value = *head;
+    (*queue).head = if ptr::eq(head, (*queue).end) {
+        (*queue).start.cast_mut()
+    } else {
+        head.add(1)
+    };
+    (*queue).last_head = head;
+    *result = 0;
+
+    value
+}
+
+/// Get a field of the queue for the info functions
+///
+/// The status is written to result: 0 on success and 1 if queue is
+/// null.
+unsafe fn queue_field(
+    queue: *mut QueueObj,
+    result: *mut u8,
+    field: fn(&QueueObj) -> *const u8,
+) -> *const u8 {
+    if queue.is_null() {
+        *result = 1;
+        return core::ptr::null();
+    }
+
+    *result = 0;
+    field(&*queue)
+}
+
+/// Get the start of the queue
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure.  result
+/// must point to valid memory.
+pub unsafe fn basino_queue_get_queue_start(queue: *mut QueueObj, result: *mut u8) -> *const u8 {
+    queue_field(queue, result, |queue| queue.start)
+}
+
+/// Get the end of the queue
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure.  result
+/// must point to valid memory.
+pub unsafe fn basino_queue_get_queue_end(queue: *mut QueueObj, result: *mut u8) -> *const u8 {
+    queue_field(queue, result, |queue| queue.end)
+}
+
+/// Get the current head of the queue
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure.  result
+/// must point to valid memory.
+pub unsafe fn basino_queue_get_head(queue: *mut QueueObj, result: *mut u8) -> *const u8 {
+    queue_field(queue, result, |queue| queue.head)
+}
+
+/// Get the last head of the queue
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure.  result
+/// must point to valid memory.
+pub unsafe fn basino_queue_get_last_head(queue: *mut QueueObj, result: *mut u8) -> *const u8 {
+    queue_field(queue, result, |queue| queue.last_head)
+}
+
+/// Get the current tail of the queue
+///
+/// # Safety
+///
+/// queue must be null or point to a valid queue structure.  result
+/// must point to valid memory.
+pub unsafe fn basino_queue_get_tail(queue: *mut QueueObj, result: *mut u8) -> *const u8 {
+    queue_field(queue, result, |queue| queue.tail)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::String;
+
+    use super::*;
+    use crate::{
+        error::{Error, ErrorKind},
+        queue::{self, QueueImpl},
+        stack::StackImpl,
+        testing::{Format, Reporter, TestReporter},
+        ArrayHandle, Queue,
+    };
+    use core::convert::Infallible;
+    use ufmt::uWrite;
+
+    /// A uWrite that collects the output of an on-device test suite
+    struct Output(String);
+
+    impl uWrite for Output {
+        type Error = Infallible;
+
+        fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+            self.0.push_str(s);
+            Ok(())
+        }
+    }
+
+    /// Run an on-device test suite and check that nothing failed
+    fn run_suite(run_tests: fn(&mut dyn TestReporter)) {
+        let mut output = Output(String::new());
+        let mut reporter = Reporter::new(&mut output, Format::Plain);
+        run_tests(&mut reporter);
+        let failed = reporter.failed();
+
+        assert_eq!(failed, 0, "{}", output.0);
+    }
+
+    #[test]
+    fn arithmetic() {
+        unsafe {
+            assert_eq!(basino_add(1, 2), 3);
+            assert_eq!(basino_add(200, 100), 0x012C);
+            let mut carry = 0xFF;
+            assert_eq!(basino_address_add(0x1234, 0x0101, &mut carry), 0x1335);
+            assert_eq!(carry, 0);
+            assert_eq!(basino_address_add(0xFFFF, 0x0002, &mut carry), 0x0001);
+            assert_eq!(carry, 1);
+            assert_eq!(basino_gt(0x0100, 0x00FF), 1);
+            assert_eq!(basino_gt(0x1234, 0x1234), 0);
+            assert_eq!(basino_gt_eq(0x1234, 0x1234), 1);
+            assert_eq!(basino_gt_eq(0x00FF, 0x0100), 0);
+        }
+    }
+
+    #[test]
+    fn stack_push_pop() {
+        let mut array = [0u8; 4];
+        let handle = ArrayHandle::new(array.as_mut_ptr(), array.len());
+        let mut stack = Stack::new(&handle).unwrap();
+
+        for value in [1, 2, 3] {
+            assert!(stack.push(value).is_ok());
+        }
+        assert!(stack.push(4) == Err(Error::new(ErrorKind::StackOverflow)));
+        assert_eq!(stack.size(), 3);
+
+        for value in [3, 2, 1] {
+            assert!(stack.pop() == Ok(value));
+        }
+        assert!(stack.pop() == Err(Error::new(ErrorKind::StackUnderflow)));
+    }
+
+    #[test]
+    fn stack_pointers() {
+        let mut array = [0u8; 4];
+        let bottom = array.as_mut_ptr();
+        let handle = ArrayHandle::new(bottom, array.len());
+        let mut stack = Stack::new(&handle).unwrap();
+        let top = unsafe { bottom.add(3) };
+
+        assert_eq!(stack.bottom, bottom);
+        assert_eq!(stack.top, top);
+        assert_eq!(stack.top_sentinel, top);
+
+        stack.push(0xAB).unwrap();
+
+        assert_eq!(stack.top, unsafe { bottom.add(2) });
+        assert_eq!(unsafe { basino_get_basino_stack_top(&stack) }, stack.top);
+        assert_eq!(array[2], 0xAB);
+    }
+
+    #[test]
+    fn stack_invalid_arguments() {
+        let mut array = [0u8; 1];
+        let handle = ArrayHandle::new(array.as_mut_ptr(), array.len());
+
+        assert!(matches!(
+            Stack::new(&handle),
+            Err(e) if e == Error::new(ErrorKind::InvalidArguments)
+        ));
+    }
+
+    #[test]
+    fn stack_null_pointer() {
+        let mut result = 0xFF;
+        let mut array = [0u8; 4];
+        let top = unsafe { array.as_mut_ptr().add(3) };
+
+        unsafe {
+            assert_eq!(
+                basino_stack_init(core::ptr::null_mut(), top, array.as_mut_ptr()),
+                1
+            );
+            assert_eq!(basino_stack_push(core::ptr::null(), 1), 1);
+            basino_stack_pop(core::ptr::null(), &mut result);
+        }
+        assert_eq!(result, 1);
+    }
+
+    #[test]
+    fn queue_put_get() {
+        let mut array = [0u8; 4];
+        let mut queue = Queue::uninit();
+        unsafe { queue.init(array.as_mut_ptr(), array.len()) }.unwrap();
+
+        for value in [1, 2, 3] {
+            assert!(queue.put(value).is_ok());
+        }
+        assert!(
+            matches!(queue.put(4), Err(e) if e == queue::Error::new(queue::ErrorKind::QueueFull))
+        );
+
+        assert!(matches!(queue.peek(), Ok(1)));
+        for value in [1, 2, 3] {
+            assert!(matches!(queue.get(), Ok(v) if v == value));
+        }
+        assert!(
+            matches!(queue.get(), Err(e) if e == queue::Error::new(queue::ErrorKind::QueueEmpty))
+        );
+    }
+
+    #[test]
+    fn queue_pointers_wrap() {
+        let mut array = [0u8; 4];
+        let start = array.as_mut_ptr();
+        let mut queue = Queue::uninit();
+        unsafe { queue.init(start, array.len()) }.unwrap();
+        let end = unsafe { start.add(3) };
+
+        assert_eq!(queue.get_head().unwrap(), start.cast_const());
+        assert_eq!(queue.get_last_head().unwrap(), end.cast_const());
+        assert_eq!(queue.get_tail().unwrap(), start.cast_const());
+
+        for value in [1, 2, 3] {
+            queue.put(value).unwrap();
+        }
+        queue.get().unwrap();
+        queue.put(4).unwrap();
+
+        // The tail wrapped and caught up with the last head
+        assert_eq!(queue.get_tail().unwrap(), start.cast_const());
+        assert_eq!(queue.get_last_head().unwrap(), start.cast_const());
+        assert!(queue.put(5).is_err());
+
+        for value in [2, 3, 4] {
+            assert!(matches!(queue.get(), Ok(v) if v == value));
+        }
+        assert_eq!(queue.get_head().unwrap(), start.cast_const());
+        assert_eq!(queue.get_last_head().unwrap(), end.cast_const());
+    }
+
+    #[test]
+    fn queue_null_pointer() {
+        let mut result = 0xFF;
+        let mut array = [0u8; 4];
+        let end = unsafe { array.as_mut_ptr().add(3) };
+
+        unsafe {
+            assert_eq!(
+                basino_queue_init(core::ptr::null_mut(), array.as_mut_ptr(), end),
+                1
+            );
+            assert_eq!(basino_queue_put(core::ptr::null(), 1), 1);
+            basino_queue_get(core::ptr::null(), &mut result);
+            assert_eq!(result, 1);
+            result = 0xFF;
+            assert!(basino_queue_get_head(core::ptr::null_mut(), &mut result).is_null());
+        }
+        assert_eq!(result, 1);
+    }
+
+    #[test]
+    fn base_suite() {
+        run_suite(crate::tests::run_tests);
+    }
+
+    #[test]
+    fn testing_suite() {
+        run_suite(crate::testing::tests::run_tests);
+    }
+
+    #[test]
+    fn stack_suite() {
+        run_suite(crate::stack::tests::run_tests);
+    }
+
+    #[test]
+    fn queue_suite() {
+        run_suite(crate::queue::tests::run_tests);
+    }
+
+    #[test]
+    fn eeprom_suite() {
+        run_suite(crate::eeprom::tests::run_tests);
+    }
+
+    #[test]
+    fn kvstore_suite() {
+        run_suite(crate::kvstore::tests::run_tests);
+    }
+}
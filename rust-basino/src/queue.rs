@@ -105,11 +105,11 @@ pub trait QueueImpl {
     /// # Examples
     ///
     /// ```
-    /// use crate::{ArrayHandle, queue::{Queue, QueueImpl}};
+    /// use rust_basino::{queue::QueueImpl, Queue};
     ///
     /// let mut arr: [u8; 4] = [0; 4];
-    /// let queue_handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
-    /// let mut queue = Queue::new_from_array_handle(&queue_handle).unwrap();
+    /// let mut queue = Queue::uninit();
+    /// unsafe { queue.init(arr.as_mut_ptr(), arr.len()) }.unwrap();
     ///
     /// let put_res = queue.put(3);
     /// assert!(put_res.is_ok());
@@ -121,11 +121,11 @@ pub trait QueueImpl {
     /// # Examples
     ///
     /// ```
-    /// use crate::{ArrayHandle, queue::{Queue, QueueImpl}};
+    /// use rust_basino::{queue::QueueImpl, Queue};
     ///
     /// let mut arr: [u8; 4] = [0; 4];
-    /// let queue_handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
-    /// let mut queue = Queue::new_from_array_handle(&queue_handle).unwrap();
+    /// let mut queue = Queue::uninit();
+    /// unsafe { queue.init(arr.as_mut_ptr(), arr.len()) }.unwrap();
     ///
     /// queue.put(3).unwrap();
     /// let get_res = queue.get();
@@ -134,6 +134,23 @@ pub trait QueueImpl {
     /// ```
     fn get(&mut self) -> Result<u8, Error>;
 
+    /// Get the value at the head of the queue without removing it
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_basino::{queue::QueueImpl, Queue};
+    ///
+    /// let mut arr: [u8; 4] = [0; 4];
+    /// let mut queue = Queue::uninit();
+    /// unsafe { queue.init(arr.as_mut_ptr(), arr.len()) }.unwrap();
+    ///
+    /// queue.put(3).unwrap();
+    /// assert_eq!(queue.peek().expect("Should peek a value"), 3);
+    /// assert_eq!(queue.get().expect("Should get a value"), 3);
+    /// ```
+    fn peek(&mut self) -> Result<u8, Error>;
+
     // Debugging functions
 
     /// Get the start of the queue
@@ -152,10 +169,12 @@ pub trait QueueImpl {
 impl<'a> Queue<'a> {
     /// Create a new queue from an array handle
     ///
+    /// This is private, the example can't be run as a doctest.
+    ///
     /// # Examples
     ///
-    /// ```
-    /// use crate::{ArrayHandle, queue::{Queue, QueueImpl}};
+    /// ```ignore
+    /// use rust_basino::{ArrayHandle, Queue};
     ///
     /// let mut arr: [u8; 4] = [0; 4];
     /// let queue_handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
@@ -201,6 +220,49 @@ impl<'a> Queue<'a> {
             _ => Err(Error::new(ErrorKind::Unknown)),
         }
     }
+
+    /// Create an uninitialized queue
+    ///
+    /// This is for queues that live in statics, for example queues
+    /// shared with an interrupt handler.  The queue must be
+    /// initialized with init before it's used.
+    pub const fn uninit() -> Queue<'a> {
+        Self {
+            queue: QueueObj {
+                queue: core::ptr::null_mut::<u8>(),
+                start: core::ptr::null_mut::<u8>(),
+                end: core::ptr::null_mut::<u8>(),
+                head: core::ptr::null_mut::<u8>(),
+                last_head: core::ptr::null_mut::<u8>(),
+                tail: core::ptr::null_mut::<u8>(),
+                _marker: PhantomData,
+            },
+            queue_len: 0,
+        }
+    }
+
+    /// Initialize a queue in place
+    ///
+    /// start points to the queue array and len is its length.
+    ///
+    /// # Safety
+    ///
+    /// start must point to an array of length len that lives as long
+    /// as the queue.
+    pub unsafe fn init(&mut self, start: *mut u8, len: usize) -> Result<(), Error> {
+        let end = (start as usize + len - 1) as *mut u8;
+
+        self.queue_len = len;
+
+        let res = basino_queue_init(core::ptr::addr_of_mut!(self.queue), start, end);
+
+        match res {
+            0 => Ok(()),
+            1 => Err(Error::new(ErrorKind::NullPointer)),
+            2 => Err(Error::new(ErrorKind::InvalidArguments)),
+            _ => Err(Error::new(ErrorKind::Unknown)),
+        }
+    }
 }
 
 impl<'a> QueueImpl for Queue<'a> {
@@ -228,6 +290,19 @@ impl<'a> QueueImpl for Queue<'a> {
         }
     }
 
+    fn peek(&mut self) -> Result<u8, Error> {
+        let head = self.get_head()?;
+        let tail = self.get_tail()?;
+
+        // The head always points into the queue, get wraps it after
+        // reading the last element
+        if head == tail {
+            Err(Error::new(ErrorKind::QueueEmpty))
+        } else {
+            Ok(unsafe { *head })
+        }
+    }
+
     // Debugging functions
 
     fn get_start(&mut self) -> Result<*const u8, Error> {
@@ -309,6 +384,9 @@ impl<'a> QueueImpl for Queue<'a> {
 /// A tests module
 /// This doesn't use the standard Rust testing framework.  Instead it's a normal
 /// public module that can be called by other systems.
+///
+/// On AVR the tests use the buffers in lib.rs, other targets use an
+/// array of the same size on the stack.
 pub mod tests {
     use crate::{
         queue::{
@@ -316,100 +394,157 @@ pub mod tests {
             basino_queue_get_queue_end, basino_queue_get_queue_start, basino_queue_get_tail,
             basino_queue_init, basino_queue_put, ErrorKind, Queue, QueueImpl, QueueObj,
         },
-        tests::write_test_result,
-        ArrayHandle, BASINO_QUEUE_DATA,
-    };
-
-    use arduino_hal::{
-        hal::port::{PD0, PD1},
-        pac::USART0,
-        port::{
-            mode::{Input, Output},
-            Pin,
+        testing::{
+            report_mismatch, run_random_sequences, run_suite, BufferWriter, Mismatch, RandomOp,
+            Rng, TestCase, TestReporter, RANDOM_OPS,
         },
-        Usart,
+        tests::write_test_result,
+        ArrayHandle,
     };
 
+    #[cfg(target_arch = "avr")]
+    use crate::BASINO_QUEUE_DATA;
+    #[cfg(target_arch = "avr")]
     use avr_device::interrupt::free;
 
+    /// Run f with the queue buffer
+    ///
+    /// On AVR this is BASINO_QUEUE_DATA in lib.rs, borrowed in a
+    /// critical section.
+    #[cfg(target_arch = "avr")]
+    fn with_queue_buffer<R>(f: impl FnOnce(&mut [u8]) -> R) -> R {
+        free(|cs| f(&mut BASINO_QUEUE_DATA.borrow(cs).borrow_mut()[..]))
+    }
+
+    /// Run f with the queue buffer
+    ///
+    /// Other targets don't have BASINO_QUEUE_DATA, this uses an
+    /// array of the same size.
+    #[cfg(not(target_arch = "avr"))]
+    fn with_queue_buffer<R>(f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let mut buffer = [0u8; 4];
+        f(&mut buffer)
+    }
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("init_works", test_queue_init_works),
+        TestCase::new("empty_get_fails", test_queue_empty_get_fails),
+        TestCase::new("put_works", test_queue_put_works),
+        TestCase::new("put_twice_works", test_queue_put_twice_works),
+        TestCase::new("put_fill_works", test_queue_put_fill_works),
+        TestCase::new("put_and_get_fill_works", test_queue_put_and_get_fill_works),
+        TestCase::new("head_wraps_works", test_queue_head_wraps_works),
+        TestCase::new(
+            "head_wraps_nonfilled_works",
+            test_queue_head_wraps_nonfilled_works,
+        ),
+        TestCase::new(
+            "head_wraps_nonemptied_works",
+            test_queue_head_wraps_nonemptied_works,
+        ),
+        TestCase::new("last_head_update", test_queue_last_head_update),
+        TestCase::new("init_null_queue_fails", test_queue_init_null_queue_fails),
+        TestCase::new(
+            "basino_queue_put_null_queue_fails",
+            test_queue_basino_queue_put_null_queue_fails,
+        ),
+        TestCase::new(
+            "basino_queue_get_null_queue_fails",
+            test_queue_basino_queue_get_null_queue_fails,
+        ),
+        TestCase::new(
+            "basino_queue_get_last_head_null_queue_fails",
+            test_queue_basino_queue_get_last_head_null_queue_fails,
+        ),
+        TestCase::new(
+            "basino_queue_get_head_null_queue_fails",
+            test_queue_basino_queue_get_head_null_queue_fails,
+        ),
+        TestCase::new(
+            "basino_queue_get_tail_null_queue_fails",
+            test_queue_basino_queue_get_tail_null_queue_fails,
+        ),
+        TestCase::new(
+            "basino_queue_get_queue_start_null_queue_fails",
+            test_queue_basino_queue_get_queue_start_null_queue_fails,
+        ),
+        TestCase::new(
+            "basino_queue_get_queue_end_null_queue_fails",
+            test_queue_basino_queue_get_queue_end_null_queue_fails,
+        ),
+        TestCase::new(
+            "basino_queue_get_last_head_works",
+            test_queue_basino_queue_get_last_head_works,
+        ),
+        TestCase::new(
+            "basino_queue_get_head_works",
+            test_queue_basino_queue_get_head_works,
+        ),
+        TestCase::new(
+            "basino_queue_get_tail_works",
+            test_queue_basino_queue_get_tail_works,
+        ),
+        TestCase::new(
+            "basino_queue_get_queue_start_works",
+            test_queue_basino_queue_get_queue_start_works,
+        ),
+        TestCase::new(
+            "basino_queue_get_queue_end_works",
+            test_queue_basino_queue_get_queue_end_works,
+        ),
+        TestCase::new("peek_works", test_queue_peek_works),
+        TestCase::new("random_matches_model", test_queue_random_matches_model),
+    ];
+
     /// Run all the tests in this module
-    pub fn run_tests(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
-        test_queue_init_works(writer);
-        test_queue_empty_get_fails(writer);
-        test_queue_put_works(writer);
-        test_queue_put_twice_works(writer);
-        test_queue_put_fill_works(writer);
-        test_queue_put_and_get_fill_works(writer);
-        test_queue_head_wraps_works(writer);
-        test_queue_head_wraps_nonfilled_works(writer);
-        test_queue_head_wraps_nonemptied_works(writer);
-        test_queue_last_head_update(writer);
-        test_queue_init_null_queue_fails(writer);
-        test_queue_basino_queue_put_null_queue_fails(writer);
-        test_queue_basino_queue_get_null_queue_fails(writer);
-        test_queue_basino_queue_get_last_head_null_queue_fails(writer);
-        test_queue_basino_queue_get_head_null_queue_fails(writer);
-        test_queue_basino_queue_get_tail_null_queue_fails(writer);
-        test_queue_basino_queue_get_queue_start_null_queue_fails(writer);
-        test_queue_basino_queue_get_queue_end_null_queue_fails(writer);
-        test_queue_basino_queue_get_last_head_works(writer);
-        test_queue_basino_queue_get_head_works(writer);
-        test_queue_basino_queue_get_tail_works(writer);
-        test_queue_basino_queue_get_queue_start_works(writer);
-        test_queue_basino_queue_get_queue_end_works(writer);
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "queue", TEST_CASES);
     }
 
     /// Test that initializing the queue works
-    pub fn test_queue_init_works(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_init_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let res = Queue::new(&ah);
 
-            write_test_result(writer, res.is_ok(), "should initialize queue");
+            write_test_result(reporter, res.is_ok(), "should initialize queue");
         });
     }
 
     /// Test that getting from an empty queue fails
-    pub fn test_queue_empty_get_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_empty_get_fails(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
 
             let res = queue.get();
-            write_test_result(writer, res.is_err(), "get from empty queue should fail");
+            write_test_result(reporter, res.is_err(), "get from empty queue should fail");
         });
     }
 
     /// Test that putting an item into the queue works
-    pub fn test_queue_put_works(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_put_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
 
             let res = queue.put(5);
 
-            write_test_result(writer, res.is_ok(), "should put 5 into queue");
+            write_test_result(reporter, res.is_ok(), "should put 5 into queue");
 
             let res = queue.get();
 
             match res {
                 Ok(v) => {
-                    write_test_result(writer, true, "get should be ok");
-                    write_test_result(writer, v == 5, "get should return 5");
+                    write_test_result(reporter, true, "get should be ok");
+                    write_test_result(reporter, v == 5, "get should return 5");
                 }
                 Err(_e) => {
-                    write_test_result(writer, false, "get should be ok and return 5");
+                    write_test_result(reporter, false, "get should be ok and return 5");
                 }
             }
         });
@@ -417,12 +552,8 @@ pub mod tests {
 
     /// Test that putting two items and getting two items from the queue works
     /// This puts both items first, then gets both items.
-    pub fn test_queue_put_twice_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_put_twice_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
@@ -431,10 +562,10 @@ pub mod tests {
 
             let res = queue.put(5);
 
-            write_test_result(writer, res.is_ok(), "should put 5 into queue");
+            write_test_result(reporter, res.is_ok(), "should put 5 into queue");
             let res = queue.put(3);
 
-            write_test_result(writer, res.is_ok(), "should put 3 into queue");
+            write_test_result(reporter, res.is_ok(), "should put 3 into queue");
 
             // Now get both items
 
@@ -442,65 +573,57 @@ pub mod tests {
 
             match res {
                 Ok(v) => {
-                    write_test_result(writer, v == 5, "get should return 5");
+                    write_test_result(reporter, v == 5, "get should return 5");
                 }
                 Err(_e) => {
-                    write_test_result(writer, false, "get should be ok and return 5");
+                    write_test_result(reporter, false, "get should be ok and return 5");
                 }
             }
             let res = queue.get();
 
             match res {
                 Ok(v) => {
-                    write_test_result(writer, v == 3, "get should return 3");
+                    write_test_result(reporter, v == 3, "get should return 3");
                 }
                 Err(_e) => {
-                    write_test_result(writer, false, "get should be ok and return 3");
+                    write_test_result(reporter, false, "get should be ok and return 3");
                 }
             }
         });
     }
 
     /// Test that filling the queue works
-    pub fn test_queue_put_fill_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_put_fill_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
 
             for i in 1..queue.queue_len {
                 let res = queue.put((i % 256) as u8);
-                write_test_result(writer, res.is_ok(), "should be able to fill queue");
+                write_test_result(reporter, res.is_ok(), "should be able to fill queue");
             }
 
             let res = queue.put(130_u8);
-            write_test_result(writer, res.is_err(), "last put to full queue should fail");
+            write_test_result(reporter, res.is_err(), "last put to full queue should fail");
             match res {
                 Err(e) => {
                     write_test_result(
-                        writer,
+                        reporter,
                         e.kind == ErrorKind::QueueFull,
                         "last put should fail with QueueFull error",
                     );
                 }
                 _ => {
-                    write_test_result(writer, false, "last put should fail with QueueFull error");
+                    write_test_result(reporter, false, "last put should fail with QueueFull error");
                 }
             }
         });
     }
 
     /// Test that filling the queue and getting all the values works
-    pub fn test_queue_put_and_get_fill_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_put_and_get_fill_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
@@ -512,7 +635,7 @@ pub mod tests {
             for i in 1..queue.queue_len {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == ((i % 256) as u8),
                     "should get filled value",
                 );
@@ -522,40 +645,36 @@ pub mod tests {
 
     /// Test that putting a value, getting it, and then filling the queue works
     /// This tests for the case where we move the head and tail
-    pub fn test_queue_put_get_put_fill_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_put_get_put_fill_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
 
             let res = queue.put(0x23_u8);
-            write_test_result(writer, res.is_ok(), "single put of 0x23 should work");
+            write_test_result(reporter, res.is_ok(), "single put of 0x23 should work");
 
             let res = queue.get();
-            write_test_result(writer, res.is_ok(), "single get should work");
-            write_test_result(writer, res.unwrap() == 0x23, "single get should equal 0x23");
+            write_test_result(reporter, res.is_ok(), "single get should work");
+            write_test_result(
+                reporter,
+                res.unwrap() == 0x23,
+                "single get should equal 0x23",
+            );
 
             for i in 1..queue.queue_len {
                 let res = queue.put((i % 256) as u8);
-                write_test_result(writer, res.is_ok(), "should be able to fill queue");
+                write_test_result(reporter, res.is_ok(), "should be able to fill queue");
             }
 
             let res = queue.put(130_u8);
-            write_test_result(writer, res.is_err(), "last put to full queue should fail");
+            write_test_result(reporter, res.is_err(), "last put to full queue should fail");
         });
     }
 
     /// Test a case where the head wraps around
-    pub fn test_queue_head_wraps_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_head_wraps_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
@@ -565,13 +684,13 @@ pub mod tests {
             // So, if queue.queue_len is 4, this iterates through [1, 2, 3]
             for i in 1..queue.queue_len {
                 let res = queue.put((i % 256) as u8);
-                write_test_result(writer, res.is_ok(), "should be able to fill queue");
+                write_test_result(reporter, res.is_ok(), "should be able to fill queue");
             }
 
             for i in 1..queue.queue_len {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == ((i % 256) as u8),
                     "should be able to get filled values ",
                 );
@@ -580,7 +699,7 @@ pub mod tests {
             for i in 1..=2 {
                 let res = queue.put(i as u8);
                 write_test_result(
-                    writer,
+                    reporter,
                     res.is_ok(),
                     "should be able to put in 2 more values",
                 );
@@ -589,7 +708,7 @@ pub mod tests {
             for i in 1..=2 {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == (i as u8),
                     "should be able to get values",
                 );
@@ -600,12 +719,8 @@ pub mod tests {
     /// Test a case where the head wraps around
     /// This tests a case where we don't fill the queue all the way,
     /// then read those values, then try to wrap
-    pub fn test_queue_head_wraps_nonfilled_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_head_wraps_nonfilled_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
@@ -614,13 +729,13 @@ pub mod tests {
             // So, if queue.queue_len is 4, this iterates through [1, 2, 3]
             for i in 1..=2 {
                 let res = queue.put((i % 256) as u8);
-                write_test_result(writer, res.is_ok(), "should be able to fill queue");
+                write_test_result(reporter, res.is_ok(), "should be able to fill queue");
             }
 
             for i in 1..=2 {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == ((i % 256) as u8),
                     "should be able to get filled values ",
                 );
@@ -629,7 +744,7 @@ pub mod tests {
             for i in 1..=2 {
                 let res = queue.put(i as u8);
                 write_test_result(
-                    writer,
+                    reporter,
                     res.is_ok(),
                     "should be able to put in 2 more values",
                 );
@@ -638,7 +753,7 @@ pub mod tests {
             for i in 1..=2 {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == (i as u8),
                     "should be able to get values",
                 );
@@ -646,18 +761,14 @@ pub mod tests {
 
             // The queue should now be empty
             let res = queue.get();
-            write_test_result(writer, res.is_err(), "get from empty queue should fail");
+            write_test_result(reporter, res.is_err(), "get from empty queue should fail");
         });
     }
 
     /// Test where we wrap the tail and head with gets in between filling the queue
     /// Don't empty the queue all the way when getting values before the wrap
-    pub fn test_queue_head_wraps_nonemptied_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_head_wraps_nonemptied_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
@@ -665,13 +776,13 @@ pub mod tests {
             // put in two items [1, 2]
             for i in [1, 2] {
                 let res = queue.put((i % 256) as u8);
-                write_test_result(writer, res.is_ok(), "should be able to put in two items");
+                write_test_result(reporter, res.is_ok(), "should be able to put in two items");
             }
 
             // Remove one item
             let res = queue.get();
             write_test_result(
-                writer,
+                reporter,
                 res.unwrap() == 1_u8,
                 "should be able to get one item ",
             );
@@ -680,7 +791,7 @@ pub mod tests {
             for i in [3, 4] {
                 let res = queue.put(i as u8);
                 write_test_result(
-                    writer,
+                    reporter,
                     res.is_ok(),
                     "should be able to put in 2 more values",
                 );
@@ -690,7 +801,7 @@ pub mod tests {
             for i in [2, 3, 4] {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == (i as u8),
                     "should be able to get all values",
                 );
@@ -698,18 +809,14 @@ pub mod tests {
 
             // The queue should now be empty
             let res = queue.get();
-            write_test_result(writer, res.is_err(), "get from empty queue should fail");
+            write_test_result(reporter, res.is_err(), "get from empty queue should fail");
         });
     }
 
     /// Test a case where the last head wasn't being updated in the
     /// end-of-queue code path
-    pub fn test_queue_last_head_update(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_last_head_update(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let mut queue = Queue::new(&ah).unwrap();
@@ -718,13 +825,13 @@ pub mod tests {
             // So, if queue.queue_len is 4, this iterates through [1, 2, 3]
             for i in 1..=2 {
                 let res = queue.put((i % 256) as u8);
-                write_test_result(writer, res.is_ok(), "should be able to fill queue");
+                write_test_result(reporter, res.is_ok(), "should be able to fill queue");
             }
 
             for i in 1..=2 {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == ((i % 256) as u8),
                     "should be able to get filled values ",
                 );
@@ -733,7 +840,7 @@ pub mod tests {
             for i in 1..=2 {
                 let res = queue.put(i as u8);
                 write_test_result(
-                    writer,
+                    reporter,
                     res.is_ok(),
                     "should be able to put in 2 more values",
                 );
@@ -742,7 +849,7 @@ pub mod tests {
             for i in 1..=2 {
                 let res = queue.get();
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == (i as u8),
                     "should be able to get values",
                 );
@@ -750,12 +857,12 @@ pub mod tests {
 
             // The queue should now be empty
             let res = queue.get();
-            write_test_result(writer, res.is_err(), "get from empty queue should fail");
+            write_test_result(reporter, res.is_err(), "get from empty queue should fail");
 
             for i in 1..=3 {
                 let res = queue.put(i as u8);
                 write_test_result(
-                    writer,
+                    reporter,
                     res.is_ok(),
                     "should be able to put in 3 more values",
                 );
@@ -764,12 +871,8 @@ pub mod tests {
     }
 
     /// Test that init with a NULL queue pointer fails
-    pub fn test_queue_init_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_init_null_queue_fails(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let queue_start_ptr = ah.ptr;
@@ -784,15 +887,17 @@ pub mod tests {
                 )
             };
 
-            write_test_result(writer, res == 1, "init should fail with null queue pointer");
+            write_test_result(
+                reporter,
+                res == 1,
+                "init should fail with null queue pointer",
+            );
         });
     }
 
     /// Test that get with a NULL queue pointer fails
     /// Tests the raw error code
-    pub fn test_queue_basino_queue_get_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_queue_basino_queue_get_null_queue_fails(reporter: &mut dyn TestReporter) {
         let mut result: u8 = 0;
         let _res = unsafe {
             basino_queue_get(
@@ -802,7 +907,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             result == 1,
             "get should fail with null queue pointer",
         );
@@ -810,19 +915,19 @@ pub mod tests {
 
     /// Test that put with a NULL queue pointer fails
     /// Tests the raw error code
-    pub fn test_queue_basino_queue_put_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_queue_basino_queue_put_null_queue_fails(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_queue_put(core::ptr::null_mut::<u16>() as *mut QueueObj, 5) };
 
-        write_test_result(writer, res == 1, "put should fail with null queue pointer");
+        write_test_result(
+            reporter,
+            res == 1,
+            "put should fail with null queue pointer",
+        );
     }
 
     /// Test that put with a NULL queue pointer fails
     /// Tests the raw error code
-    pub fn test_queue_basino_queue_get_last_head_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_queue_basino_queue_get_last_head_null_queue_fails(reporter: &mut dyn TestReporter) {
         let mut result: u8 = 0;
         let _res = unsafe {
             basino_queue_get_last_head(
@@ -832,7 +937,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             result == 1,
             "put should fail with null queue pointer",
         );
@@ -840,9 +945,7 @@ pub mod tests {
 
     /// Test that put with a NULL queue pointer fails
     /// Tests the raw error code
-    pub fn test_queue_basino_queue_get_head_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_queue_basino_queue_get_head_null_queue_fails(reporter: &mut dyn TestReporter) {
         let mut result: u8 = 0;
         let _res = unsafe {
             basino_queue_get_head(
@@ -852,7 +955,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             result == 1,
             "put should fail with null queue pointer",
         );
@@ -860,9 +963,7 @@ pub mod tests {
 
     /// Test that put with a NULL queue pointer fails
     /// Tests the raw error code
-    pub fn test_queue_basino_queue_get_tail_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_queue_basino_queue_get_tail_null_queue_fails(reporter: &mut dyn TestReporter) {
         let mut result: u8 = 0;
         let _res = unsafe {
             basino_queue_get_tail(
@@ -872,7 +973,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             result == 1,
             "put should fail with null queue pointer",
         );
@@ -881,7 +982,7 @@ pub mod tests {
     /// Test that put with a NULL queue pointer fails
     /// Tests the raw error code
     pub fn test_queue_basino_queue_get_queue_start_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
+        reporter: &mut dyn TestReporter,
     ) {
         let mut result: u8 = 0;
         let _res = unsafe {
@@ -892,7 +993,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             result == 1,
             "put should fail with null queue pointer",
         );
@@ -900,9 +1001,7 @@ pub mod tests {
 
     /// Test that put with a NULL queue pointer fails
     /// Tests the raw error code
-    pub fn test_queue_basino_queue_get_queue_end_null_queue_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_queue_basino_queue_get_queue_end_null_queue_fails(reporter: &mut dyn TestReporter) {
         let mut result: u8 = 0;
         let _res = unsafe {
             basino_queue_get_queue_end(
@@ -912,7 +1011,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             result == 1,
             "put should fail with null queue pointer",
         );
@@ -921,12 +1020,8 @@ pub mod tests {
     // Test the debugging functions
 
     /// Test that get_last_head works
-    pub fn test_queue_basino_queue_get_last_head_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_basino_queue_get_last_head_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let queue_start = ah.ptr;
@@ -936,7 +1031,7 @@ pub mod tests {
             let last_head = queue.get_last_head();
 
             write_test_result(
-                writer,
+                reporter,
                 last_head.unwrap() == queue_end,
                 "get_last_head should return correct value",
             );
@@ -944,12 +1039,8 @@ pub mod tests {
     }
 
     /// Test that get_last_head works
-    pub fn test_queue_basino_queue_get_head_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_basino_queue_get_head_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let queue_start = ah.ptr;
@@ -958,7 +1049,7 @@ pub mod tests {
             let head = queue.get_head();
 
             write_test_result(
-                writer,
+                reporter,
                 head.unwrap() == queue_start,
                 "get_head should return correct value",
             );
@@ -966,12 +1057,8 @@ pub mod tests {
     }
 
     /// Test that get_last_head works
-    pub fn test_queue_basino_queue_get_tail_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_basino_queue_get_tail_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let queue_start = ah.ptr;
@@ -980,7 +1067,7 @@ pub mod tests {
             let tail = queue.get_tail();
 
             write_test_result(
-                writer,
+                reporter,
                 tail.unwrap() == queue_start,
                 "get_tail should return correct value",
             );
@@ -988,12 +1075,8 @@ pub mod tests {
     }
 
     /// Test that get_last_head works
-    pub fn test_queue_basino_queue_get_queue_start_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_basino_queue_get_queue_start_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let queue_start = ah.ptr;
@@ -1002,7 +1085,7 @@ pub mod tests {
             let res = queue.get_start();
 
             write_test_result(
-                writer,
+                reporter,
                 res.unwrap() == queue_start,
                 "get_start should return correct value",
             );
@@ -1010,12 +1093,8 @@ pub mod tests {
     }
 
     /// Test that get_queue_end works
-    pub fn test_queue_basino_queue_get_queue_end_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut queue_handle = BASINO_QUEUE_DATA.borrow(cs).borrow_mut();
-
+    pub fn test_queue_basino_queue_get_queue_end_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
             let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
 
             let queue_start = ah.ptr;
@@ -1025,10 +1104,242 @@ pub mod tests {
             let res = queue.get_end();
 
             write_test_result(
-                writer,
+                reporter,
                 res.unwrap() == queue_end,
                 "get_end should return correct value",
             );
         });
     }
+
+    /// Test that peek returns the head without removing it
+    pub fn test_queue_peek_works(reporter: &mut dyn TestReporter) {
+        with_queue_buffer(|queue_handle| {
+            let ah = ArrayHandle::new(queue_handle.as_mut_ptr(), queue_handle.len());
+            let mut queue = Queue::new(&ah).unwrap();
+
+            match queue.peek() {
+                Err(e) => write_test_result(
+                    reporter,
+                    e.kind == ErrorKind::QueueEmpty,
+                    "peek on an empty queue should fail with QueueEmpty",
+                ),
+                Ok(_) => write_test_result(reporter, false, "peek on an empty queue should fail"),
+            }
+
+            queue.put(7).unwrap();
+            queue.put(9).unwrap();
+
+            write_test_result(reporter, queue.peek() == Ok(7), "peek should return 7");
+            write_test_result(reporter, queue.peek() == Ok(7), "peek should not remove 7");
+            write_test_result(reporter, queue.get() == Ok(7), "get should return 7");
+            write_test_result(reporter, queue.peek() == Ok(9), "peek should return 9");
+        });
+    }
+
+    /// The largest queue array a randomized sequence uses
+    const RANDOM_MAX_LEN: usize = 8;
+
+    /// An operation in a randomized sequence
+    #[derive(Clone, Copy)]
+    enum Op {
+        Put(u8),
+        Get,
+        Peek,
+    }
+
+    impl RandomOp for Op {
+        fn write(&self, line: &mut BufferWriter) {
+            let _ = match *self {
+                Op::Put(value) => ufmt::uwrite!(line, "put {}", value),
+                Op::Get => ufmt::uwrite!(line, "get"),
+                Op::Peek => ufmt::uwrite!(line, "peek"),
+            };
+        }
+    }
+
+    /// A reference ring buffer with the same semantics as queue.S
+    ///
+    /// An array of length len holds len - 1 items, the queue is full
+    /// when the tail is just behind the head.  last_head is always the
+    /// slot before the head.
+    struct Model {
+        data: [u8; RANDOM_MAX_LEN],
+        len: usize,
+        head: usize,
+        tail: usize,
+    }
+
+    impl Model {
+        fn new(len: usize) -> Self {
+            Model {
+                data: [0; RANDOM_MAX_LEN],
+                len,
+                head: 0,
+                tail: 0,
+            }
+        }
+
+        fn put(&mut self, value: u8) -> Result<(), ErrorKind> {
+            if (self.tail + 1) % self.len == self.head {
+                return Err(ErrorKind::QueueFull);
+            }
+            self.data[self.tail] = value;
+            self.tail = (self.tail + 1) % self.len;
+            Ok(())
+        }
+
+        fn peek(&self) -> Result<u8, ErrorKind> {
+            if self.head == self.tail {
+                Err(ErrorKind::QueueEmpty)
+            } else {
+                Ok(self.data[self.head])
+            }
+        }
+
+        fn get(&mut self) -> Result<u8, ErrorKind> {
+            let value = self.peek()?;
+            self.head = (self.head + 1) % self.len;
+            Ok(value)
+        }
+
+        fn last_head(&self) -> usize {
+            (self.head + self.len - 1) % self.len
+        }
+    }
+
+    /// Compare a queue result with a model result
+    fn same<T: PartialEq>(
+        queue: Result<T, crate::queue::Error>,
+        model: Result<T, ErrorKind>,
+    ) -> bool {
+        match (queue, model) {
+            (Ok(a), Ok(b)) => a == b,
+            (Err(e), Err(kind)) => e.kind == kind,
+            _ => false,
+        }
+    }
+
+    /// Run a sequence on a queue of length len and on the model
+    ///
+    /// Returns the first difference in a result or a pointer.
+    fn check_sequence(len: usize, ops: &[Op]) -> Option<Mismatch> {
+        let mut buffer = [0u8; RANDOM_MAX_LEN];
+        let start = buffer.as_mut_ptr();
+        let handle = ArrayHandle::new(start, len);
+        let mut queue = match Queue::new(&handle) {
+            Ok(queue) => queue,
+            Err(_) => {
+                return Some(Mismatch {
+                    step: 0,
+                    what: "init failed",
+                })
+            }
+        };
+        let mut model = Model::new(len);
+        let at = |index: usize| (start as usize + index) as *const u8;
+
+        for (step, op) in ops.iter().enumerate() {
+            let ok = match *op {
+                Op::Put(value) => same(queue.put(value), model.put(value)),
+                Op::Get => same(queue.get(), model.get()),
+                Op::Peek => same(queue.peek(), model.peek()),
+            };
+            let what = if !ok {
+                "result differs"
+            } else if queue.get_head() != Ok(at(model.head)) {
+                "head differs"
+            } else if queue.get_tail() != Ok(at(model.tail)) {
+                "tail differs"
+            } else if queue.get_last_head() != Ok(at(model.last_head())) {
+                "last_head differs"
+            } else {
+                continue;
+            };
+
+            return Some(Mismatch { step, what });
+        }
+
+        None
+    }
+
+    /// Generate the queue length and operations for a seed
+    fn generate(seed: u32, ops: &mut [Op; RANDOM_OPS]) -> usize {
+        let mut rng = Rng::new(seed);
+        let len = 1 + rng.below(RANDOM_MAX_LEN as u32) as usize;
+        // Vary the mix so some sequences fill the queue and some
+        // keep it empty
+        let put_percent = rng.below(100);
+
+        for op in ops.iter_mut() {
+            let n = rng.below(100);
+            *op = if n < put_percent {
+                Op::Put(rng.next_u32() as u8)
+            } else if n.is_multiple_of(4) {
+                Op::Peek
+            } else {
+                Op::Get
+            };
+        }
+
+        len
+    }
+
+    /// Shrink a failing sequence
+    ///
+    /// Tries shorter queues, then drops operations one at a time,
+    /// keeping each change that still fails.  Returns the new queue
+    /// length and number of operations.
+    fn shrink(mut len: usize, ops: &mut [Op; RANDOM_OPS], mut count: usize) -> (usize, usize) {
+        while len > 1 && check_sequence(len - 1, &ops[..count]).is_some() {
+            len -= 1;
+        }
+
+        let mut i = 0;
+        while i < count {
+            let mut candidate = *ops;
+            candidate.copy_within(i + 1..count, i);
+            if check_sequence(len, &candidate[..count - 1]).is_some() {
+                *ops = candidate;
+                count -= 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        (len, count)
+    }
+
+    /// Run one seeded sequence, report it and return false if it fails
+    pub fn run_random_sequence(reporter: &mut dyn TestReporter, seed: u32) -> bool {
+        let mut ops = [Op::Get; RANDOM_OPS];
+        let len = generate(seed, &mut ops);
+
+        if check_sequence(len, &ops).is_none() {
+            return true;
+        }
+
+        let (len, count) = shrink(len, &mut ops, RANDOM_OPS);
+        if let Some(mismatch) = check_sequence(len, &ops[..count]) {
+            report_mismatch(reporter, "queue", seed, len, &ops[..count], &mismatch);
+        }
+
+        false
+    }
+
+    /// Test seeded random sequences of put, get and peek against a
+    /// reference model
+    ///
+    /// Every result and the head, tail and last_head pointers are
+    /// compared after each operation.  The first failing sequence is
+    /// shrunk and printed with its seed, run_random_sequence replays
+    /// it.
+    pub fn test_queue_random_matches_model(reporter: &mut dyn TestReporter) {
+        let ok = run_random_sequences(reporter, run_random_sequence);
+
+        write_test_result(
+            reporter,
+            ok,
+            "random queue sequences should match the reference model",
+        );
+    }
 }
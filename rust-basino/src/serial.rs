@@ -0,0 +1,797 @@
+//! Interrupt driven serial input and output
+//!
+//! The arduino_hal Usart reads one byte at a time when it's asked to.
+//! Bytes that arrive while the program is busy are lost after the
+//! two byte hardware buffer fills.  This module reads USART0 from the
+//! RX complete interrupt instead and puts the bytes into a basino
+//! queue.  The main program is the only consumer.
+//!
+//! The queue operations aren't reentrant.  The interrupt handler is
+//! the only producer and runs with interrupts disabled, the consumer
+//! functions take bytes off the queue inside interrupt::free, so the
+//! two never run at the same time.
+//!
+//! Bytes that can't be stored are counted as overruns.  That includes
+//! bytes dropped because the queue is full and bytes the hardware
+//! dropped before the interrupt ran (the DOR0 flag).
+//!
+//! Ctrl-C (0x03) isn't put into the queue.  It sets a break flag that
+//! the interpreter checks between statements, so a runaway program
+//! can be stopped even when it never reads input.
+//!
+//! Output works the same way in the other direction.  SerialWriter
+//! puts bytes into a transmit queue and the data register empty
+//! interrupt sends them, so printing only waits when the queue is
+//! full.  Once transmitting is started, output should go through
+//! SerialWriter, or flush should be called before writing to the
+//! Usart directly, otherwise the output is interleaved.
+//!
+//! The Usart still has to be set up with arduino_hal::default_serial,
+//! this module only takes over the interrupts.
+#![warn(missing_docs)]
+
+use crate::{
+    basino_sleep_idle,
+    hardware::{read_reg, update_reg, write_reg},
+    queue::QueueImpl,
+    tick, Queue,
+};
+
+use avr_device::interrupt::{free, Mutex};
+use core::{
+    cell::Cell,
+    convert::Infallible,
+    fmt::{Debug, Display, Formatter},
+};
+use ufmt::{uDebug, uWrite};
+
+/// The kinds of errors that can occur working with the serial port
+#[derive(Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The receive queue couldn't be initialized
+    QueueError,
+    /// An unknown error type
+    Unknown,
+}
+
+impl uDebug for ErrorKind {
+    fn fmt<T>(&self, f: &mut ufmt::Formatter<'_, T>) -> core::result::Result<(), T::Error>
+    where
+        T: uWrite + ?Sized,
+    {
+        match self {
+            ErrorKind::QueueError => f.write_str("The serial queue couldn't be initialized"),
+            ErrorKind::Unknown => f.write_str("An unknown error occurred"),
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            ErrorKind::QueueError => write!(f, "The serial queue couldn't be initialized"),
+            ErrorKind::Unknown => write!(f, "An unknown error occurred"),
+        }
+    }
+}
+
+/// An error that can occur when working with the serial port
+#[derive(PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// The length of the receive queue array
+pub const RX_QUEUE_LEN: usize = 32;
+
+/// The length of the transmit queue array
+pub const TX_QUEUE_LEN: usize = 32;
+
+// USART0 register addresses
+const UCSR0A: usize = 0xC0;
+const UCSR0B: usize = 0xC1;
+const UDR0: usize = 0xC6;
+
+const SREG: usize = 0x5F;
+
+// UCSR0A bits
+const UDRE0: u8 = 5;
+const DOR0: u8 = 3;
+// UCSR0B bits
+const RXCIE0: u8 = 7;
+const UDRIE0: u8 = 5;
+// SREG bits
+const SREG_I: u8 = 7;
+
+/// The array the receive queue stores bytes in
+#[link_section = ".ram2bss"]
+pub(crate) static mut BASINO_RX_QUEUE_DATA: [u8; RX_QUEUE_LEN] = [0; RX_QUEUE_LEN];
+
+/// The receive queue shared between the RX complete interrupt and the
+/// main program.
+///
+/// This can't be in a Mutex, the queue holds raw pointers.  It's
+/// only accessed inside interrupt::free or from an interrupt handler.
+#[link_section = ".ram2bss"]
+pub(crate) static mut BASINO_RX_QUEUE: Queue<'static> = Queue::uninit();
+
+/// The array the transmit queue stores bytes in
+#[link_section = ".ram2bss"]
+pub(crate) static mut BASINO_TX_QUEUE_DATA: [u8; TX_QUEUE_LEN] = [0; TX_QUEUE_LEN];
+
+/// The transmit queue shared between the main program and the data
+/// register empty interrupt.
+///
+/// This can't be in a Mutex, the queue holds raw pointers.  It's
+/// only accessed inside interrupt::free or from an interrupt handler.
+#[link_section = ".ram2bss"]
+pub(crate) static mut BASINO_TX_QUEUE: Queue<'static> = Queue::uninit();
+
+/// The number of bytes waiting in the transmit queue
+static TX_PENDING: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// The number of bytes waiting in the receive queue
+static RX_PENDING: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// The number of received bytes that were dropped
+static RX_OVERRUNS: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+/// Set when Ctrl-C is received
+static BREAK_FLAG: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// The byte Ctrl-C sends
+pub const BREAK_KEY: u8 = 0x03;
+
+/// Start interrupt driven receiving
+///
+/// This clears the receive queue, the overrun count and the break
+/// flag and enables the RX complete interrupt.  Interrupts have to be
+/// enabled to receive anything.
+pub fn init() -> Result<(), Error> {
+    update_reg(UCSR0B, 1 << RXCIE0, false);
+
+    free(|cs| {
+        RX_PENDING.borrow(cs).set(0);
+        RX_OVERRUNS.borrow(cs).set(0);
+        BREAK_FLAG.borrow(cs).set(false);
+
+        unsafe {
+            (*core::ptr::addr_of_mut!(BASINO_RX_QUEUE)).init(
+                core::ptr::addr_of_mut!(BASINO_RX_QUEUE_DATA) as *mut u8,
+                RX_QUEUE_LEN,
+            )
+        }
+        .map_err(|_| Error::new(ErrorKind::QueueError))
+    })?;
+
+    update_reg(UCSR0B, 1 << RXCIE0, true);
+
+    Ok(())
+}
+
+/// Stop interrupt driven receiving
+///
+/// Bytes already in the queue can still be read.
+pub fn deinit() {
+    update_reg(UCSR0B, 1 << RXCIE0, false);
+}
+
+/// Store a received byte
+///
+/// This is the body of the RX complete interrupt.  hardware_overrun
+/// is true if the USART dropped a byte before this one.  The break key
+/// sets the break flag instead of being stored.
+pub(crate) fn receive(byte: u8, hardware_overrun: bool) {
+    free(|cs| {
+        let overruns = RX_OVERRUNS.borrow(cs);
+
+        if hardware_overrun {
+            overruns.set(overruns.get().saturating_add(1));
+        }
+
+        if byte == BREAK_KEY {
+            BREAK_FLAG.borrow(cs).set(true);
+            return;
+        }
+
+        let res = unsafe { (*core::ptr::addr_of_mut!(BASINO_RX_QUEUE)).put(byte) };
+        match res {
+            Ok(()) => {
+                let pending = RX_PENDING.borrow(cs);
+                pending.set(pending.get() + 1);
+            }
+            Err(_) => overruns.set(overruns.get().saturating_add(1)),
+        }
+    });
+}
+
+/// Read a byte without waiting
+///
+/// Returns None if no byte has been received.
+pub fn try_read() -> Option<u8> {
+    free(|cs| {
+        let byte = unsafe { (*core::ptr::addr_of_mut!(BASINO_RX_QUEUE)).get() }.ok();
+        if byte.is_some() {
+            let pending = RX_PENDING.borrow(cs);
+            pending.set(pending.get() - 1);
+        }
+        byte
+    })
+}
+
+/// Read a byte, waiting until one is received
+///
+/// The CPU sleeps in Idle mode while it waits.  Interrupts must be
+/// enabled, otherwise this never returns.
+pub fn read() -> u8 {
+    loop {
+        if let Some(byte) = try_read() {
+            return byte;
+        }
+        unsafe { basino_sleep_idle() };
+    }
+}
+
+/// Read a byte, waiting up to timeout milliseconds
+///
+/// This needs the system tick to be running.  Returns None if no byte
+/// was received in time.
+pub fn read_timeout(timeout: u32) -> Option<u8> {
+    let start = tick::millis();
+
+    loop {
+        if let Some(byte) = try_read() {
+            return Some(byte);
+        }
+        if tick::elapsed(tick::millis(), start) >= timeout {
+            return None;
+        }
+        unsafe { basino_sleep_idle() };
+    }
+}
+
+/// Get the number of bytes waiting in the receive queue
+pub fn rx_pending() -> u8 {
+    free(|cs| RX_PENDING.borrow(cs).get())
+}
+
+/// Get the number of received bytes that were dropped
+pub fn overruns() -> u16 {
+    free(|cs| RX_OVERRUNS.borrow(cs).get())
+}
+
+/// Get the number of dropped bytes and reset the count to zero
+pub fn take_overruns() -> u16 {
+    free(|cs| RX_OVERRUNS.borrow(cs).replace(0))
+}
+
+/// Test if the break key has been received
+pub fn break_requested() -> bool {
+    free(|cs| BREAK_FLAG.borrow(cs).get())
+}
+
+/// Test if the break key has been received and clear the flag
+///
+/// This is what the interpreter calls between statements.
+pub fn take_break() -> bool {
+    free(|cs| BREAK_FLAG.borrow(cs).replace(false))
+}
+
+/// Clear the break flag
+///
+/// Call this before running or continuing a program, so a break key
+/// pressed while it was stopped doesn't stop it again.
+pub fn clear_break() {
+    free(|cs| BREAK_FLAG.borrow(cs).set(false));
+}
+
+/// Start interrupt driven transmitting
+///
+/// This clears the transmit queue.  Bytes waiting in it are dropped,
+/// call flush first to send them.
+pub fn init_tx() -> Result<(), Error> {
+    update_reg(UCSR0B, 1 << UDRIE0, false);
+
+    free(|cs| {
+        TX_PENDING.borrow(cs).set(0);
+
+        unsafe {
+            (*core::ptr::addr_of_mut!(BASINO_TX_QUEUE)).init(
+                core::ptr::addr_of_mut!(BASINO_TX_QUEUE_DATA) as *mut u8,
+                TX_QUEUE_LEN,
+            )
+        }
+        .map_err(|_| Error::new(ErrorKind::QueueError))
+    })
+}
+
+/// Test if interrupts are enabled
+fn interrupts_enabled() -> bool {
+    (read_reg(SREG) & (1 << SREG_I)) != 0
+}
+
+/// Send the next byte in the transmit queue
+///
+/// This is the body of the data register empty interrupt.  The
+/// interrupt is disabled when the queue is empty.
+fn transmit_next() {
+    free(|cs| {
+        let res = unsafe { (*core::ptr::addr_of_mut!(BASINO_TX_QUEUE)).get() };
+
+        match res {
+            Ok(byte) => {
+                write_reg(UDR0, byte);
+                let pending = TX_PENDING.borrow(cs);
+                pending.set(pending.get() - 1);
+            }
+            Err(_) => update_reg(UCSR0B, 1 << UDRIE0, false),
+        }
+    });
+}
+
+/// Put a byte in the transmit queue
+///
+/// If the queue is full this waits for space.  With interrupts enabled
+/// the CPU sleeps until the interrupt sends a byte.  With interrupts
+/// disabled, for example inside interrupt::free, it sends a byte from
+/// the queue itself so it can't deadlock.
+pub fn write_byte(byte: u8) {
+    loop {
+        let queued = free(|cs| {
+            let res = unsafe { (*core::ptr::addr_of_mut!(BASINO_TX_QUEUE)).put(byte) };
+            if res.is_ok() {
+                let pending = TX_PENDING.borrow(cs);
+                pending.set(pending.get() + 1);
+            }
+            res.is_ok()
+        });
+
+        if queued {
+            update_reg(UCSR0B, 1 << UDRIE0, true);
+            return;
+        }
+
+        if interrupts_enabled() {
+            unsafe { basino_sleep_idle() };
+        } else {
+            while (read_reg(UCSR0A) & (1 << UDRE0)) == 0 {}
+            transmit_next();
+        }
+    }
+}
+
+/// Get the number of bytes waiting in the transmit queue
+pub fn tx_pending() -> u8 {
+    free(|cs| TX_PENDING.borrow(cs).get())
+}
+
+/// Wait until the transmit queue is empty
+///
+/// The last byte may still be shifting out of the USART when this
+/// returns.
+pub fn flush() {
+    while tx_pending() != 0 {
+        if interrupts_enabled() {
+            unsafe { basino_sleep_idle() };
+        } else {
+            while (read_reg(UCSR0A) & (1 << UDRE0)) == 0 {}
+            transmit_next();
+        }
+    }
+}
+
+/// A writer that sends through the transmit queue
+///
+/// init_tx must be called before writing.
+///
+/// # Examples
+///
+/// ```
+/// use rust_basino::serial::{init_tx, SerialWriter};
+///
+/// init_tx().unwrap();
+/// let mut writer = SerialWriter;
+/// ufmt::uwriteln!(writer, "Hello\r").unwrap();
+/// ```
+pub struct SerialWriter;
+
+impl uWrite for SerialWriter {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        for byte in s.bytes() {
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn USART_UDRE() {
+    transmit_next();
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn USART_RX() {
+    // DOR0 is only valid until UDR0 is read
+    let hardware_overrun = (read_reg(UCSR0A) & (1 << DOR0)) != 0;
+    let byte = read_reg(UDR0);
+
+    receive(byte, hardware_overrun);
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+pub mod tests {
+    use crate::{
+        basino_sleep_idle,
+        serial::{
+            break_requested, clear_break, flush, init, init_tx, overruns, receive, rx_pending,
+            take_break, take_overruns, try_read, tx_pending, SerialWriter, BREAK_KEY, RX_QUEUE_LEN,
+            TX_QUEUE_LEN,
+        },
+        testing::{run_suite, TestCase, TestReporter},
+        tests::write_test_result,
+        tick,
+    };
+
+    /// The number of bytes the host sends for the stress test
+    pub const STRESS_LEN: u16 = 1024;
+
+    /// The line the host waits for before sending the stress test data
+    pub const STRESS_READY_MARKER: &str = "SERIAL RX STRESS READY";
+
+    /// How many bytes the stress test lets into the receive queue
+    /// before it reads them
+    ///
+    /// The queue holds RX_QUEUE_LEN - 1 bytes, this leaves a little
+    /// room for bytes that arrive while it starts reading.
+    pub const STRESS_BURST: u8 = RX_QUEUE_LEN as u8 - 4;
+
+    /// How long the stress test waits for the host, in milliseconds
+    const STRESS_TIMEOUT: u32 = 2000;
+
+    /// Get the stress test byte after byte
+    ///
+    /// The bytes count up and wrap at 256, skipping BREAK_KEY.  receive
+    /// doesn't queue BREAK_KEY, it sets the break flag instead.
+    pub fn next_stress_byte(byte: u8) -> u8 {
+        match byte.wrapping_add(1) {
+            BREAK_KEY => BREAK_KEY + 1,
+            next => next,
+        }
+    }
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("init_works", test_serial_init_works),
+        TestCase::new("empty_read_fails", test_serial_empty_read_fails),
+        TestCase::new("receive_and_read_works", test_serial_receive_and_read_works),
+        TestCase::new(
+            "full_queue_counts_overruns",
+            test_serial_full_queue_counts_overruns,
+        ),
+        TestCase::new(
+            "hardware_overrun_counted",
+            test_serial_hardware_overrun_counted,
+        ),
+        TestCase::new("break_key_sets_flag", test_serial_break_key_sets_flag),
+        TestCase::new("clear_break_works", test_serial_clear_break_works),
+        TestCase::new("tx_init_works", test_serial_tx_init_works),
+        TestCase::new("writer_works", test_serial_writer_works),
+        TestCase::new(
+            "writer_interrupts_disabled_works",
+            test_serial_writer_interrupts_disabled_works,
+        ),
+        TestCase::new("rx_stress", test_serial_rx_stress),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "serial", TEST_CASES);
+    }
+
+    /// Test that init works
+    pub fn test_serial_init_works(reporter: &mut dyn TestReporter) {
+        write_test_result(reporter, init().is_ok(), "init should work");
+    }
+
+    /// Test that reading with nothing received fails
+    pub fn test_serial_empty_read_fails(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+
+        write_test_result(
+            reporter,
+            try_read().is_none(),
+            "try_read with nothing received should return nothing",
+        );
+    }
+
+    /// Test that received bytes are read back in order
+    pub fn test_serial_receive_and_read_works(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+
+        receive(b'O', false);
+        receive(b'K', false);
+        write_test_result(
+            reporter,
+            rx_pending() == 2,
+            "rx_pending should count the received bytes",
+        );
+
+        let first = try_read();
+        let second = try_read();
+        write_test_result(
+            reporter,
+            first == Some(b'O') && second == Some(b'K'),
+            "received bytes should be read in order",
+        );
+        write_test_result(
+            reporter,
+            rx_pending() == 0,
+            "rx_pending should be zero after reading everything",
+        );
+        write_test_result(reporter, overruns() == 0, "there should be no overruns");
+    }
+
+    /// Test that bytes received into a full queue are counted
+    pub fn test_serial_full_queue_counts_overruns(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+
+        for i in 0..RX_QUEUE_LEN * 2 {
+            receive(i as u8, false);
+        }
+
+        write_test_result(
+            reporter,
+            overruns() > 0,
+            "receiving into a full queue should count overruns",
+        );
+
+        // The bytes that fit are still read in order
+        let mut ok = true;
+        let mut expected = 0;
+        while let Some(byte) = try_read() {
+            if byte != expected {
+                ok = false;
+            }
+            expected += 1;
+        }
+        write_test_result(reporter, ok, "the bytes that fit should be kept in order");
+
+        let count = take_overruns();
+        write_test_result(
+            reporter,
+            count > 0 && overruns() == 0,
+            "take_overruns should reset the count",
+        );
+    }
+
+    /// Test that a hardware overrun is counted
+    pub fn test_serial_hardware_overrun_counted(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+
+        receive(0, true);
+
+        write_test_result(
+            reporter,
+            overruns() == 1,
+            "a hardware overrun should be counted",
+        );
+        write_test_result(
+            reporter,
+            try_read() == Some(0),
+            "the byte after a hardware overrun should be kept",
+        );
+    }
+
+    /// Test that the break key sets the break flag and isn't queued
+    pub fn test_serial_break_key_sets_flag(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+
+        write_test_result(
+            reporter,
+            !break_requested(),
+            "the break flag should start cleared",
+        );
+
+        receive(b'A', false);
+        receive(BREAK_KEY, false);
+        receive(b'B', false);
+
+        write_test_result(
+            reporter,
+            break_requested(),
+            "the break key should set the break flag",
+        );
+
+        let first = try_read();
+        let second = try_read();
+        write_test_result(
+            reporter,
+            first == Some(b'A') && second == Some(b'B') && try_read().is_none(),
+            "the break key shouldn't be queued",
+        );
+
+        write_test_result(reporter, take_break(), "take_break should return the flag");
+        write_test_result(
+            reporter,
+            !break_requested(),
+            "take_break should clear the flag",
+        );
+    }
+
+    /// Test that clear_break clears the break flag
+    pub fn test_serial_clear_break_works(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+
+        receive(BREAK_KEY, false);
+        clear_break();
+
+        write_test_result(
+            reporter,
+            !break_requested(),
+            "clear_break should clear the flag",
+        );
+    }
+
+    /// Test that init_tx works
+    pub fn test_serial_tx_init_works(reporter: &mut dyn TestReporter) {
+        write_test_result(reporter, init_tx().is_ok(), "init_tx should work");
+        write_test_result(
+            reporter,
+            tx_pending() == 0,
+            "the transmit queue should be empty",
+        );
+    }
+
+    /// Test that output through SerialWriter is sent by the interrupt
+    pub fn test_serial_writer_works(reporter: &mut dyn TestReporter) {
+        init_tx().unwrap();
+        unsafe { avr_device::interrupt::enable() };
+
+        let mut serial_writer = SerialWriter;
+        let res = ufmt::uwriteln!(serial_writer, "SerialWriter output line\r");
+        let queued = tx_pending();
+        flush();
+        let pending = tx_pending();
+
+        avr_device::interrupt::disable();
+
+        write_test_result(reporter, res.is_ok(), "writing to SerialWriter should work");
+        write_test_result(
+            reporter,
+            queued > 0,
+            "writing shouldn't wait for the bytes to be sent",
+        );
+        write_test_result(
+            reporter,
+            pending == 0,
+            "flush should wait for the queue to empty",
+        );
+    }
+
+    /// Test that writing more than the queue holds with interrupts
+    /// disabled doesn't deadlock
+    pub fn test_serial_writer_interrupts_disabled_works(reporter: &mut dyn TestReporter) {
+        init_tx().unwrap();
+
+        let mut serial_writer = SerialWriter;
+        for _ in 0..TX_QUEUE_LEN * 2 {
+            ufmt::uwrite!(serial_writer, ".").unwrap();
+        }
+        ufmt::uwriteln!(serial_writer, "\r").unwrap();
+        flush();
+
+        write_test_result(
+            reporter,
+            tx_pending() == 0,
+            "writing with interrupts disabled should send everything",
+        );
+    }
+
+    /// Test receiving data from the host while the main program reads
+    /// it
+    ///
+    /// After the ready marker the host sends STRESS_LEN bytes counting
+    /// up from zero and wrapping at 256, skipping BREAK_KEY.  The main
+    /// program reads in bursts, like an interpreter busy running a
+    /// statement: it leaves the bytes in the queue until STRESS_BURST
+    /// of them are waiting, then reads them all.  The queue has to
+    /// come close to full without losing or reordering bytes.
+    ///
+    /// simavr can't send input, basino-runner runs this suite under
+    /// QEMU.
+    pub fn test_serial_rx_stress(reporter: &mut dyn TestReporter) {
+        init().unwrap();
+        tick::init();
+        unsafe { avr_device::interrupt::enable() };
+
+        reporter.diagnostic(STRESS_READY_MARKER);
+
+        let mut received: u16 = 0;
+        let mut expected: u8 = 0;
+        let mut in_order = true;
+        let mut most_pending: u8 = 0;
+
+        while received < STRESS_LEN {
+            // The end of the data can be shorter than a burst
+            let burst = core::cmp::min(STRESS_BURST as u16, STRESS_LEN - received) as u8;
+            let start = tick::millis();
+            while rx_pending() < burst && tick::elapsed(tick::millis(), start) < STRESS_TIMEOUT {
+                unsafe { basino_sleep_idle() };
+            }
+
+            let pending = rx_pending();
+            if pending == 0 {
+                break;
+            }
+            most_pending = core::cmp::max(most_pending, pending);
+
+            while let Some(byte) = try_read() {
+                if byte != expected {
+                    in_order = false;
+                }
+                expected = next_stress_byte(expected);
+                received += 1;
+            }
+        }
+
+        avr_device::interrupt::disable();
+
+        write_test_result(
+            reporter,
+            received == STRESS_LEN,
+            "all the stress test bytes should be received",
+        );
+        write_test_result(
+            reporter,
+            most_pending >= STRESS_BURST,
+            "the receive queue should come close to full in the stress test",
+        );
+        write_test_result(
+            reporter,
+            in_order,
+            "the stress test bytes should be in order",
+        );
+        write_test_result(
+            reporter,
+            overruns() == 0,
+            "there should be no overruns in the stress test",
+        );
+        write_test_result(
+            reporter,
+            !take_break(),
+            "the stress test shouldn't set the break flag",
+        );
+    }
+}
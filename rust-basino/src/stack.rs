@@ -24,15 +24,7 @@
 
 use core::marker::PhantomData;
 
-use arduino_hal::{
-    hal::port::{PD0, PD1},
-    pac::USART0,
-    port::{
-        mode::{Input, Output},
-        Pin,
-    },
-    Usart,
-};
+use ufmt::uWrite;
 
 use crate::{
     basino_get_basino_stack_bottom, basino_get_basino_stack_top,
@@ -48,7 +40,7 @@ pub trait StackImpl<'a> {
     /// # Examples
     ///
     /// ```
-    /// use crate::{ArrayHandle, stack::{Stack, StackImpl}};
+    /// use rust_basino::{stack::StackImpl, ArrayHandle, Stack};
     ///
     /// let mut arr: [u8; 4] = [0; 4];
     /// let stack_handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
@@ -65,13 +57,13 @@ pub trait StackImpl<'a> {
     /// # Examples
     ///
     /// ```
-    /// use crate::{ArrayHandle, stack::{Stack, StackImpl}};
+    /// use rust_basino::{stack::StackImpl, ArrayHandle, Stack};
     ///
     /// let mut arr: [u8; 4] = [0; 4];
     /// let stack_handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
-    /// let mut stack = Stack::new(stack_handle.ptr, stack_handle.len).unwrap();
+    /// let mut stack = Stack::new(&stack_handle).unwrap();
     ///
-    /// stack.push(3);
+    /// stack.push(3).unwrap();
     /// let pop_res = stack.pop();
     /// assert!(pop_res.is_ok());
     /// ```
@@ -82,13 +74,13 @@ pub trait StackImpl<'a> {
     /// # Examples
     ///
     /// ```
-    /// use crate::{ArrayHandle, stack::{Stack, StackImpl}};
+    /// use rust_basino::{stack::StackImpl, ArrayHandle, Stack};
     ///
     /// let mut arr: [u8; 4] = [0; 4];
     /// let stack_handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
-    /// let mut stack = Stack::new(stack_handle.ptr, stack_handle.len).unwrap();
+    /// let mut stack = Stack::new(&stack_handle).unwrap();
     ///
-    /// let push_res = stack.push(3).unwrap();
+    /// let push_res = stack.push(3);
     /// assert!(push_res.is_ok());
     /// ```
     fn push(&mut self, value: u8) -> Result<(), Error>;
@@ -100,11 +92,11 @@ pub trait StackImpl<'a> {
     /// # Examples
     ///
     /// ```
-    /// use crate::{ArrayHandle, stack::{Stack, StackImpl}};
+    /// use rust_basino::{stack::StackImpl, ArrayHandle, Stack};
     ///
     /// let mut arr: [u8; 4] = [0; 4];
     /// let stack_handle = ArrayHandle::new(arr.as_mut_ptr(), arr.len());
-    /// let mut stack = Stack::new(stack_handle.ptr, stack_handle.len).unwrap();
+    /// let mut stack = Stack::new(&stack_handle).unwrap();
     /// let size = stack.size();
     ///
     /// assert_eq!(size, 3);
@@ -182,62 +174,99 @@ impl<'a> StackImpl<'a> for Stack<'a> {
 }
 
 impl<'a> Stack<'a> {
+    /// Get the number of elements on the stack
+    ///
+    /// The stack grows down from the top sentinel, so this is the
+    /// distance from the current top to the sentinel.  A debugger can
+    /// use it to show the GOSUB or FOR stack depth.
+    pub fn depth(&mut self) -> u16 {
+        unsafe {
+            basino_get_basino_stack_top_sentinel(core::ptr::addr_of_mut!(*self)) as u16
+                - basino_get_basino_stack_top(core::ptr::addr_of_mut!(*self)) as u16
+        }
+    }
+
     /// Print a bunch of debugging information about the stack
     #[allow(dead_code)]
-    fn debug_print(&mut self, writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
+    fn debug_print<W: uWrite>(&mut self, writer: &mut W) {
         let res = unsafe { basino_get_basino_stack_bottom(core::ptr::addr_of_mut!(*self)) };
 
-        ufmt::uwriteln!(writer, "basino_get_basino_stack_bottom result: {:?}\r", res).unwrap();
+        let _ = ufmt::uwriteln!(writer, "basino_get_basino_stack_bottom result: {:?}\r", res);
 
         let res = unsafe { basino_get_basino_stack_top(core::ptr::addr_of_mut!(*self)) };
-        ufmt::uwriteln!(writer, "basino_get_basino_stack_top result: {:?}\r", res).unwrap();
+        let _ = ufmt::uwriteln!(writer, "basino_get_basino_stack_top result: {:?}\r", res);
 
         let res = unsafe { basino_get_basino_stack_top_sentinel(core::ptr::addr_of_mut!(*self)) };
-        ufmt::uwriteln!(
+        let _ = ufmt::uwriteln!(
             writer,
             "basino_get_basino_stack_top_sentinel result: {:?}\r",
             res
-        )
-        .unwrap();
+        );
 
         let res = self.size();
-        ufmt::uwriteln!(writer, "size result: {}\r", res).unwrap();
+        let _ = ufmt::uwriteln!(writer, "size result: {}\r", res);
     }
 }
 
 /// A tests module
 /// This doesn't use the standard Rust testing framework.  Instead it's a normal
 /// public module that can be called by other systems.
+///
+/// On AVR the tests use the buffers in lib.rs, other targets use an
+/// array of the same size on the stack.
 pub mod tests {
     use core::marker::PhantomData;
 
     use crate::{
         basino_get_basino_stack_bottom, basino_get_basino_stack_top,
         basino_get_basino_stack_top_sentinel, basino_stack_init, basino_stack_pop,
-        basino_stack_push, error::Error, error::ErrorKind, stack::StackImpl,
-        tests::write_test_result, ArrayHandle, Stack, BASINO_STACK_BUFFER,
+        basino_stack_push,
+        error::Error,
+        error::ErrorKind,
+        stack::StackImpl,
+        testing::{
+            report_mismatch, run_random_sequences, run_suite, BufferWriter, Mismatch, RandomOp,
+            Rng, TestCase, TestReporter, RANDOM_OPS,
+        },
+        tests::write_test_result,
+        ArrayHandle, Stack,
     };
 
+    #[cfg(target_arch = "avr")]
+    use crate::BASINO_STACK_BUFFER;
+    #[cfg(target_arch = "avr")]
     use avr_device::interrupt::free;
 
-    use arduino_hal::{
-        hal::port::{PD0, PD1},
-        pac::USART0,
-        port::{
-            mode::{Input, Output},
-            Pin,
-        },
-        Usart,
-    };
+    /// Run f with the stack buffer
+    ///
+    /// On AVR this is BASINO_STACK_BUFFER in lib.rs, borrowed in a
+    /// critical section.
+    #[cfg(target_arch = "avr")]
+    fn with_stack_buffer<R>(f: impl FnOnce(&mut [u8]) -> R) -> R {
+        free(|cs| f(&mut BASINO_STACK_BUFFER.borrow(cs).borrow_mut()[..]))
+    }
 
-    /// Run all the tests in this module
-    pub fn run_tests(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
-        test_stack_new_works(writer);
-        test_stack_push_works(writer);
-        test_stack_empty_pop_fails(writer);
-        test_stack_push_full_stack_fails(writer);
-        test_stack_push_full_stack_pop_full_works(writer);
+    /// Run f with the stack buffer
+    ///
+    /// Other targets don't have BASINO_STACK_BUFFER, this uses an
+    /// array of the same size.
+    #[cfg(not(target_arch = "avr"))]
+    fn with_stack_buffer<R>(f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let mut buffer = [0u8; 33];
+        f(&mut buffer)
+    }
 
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("new_works", test_stack_new_works),
+        TestCase::new("push_works", test_stack_push_works),
+        TestCase::new("empty_pop_fails", test_stack_empty_pop_fails),
+        TestCase::new("push_full_stack_fails", test_stack_push_full_stack_fails),
+        TestCase::new(
+            "push_full_stack_pop_full_works",
+            test_stack_push_full_stack_pop_full_works,
+        ),
+        TestCase::new("depth_works", test_stack_depth_works),
         // Technically, on embedded devices with limited memory, even
         // address zero can be used.  Especially on Harvard devices
         // with this setup: where interrupts may be in program code,
@@ -250,26 +279,46 @@ pub mod tests {
         //
         // A branch called optional-type may be added that includes an
         // Option type with Some and None values to handle this.
-        test_stack_init_null_stack_fails(writer);
-        test_stack_init_bottom_gt_top_fails(writer);
-        test_stack_init_bottom_eq_top_fails(writer);
-
+        TestCase::new("init_null_stack_fails", test_stack_init_null_stack_fails),
+        TestCase::new(
+            "init_bottom_gt_top_fails",
+            test_stack_init_bottom_gt_top_fails,
+        ),
+        TestCase::new(
+            "init_bottom_eq_top_fails",
+            test_stack_init_bottom_eq_top_fails,
+        ),
         // Test that top being one above bottom works
-        test_stack_init_bottom_one_lt_top_works(writer);
-
+        TestCase::new(
+            "init_bottom_one_lt_top_works",
+            test_stack_init_bottom_one_lt_top_works,
+        ),
         // Test that subtraction and branch algorithms are correct
-        test_stack_init_upper_byte_compare_works(writer);
-
+        TestCase::new(
+            "init_upper_byte_compare_works",
+            test_stack_init_upper_byte_compare_works,
+        ),
         // Test the raw error codes
-        test_stack_basino_stack_push_null_stack_fails(writer);
-        test_stack_basino_stack_pop_null_stack_fails(writer);
+        TestCase::new(
+            "basino_stack_push_null_stack_fails",
+            test_stack_basino_stack_push_null_stack_fails,
+        ),
+        TestCase::new(
+            "basino_stack_pop_null_stack_fails",
+            test_stack_basino_stack_pop_null_stack_fails,
+        ),
+        // Compare random push and pop sequences with a reference
+        TestCase::new("random_matches_model", test_stack_random_matches_model),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "stack", TEST_CASES);
     }
 
     /// Test that initializing the stack works
-    pub fn test_stack_new_works(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_new_works(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let mut stack = Stack::new(&ah).unwrap();
@@ -288,25 +337,25 @@ pub mod tests {
             let top_sentinel = unsafe { basino_get_basino_stack_top_sentinel(&stack) };
 
             write_test_result(
-                writer,
+                reporter,
                 bottom == expected_bottom,
                 "initialized stack should have the correct bottom",
             );
 
             write_test_result(
-                writer,
+                reporter,
                 top == expected_top,
                 "initialized stack should have the correct top",
             );
 
             write_test_result(
-                writer,
+                reporter,
                 top_sentinel == expected_top_sentinel,
                 "initialized stack should have the correct top sentinel",
             );
 
             write_test_result(
-                writer,
+                reporter,
                 size == expected_size as u16,
                 "initialized stack should have the correct size",
             );
@@ -314,34 +363,58 @@ pub mod tests {
     }
 
     /// Test that pushing a value on the stack works
-    pub fn test_stack_push_works(writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_push_works(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let mut stack = Stack::new(&ah).unwrap();
 
             let res = stack.push(5);
-            write_test_result(writer, res.is_ok(), "should be able to push value");
+            write_test_result(reporter, res.is_ok(), "should be able to push value");
 
             let res = stack.pop();
-            write_test_result(writer, res.is_ok(), "should be able to pop value");
+            write_test_result(reporter, res.is_ok(), "should be able to pop value");
             write_test_result(
-                writer,
+                reporter,
                 res.unwrap() == 5,
                 "popped value should equal pushed value",
             );
         });
     }
 
-    /// Test that popping a value from an empty stack fails
-    pub fn test_stack_empty_pop_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
+    /// Test that the depth follows pushes and pops
+    pub fn test_stack_depth_works(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
+            let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
+            let mut stack = Stack::new(&ah).unwrap();
+
+            write_test_result(
+                reporter,
+                stack.depth() == 0,
+                "a new stack should have a depth of zero",
+            );
+
+            stack.push(1).unwrap();
+            stack.push(2).unwrap();
+            write_test_result(
+                reporter,
+                stack.depth() == 2,
+                "depth should count pushed values",
+            );
+
+            stack.pop().unwrap();
+            write_test_result(
+                reporter,
+                stack.depth() == 1,
+                "depth should go down after a pop",
+            );
+        });
+    }
+
+    /// Test that popping a value from an empty stack fails
+    pub fn test_stack_empty_pop_fails(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let mut stack = Stack::new(&ah).unwrap();
@@ -349,7 +422,7 @@ pub mod tests {
             let res = stack.pop();
 
             write_test_result(
-                writer,
+                reporter,
                 res.is_err(),
                 "shouldn't be able to pop value from empty stack",
             );
@@ -357,12 +430,8 @@ pub mod tests {
     }
 
     /// Test that pushing into a full stack fails
-    pub fn test_stack_push_full_stack_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_push_full_stack_fails(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let mut stack = Stack::new(&ah).unwrap();
@@ -372,7 +441,7 @@ pub mod tests {
                 let res = stack.push(n);
 
                 write_test_result(
-                    writer,
+                    reporter,
                     res.is_ok(),
                     "should be able to push value to fill stack",
                 );
@@ -382,11 +451,11 @@ pub mod tests {
 
             match res {
                 Ok(_) => {
-                    write_test_result(writer, false, "push on full stack should fail");
+                    write_test_result(reporter, false, "push on full stack should fail");
                 }
                 Err(e) => {
                     write_test_result(
-                        writer,
+                        reporter,
                         e == Error::new(ErrorKind::StackOverflow),
                         "push on full stack should fail",
                     );
@@ -396,12 +465,8 @@ pub mod tests {
     }
 
     /// Test that creating a full stack and popping all the values succeeds
-    pub fn test_stack_push_full_stack_pop_full_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_push_full_stack_pop_full_works(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let mut stack = Stack::new(&ah).unwrap();
@@ -415,11 +480,11 @@ pub mod tests {
 
             match res {
                 Ok(_) => {
-                    write_test_result(writer, false, "push on full stack should fail");
+                    write_test_result(reporter, false, "push on full stack should fail");
                 }
                 Err(e) => {
                     write_test_result(
-                        writer,
+                        reporter,
                         e == Error::new(ErrorKind::StackOverflow),
                         "push on full stack should fail",
                     );
@@ -432,13 +497,13 @@ pub mod tests {
                 let res = stack.pop();
 
                 write_test_result(
-                    writer,
+                    reporter,
                     res.is_ok(),
                     "should be able to pop value from filled stack",
                 );
 
                 write_test_result(
-                    writer,
+                    reporter,
                     res.unwrap() == n as u8,
                     "popped value from filled stack should equal pushed value",
                 );
@@ -449,14 +514,14 @@ pub mod tests {
             match res {
                 Ok(_) => {
                     write_test_result(
-                        writer,
+                        reporter,
                         false,
                         "shouldn't be able to pop value from empty stack",
                     );
                 }
                 Err(e) => {
                     write_test_result(
-                        writer,
+                        reporter,
                         e == Error::new(ErrorKind::StackUnderflow),
                         "shouldn't be able to pop value from empty stack",
                     );
@@ -466,12 +531,8 @@ pub mod tests {
     }
 
     /// Test that init with a NULL stack pointer fails
-    pub fn test_stack_init_null_stack_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_init_null_stack_fails(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let stack_bottom_ptr = ah.ptr;
@@ -487,17 +548,17 @@ pub mod tests {
                 )
             };
 
-            write_test_result(writer, res == 1, "init should fail with null stack pointer");
+            write_test_result(
+                reporter,
+                res == 1,
+                "init should fail with null stack pointer",
+            );
         });
     }
 
     /// Test that init with bottom greater than top fails
-    pub fn test_stack_init_bottom_gt_top_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_init_bottom_gt_top_fails(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let stack_bottom_ptr = ah.ptr;
@@ -520,7 +581,7 @@ pub mod tests {
             };
 
             write_test_result(
-                writer,
+                reporter,
                 res == 2,
                 "init should fail with bottom greater than top",
             );
@@ -528,12 +589,8 @@ pub mod tests {
     }
 
     /// Test that init with bottom equal to top fails
-    pub fn test_stack_init_bottom_eq_top_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_init_bottom_eq_top_fails(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let stack_bottom_ptr = ah.ptr;
@@ -556,7 +613,7 @@ pub mod tests {
             };
 
             write_test_result(
-                writer,
+                reporter,
                 res == 2,
                 "init should fail with bottom equal to top",
             );
@@ -564,12 +621,8 @@ pub mod tests {
     }
 
     /// Test that init with bottom equal to top fails
-    pub fn test_stack_init_bottom_one_lt_top_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
-        free(|cs| {
-            let mut stack_handle = BASINO_STACK_BUFFER.borrow(cs).borrow_mut();
-
+    pub fn test_stack_init_bottom_one_lt_top_works(reporter: &mut dyn TestReporter) {
+        with_stack_buffer(|stack_handle| {
             let ah = ArrayHandle::new(stack_handle.as_mut_ptr(), stack_handle.len());
 
             let stack_bottom_ptr = ah.ptr;
@@ -592,7 +645,7 @@ pub mod tests {
             };
 
             write_test_result(
-                writer,
+                reporter,
                 res == 0,
                 "init should work with bottom one less than top",
             );
@@ -601,9 +654,7 @@ pub mod tests {
 
     /// Test that subtraction code and dealing with multi-byte values
     /// works
-    pub fn test_stack_init_upper_byte_compare_works(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_stack_init_upper_byte_compare_works(reporter: &mut dyn TestReporter) {
         let mut stack = Stack {
             data: core::ptr::null_mut::<u8>(),
             top_sentinel: core::ptr::null_mut::<u8>(),
@@ -621,7 +672,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             res == 0,
             "init should work with lower byte larger and upper byte larger",
         );
@@ -629,9 +680,7 @@ pub mod tests {
 
     /// Test that popping with a NULL stack pointer fails
     /// Tests the raw error code
-    pub fn test_stack_basino_stack_pop_null_stack_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_stack_basino_stack_pop_null_stack_fails(reporter: &mut dyn TestReporter) {
         let mut result: u8 = 0;
         let _res = unsafe {
             basino_stack_pop(
@@ -641,7 +690,7 @@ pub mod tests {
         };
 
         write_test_result(
-            writer,
+            reporter,
             result == 1,
             "pop should fail with null stack pointer",
         );
@@ -649,11 +698,183 @@ pub mod tests {
 
     /// Test that push with a NULL stack pointer fails
     /// Tests the raw error code
-    pub fn test_stack_basino_stack_push_null_stack_fails(
-        writer: &mut Usart<USART0, Pin<Input, PD0>, Pin<Output, PD1>>,
-    ) {
+    pub fn test_stack_basino_stack_push_null_stack_fails(reporter: &mut dyn TestReporter) {
         let res = unsafe { basino_stack_push(core::ptr::null_mut::<u16>() as *mut Stack, 5) };
 
-        write_test_result(writer, res == 1, "push should fail with null stack pointer");
+        write_test_result(
+            reporter,
+            res == 1,
+            "push should fail with null stack pointer",
+        );
+    }
+
+    /// The largest stack array a randomized sequence uses
+    ///
+    /// The stack holds one less than the array length, the last byte
+    /// is the top sentinel.
+    const RANDOM_MAX_LEN: usize = 12;
+
+    /// An operation in a randomized sequence
+    #[derive(Clone, Copy)]
+    enum Op {
+        Push(u8),
+        Pop,
+    }
+
+    impl RandomOp for Op {
+        fn write(&self, line: &mut BufferWriter) {
+            let _ = match *self {
+                Op::Push(value) => ufmt::uwrite!(line, "push {}", value),
+                Op::Pop => ufmt::uwrite!(line, "pop"),
+            };
+        }
+    }
+
+    /// A reference stack that works like a Vec with a fixed capacity
+    struct Model {
+        data: [u8; RANDOM_MAX_LEN],
+        capacity: usize,
+        len: usize,
+    }
+
+    impl Model {
+        fn push(&mut self, value: u8) -> Result<(), Error> {
+            if self.len == self.capacity {
+                return Err(Error::new(ErrorKind::StackOverflow));
+            }
+            self.data[self.len] = value;
+            self.len += 1;
+            Ok(())
+        }
+
+        fn pop(&mut self) -> Result<u8, Error> {
+            if self.len == 0 {
+                return Err(Error::new(ErrorKind::StackUnderflow));
+            }
+            self.len -= 1;
+            Ok(self.data[self.len])
+        }
+    }
+
+    /// Run a sequence on a stack built from an array of length len
+    /// and on the model
+    ///
+    /// After every step the bottom and top sentinel must not move, and
+    /// the top must be the sentinel minus the number of items.
+    fn check_sequence(len: usize, ops: &[Op]) -> Option<Mismatch> {
+        let mut buffer = [0u8; RANDOM_MAX_LEN];
+        let bottom = buffer.as_mut_ptr();
+        let handle = ArrayHandle::new(bottom, len);
+        let sentinel = (bottom as usize + len - 1) as *const u8;
+
+        let mut stack = match Stack::new(&handle) {
+            Ok(stack) => stack,
+            // An array of length one has the top on the bottom
+            Err(e) if len == 1 && e == Error::new(ErrorKind::InvalidArguments) => return None,
+            Err(_) => {
+                return Some(Mismatch {
+                    step: 0,
+                    what: "init failed",
+                })
+            }
+        };
+        if len == 1 {
+            return Some(Mismatch {
+                step: 0,
+                what: "init should fail",
+            });
+        }
+
+        let mut model = Model {
+            data: [0; RANDOM_MAX_LEN],
+            capacity: len - 1,
+            len: 0,
+        };
+
+        for (step, op) in ops.iter().enumerate() {
+            let ok = match *op {
+                Op::Push(value) => stack.push(value) == model.push(value),
+                Op::Pop => stack.pop() == model.pop(),
+            };
+            let top = unsafe { basino_get_basino_stack_top(&stack) };
+
+            let what = if !ok {
+                "result differs"
+            } else if unsafe { basino_get_basino_stack_bottom(&stack) } != bottom as *const u8 {
+                "bottom moved"
+            } else if unsafe { basino_get_basino_stack_top_sentinel(&stack) } != sentinel {
+                "top sentinel moved"
+            } else if top < bottom as *const u8 || top > sentinel {
+                "top out of bounds"
+            } else if top as usize != sentinel as usize - model.len {
+                "top differs"
+            } else {
+                continue;
+            };
+
+            return Some(Mismatch { step, what });
+        }
+
+        None
+    }
+
+    /// Generate the array length and operations for a seed
+    ///
+    /// Lengths one and two, the empty stack and the top one above
+    /// the bottom, come up often.
+    fn generate(seed: u32, ops: &mut [Op; RANDOM_OPS]) -> usize {
+        let mut rng = Rng::new(seed);
+        let len = match rng.below(4) {
+            0 => 1 + rng.below(2) as usize,
+            _ => 1 + rng.below(RANDOM_MAX_LEN as u32) as usize,
+        };
+        // Vary the mix so some sequences overflow and some underflow
+        let push_percent = rng.below(100);
+
+        for op in ops.iter_mut() {
+            *op = if rng.below(100) < push_percent {
+                Op::Push(rng.next_u32() as u8)
+            } else {
+                Op::Pop
+            };
+        }
+
+        len
+    }
+
+    /// Run one seeded sequence, report it and return false if it fails
+    pub fn run_random_sequence(reporter: &mut dyn TestReporter, seed: u32) -> bool {
+        let mut ops = [Op::Pop; RANDOM_OPS];
+        let len = generate(seed, &mut ops);
+
+        match check_sequence(len, &ops) {
+            Some(mismatch) => {
+                report_mismatch(
+                    reporter,
+                    "stack",
+                    seed,
+                    len,
+                    &ops[..=mismatch.step],
+                    &mismatch,
+                );
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Test seeded random sequences of push and pop against a
+    /// reference model
+    ///
+    /// The first failing sequence is printed with its seed and the
+    /// failing step, run_random_sequence replays it.
+    pub fn test_stack_random_matches_model(reporter: &mut dyn TestReporter) {
+        let ok = run_random_sequences(reporter, run_random_sequence);
+
+        write_test_result(
+            reporter,
+            ok,
+            "random stack sequences should match the reference model",
+        );
     }
 }
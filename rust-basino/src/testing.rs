@@ -0,0 +1,499 @@
+//! A small on-device test framework
+//!
+//! The tests in this crate don't use the standard Rust testing
+//! framework, they run on the device and print their results.  This
+//! module keeps the tests separate from where the results go.  A test
+//! takes a TestReporter and reports each check to it.  Reporter is a
+//! TestReporter that writes to any ufmt uWrite, the Usart on an Uno or
+//! a buffer in a test.
+//!
+//! Each test suite registers its tests as a list of named TestCases
+//! and runs them with run_suite.  The reporter counts the passed,
+//! failed and skipped checks and prints a summary at the end.
+//!
+//! There are two output formats.  The plain format prints a SUCCESS,
+//! FAILURE or SKIPPED line for each check, the format the CI scripts
+//! and basino-runner look for.  The TAP format prints Test Anything
+//! Protocol lines for other test harnesses.
+#![warn(missing_docs)]
+
+use core::convert::Infallible;
+use ufmt::uWrite;
+
+/// The line printed after the last test
+///
+/// basino-runner stops the simulator when it sees this line.
+pub const END_OF_TESTS_MARKER: &str = "END OF TESTS";
+
+/// Something that test results can be reported to
+pub trait TestReporter {
+    /// Start a test case
+    ///
+    /// suite is the name of the test suite, name is the name of the
+    /// case in the suite.
+    fn start_case(&mut self, suite: &'static str, name: &'static str);
+
+    /// Report a check, result is true if the check passed
+    fn check(&mut self, result: bool, msg: &str);
+
+    /// Report a check that couldn't be run
+    fn skip(&mut self, msg: &str);
+
+    /// Print a line that isn't a test result
+    ///
+    /// The host can use these lines to synchronize with the tests, for
+    /// example to send data.
+    fn diagnostic(&mut self, msg: &str);
+
+    /// Print the summary and the end-of-tests marker
+    fn finish(&mut self);
+
+    /// The number of checks that passed
+    fn passed(&self) -> u16;
+
+    /// The number of checks that failed
+    fn failed(&self) -> u16;
+
+    /// The number of checks that were skipped
+    fn skipped(&self) -> u16;
+}
+
+/// The output formats a Reporter can use
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    /// SUCCESS, FAILURE and SKIPPED lines
+    Plain,
+    /// Test Anything Protocol version 13
+    Tap,
+}
+
+impl Default for Format {
+    /// Tap if the crate was built with the tap feature, Plain otherwise
+    fn default() -> Self {
+        if cfg!(feature = "tap") {
+            Format::Tap
+        } else {
+            Format::Plain
+        }
+    }
+}
+
+/// A TestReporter that writes to a uWrite
+pub struct Reporter<'a, W: uWrite> {
+    writer: &'a mut W,
+    format: Format,
+    suite: &'static str,
+    name: &'static str,
+    passed: u16,
+    failed: u16,
+    skipped: u16,
+}
+
+impl<'a, W: uWrite> Reporter<'a, W> {
+    /// Create a new Reporter writing to writer in the given format
+    pub fn new(writer: &'a mut W, format: Format) -> Self {
+        let mut reporter = Reporter {
+            writer,
+            format,
+            suite: "",
+            name: "",
+            passed: 0,
+            failed: 0,
+            skipped: 0,
+        };
+
+        if format == Format::Tap {
+            reporter.line("TAP version 13");
+        }
+
+        reporter
+    }
+
+    /// The number of checks reported so far
+    fn count(&self) -> u16 {
+        self.passed + self.failed + self.skipped
+    }
+
+    /// Write a line
+    ///
+    /// Write errors are ignored, there is nowhere else to report them.
+    fn line(&mut self, s: &str) {
+        let _ = ufmt::uwrite!(self.writer, "{}\r\n", s);
+    }
+
+    /// Write a result line
+    fn result(&mut self, status: &str, msg: &str, directive: &str) {
+        let _ = match self.format {
+            Format::Plain => ufmt::uwrite!(
+                self.writer,
+                "{} {}::{}: {}\r\n",
+                status,
+                self.suite,
+                self.name,
+                msg
+            ),
+            Format::Tap => ufmt::uwrite!(
+                self.writer,
+                "{} {} - {}::{}: {}{}\r\n",
+                status,
+                self.count(),
+                self.suite,
+                self.name,
+                msg,
+                directive
+            ),
+        };
+    }
+}
+
+impl<'a, W: uWrite> TestReporter for Reporter<'a, W> {
+    fn start_case(&mut self, suite: &'static str, name: &'static str) {
+        self.suite = suite;
+        self.name = name;
+    }
+
+    fn check(&mut self, result: bool, msg: &str) {
+        if result {
+            self.passed += 1;
+        } else {
+            self.failed += 1;
+        }
+
+        let status = match (self.format, result) {
+            (Format::Plain, true) => "SUCCESS",
+            (Format::Plain, false) => "FAILURE",
+            (Format::Tap, true) => "ok",
+            (Format::Tap, false) => "not ok",
+        };
+        self.result(status, msg, "");
+    }
+
+    fn skip(&mut self, msg: &str) {
+        self.skipped += 1;
+
+        match self.format {
+            Format::Plain => self.result("SKIPPED", msg, ""),
+            Format::Tap => self.result("ok", msg, " # SKIP"),
+        }
+    }
+
+    fn diagnostic(&mut self, msg: &str) {
+        let _ = match self.format {
+            Format::Plain => ufmt::uwrite!(self.writer, "{}\r\n", msg),
+            Format::Tap => ufmt::uwrite!(self.writer, "# {}\r\n", msg),
+        };
+    }
+
+    fn finish(&mut self) {
+        if self.format == Format::Tap {
+            let _ = ufmt::uwrite!(self.writer, "1..{}\r\n", self.count());
+        }
+
+        let _ = match self.format {
+            Format::Plain => ufmt::uwrite!(self.writer, "SUMMARY"),
+            Format::Tap => ufmt::uwrite!(self.writer, "# SUMMARY"),
+        };
+        let _ = ufmt::uwrite!(
+            self.writer,
+            " {} passed, {} failed, {} skipped\r\n",
+            self.passed,
+            self.failed,
+            self.skipped
+        );
+
+        self.diagnostic(END_OF_TESTS_MARKER);
+    }
+
+    fn passed(&self) -> u16 {
+        self.passed
+    }
+
+    fn failed(&self) -> u16 {
+        self.failed
+    }
+
+    fn skipped(&self) -> u16 {
+        self.skipped
+    }
+}
+
+/// A named test
+pub struct TestCase {
+    /// The name of the test in its suite
+    pub name: &'static str,
+    /// The test function
+    pub run: fn(&mut dyn TestReporter),
+}
+
+impl TestCase {
+    /// Create a new TestCase
+    pub const fn new(name: &'static str, run: fn(&mut dyn TestReporter)) -> Self {
+        TestCase { name, run }
+    }
+}
+
+/// A writer that keeps the output in a buffer so tests can check it
+pub struct BufferWriter {
+    /// The output
+    pub buffer: [u8; 128],
+    /// The number of bytes written
+    pub len: usize,
+}
+
+impl BufferWriter {
+    /// Create an empty BufferWriter
+    pub fn new() -> Self {
+        BufferWriter {
+            buffer: [0; 128],
+            len: 0,
+        }
+    }
+
+    /// Get the output as a string
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+impl Default for BufferWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl uWrite for BufferWriter {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        for byte in s.bytes() {
+            if self.len < self.buffer.len() {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The seed randomized tests start from
+///
+/// Each randomized sequence uses this plus its sequence number as its
+/// seed.  Change it to explore different sequences.
+pub const RANDOM_SEED: u32 = 0x2545_F491;
+
+/// A small pseudo-random number generator for randomized tests
+///
+/// This is Marsaglia's 32-bit xorshift.  The same seed always gives
+/// the same numbers, so a failing sequence can be replayed from the
+/// seed the test prints.
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// Create a new Rng
+    ///
+    /// xorshift can't start from zero, a zero seed is replaced with a
+    /// fixed non-zero one.
+    pub fn new(seed: u32) -> Self {
+        Rng {
+            state: if seed == 0 { RANDOM_SEED } else { seed },
+        }
+    }
+
+    /// Get the next 32-bit number
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Get a number from zero up to but not including n
+    ///
+    /// n must not be zero.  The modulo bias doesn't matter for tests.
+    pub fn below(&mut self, n: u32) -> u32 {
+        self.next_u32() % n
+    }
+}
+
+/// The number of seeded sequences a randomized model test runs
+pub const RANDOM_SEQUENCES: u32 = 2000;
+
+/// The number of operations in each randomized sequence
+pub const RANDOM_OPS: usize = 40;
+
+/// An operation in a randomized sequence
+///
+/// A failing sequence is printed one operation at a time.
+pub trait RandomOp {
+    /// Write the operation, for example "push 5"
+    fn write(&self, line: &mut BufferWriter);
+}
+
+/// Where a randomized sequence first differed from its model
+pub struct Mismatch {
+    /// The index of the operation that differed
+    pub step: usize,
+    /// What differed
+    pub what: &'static str,
+}
+
+/// Print a failing randomized sequence so it can be replayed
+///
+/// name is the structure under test, len is the length of its array.
+/// The operations are printed eight to a line.
+pub fn report_mismatch<O: RandomOp>(
+    reporter: &mut dyn TestReporter,
+    name: &str,
+    seed: u32,
+    len: usize,
+    ops: &[O],
+    mismatch: &Mismatch,
+) {
+    let mut line = BufferWriter::new();
+    let _ = ufmt::uwrite!(
+        line,
+        "{} model mismatch: seed {}, array length {}, step {}: {}",
+        name,
+        seed,
+        len,
+        mismatch.step,
+        mismatch.what
+    );
+    reporter.diagnostic(line.as_str());
+
+    for chunk in ops.chunks(8) {
+        let mut line = BufferWriter::new();
+        let _ = ufmt::uwrite!(line, " ");
+        for op in chunk {
+            let _ = ufmt::uwrite!(line, " ");
+            op.write(&mut line);
+            let _ = ufmt::uwrite!(line, ",");
+        }
+        reporter.diagnostic(line.as_str());
+    }
+}
+
+/// Run RANDOM_SEQUENCES seeded sequences
+///
+/// run gets the seed of each sequence and returns false if it failed,
+/// after reporting it.  This stops at the first failure and returns
+/// true if every sequence passed.
+pub fn run_random_sequences(
+    reporter: &mut dyn TestReporter,
+    run: fn(&mut dyn TestReporter, u32) -> bool,
+) -> bool {
+    (0..RANDOM_SEQUENCES).all(|i| run(reporter, RANDOM_SEED.wrapping_add(i)))
+}
+
+/// Run every case in a test suite
+pub fn run_suite(reporter: &mut dyn TestReporter, suite: &'static str, cases: &[TestCase]) {
+    for case in cases {
+        reporter.start_case(suite, case.name);
+        (case.run)(reporter);
+    }
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+///
+/// These check the framework itself by reporting to a second
+/// Reporter that writes to a buffer.
+pub mod tests {
+    use crate::{
+        testing::{run_suite, BufferWriter, Format, Reporter, TestCase, TestReporter},
+        tests::write_test_result,
+    };
+
+    /// A test case that passes one check
+    fn passing(reporter: &mut dyn TestReporter) {
+        reporter.check(true, "passes");
+    }
+
+    /// A test case that fails one check
+    fn failing(reporter: &mut dyn TestReporter) {
+        reporter.check(false, "fails");
+    }
+
+    /// A test case that skips one check
+    fn skipping(reporter: &mut dyn TestReporter) {
+        reporter.skip("skips");
+    }
+
+    /// The cases run by the framework tests
+    const INNER_CASES: &[TestCase] = &[
+        TestCase::new("passing", passing),
+        TestCase::new("failing", failing),
+        TestCase::new("skipping", skipping),
+    ];
+
+    /// The test cases in this module
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("counts", test_testing_counts),
+        TestCase::new("plain_format", test_testing_plain_format),
+        TestCase::new("tap_format", test_testing_tap_format),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "testing", TEST_CASES);
+    }
+
+    /// Test that the reporter counts passed, failed and skipped checks
+    pub fn test_testing_counts(reporter: &mut dyn TestReporter) {
+        let mut output = BufferWriter::new();
+        let mut inner = Reporter::new(&mut output, Format::Plain);
+
+        run_suite(&mut inner, "inner", INNER_CASES);
+
+        write_test_result(
+            reporter,
+            inner.passed() == 1 && inner.failed() == 1 && inner.skipped() == 1,
+            "the reporter should count each kind of check",
+        );
+    }
+
+    /// Test the plain output format
+    pub fn test_testing_plain_format(reporter: &mut dyn TestReporter) {
+        let mut output = BufferWriter::new();
+        let mut inner = Reporter::new(&mut output, Format::Plain);
+
+        run_suite(&mut inner, "inner", &INNER_CASES[..1]);
+        inner.finish();
+
+        write_test_result(
+            reporter,
+            output
+                .as_str()
+                .starts_with("SUCCESS inner::passing: passes\r\nSUMMARY 1 passed"),
+            "plain output should have a SUCCESS line and a summary",
+        );
+        write_test_result(
+            reporter,
+            output.as_str().ends_with("END OF TESTS\r\n"),
+            "plain output should end with the end-of-tests marker",
+        );
+    }
+
+    /// Test the TAP output format
+    pub fn test_testing_tap_format(reporter: &mut dyn TestReporter) {
+        let mut output = BufferWriter::new();
+        let mut inner = Reporter::new(&mut output, Format::Tap);
+
+        run_suite(&mut inner, "inner", &INNER_CASES[1..]);
+        inner.finish();
+
+        write_test_result(
+            reporter,
+            output.as_str().starts_with(
+                "TAP version 13\r\nnot ok 1 - inner::failing: fails\r\n\
+                 ok 2 - inner::skipping: skips # SKIP\r\n1..2\r\n",
+            ),
+            "TAP output should have numbered results and a plan",
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! The system tick
+//!
+//! These are the building blocks for the BASIC DELAY statement and
+//! the TICKS() function.  Timer0 counts time since tick::init was
+//! called, millis and micros read it.
+//!
+//! Timer0 runs in fast PWM mode with a prescaler of 64, the same
+//! setup pwm_write uses, so PWM on D5 and D6 keeps working.  At 16 MHz
+//! the timer counts every 4 us and overflows every 1.024 ms.  The
+//! overflow interrupt adds one millisecond and keeps the extra 24 us
+//! as a fraction, the same way the Arduino core does, so millis
+//! doesn't drift.
+//!
+//! The counts are 32 bits and wrap: millis after about 49 days and
+//! micros after about 71 minutes.  Use elapsed and is_after to compare
+//! times, they work across the wrap as long as the times are less
+//! than half the range apart.
+#![warn(missing_docs)]
+
+use crate::{
+    basino_sleep_idle, events,
+    hardware::{read_reg, update_reg, write_reg, TCCR0A, TCCR0B},
+};
+
+use avr_device::interrupt::{free, Mutex};
+use core::cell::Cell;
+
+/// The CPU clock frequency of the Arduino Uno
+pub const CPU_FREQUENCY_HZ: u32 = 16_000_000;
+
+/// The Timer0 prescaler
+const PRESCALER: u32 = 64;
+
+/// The microseconds per Timer0 count
+pub const MICROS_PER_COUNT: u32 = PRESCALER * 1_000_000 / CPU_FREQUENCY_HZ;
+
+/// The microseconds per Timer0 overflow
+pub const MICROS_PER_OVERFLOW: u32 = MICROS_PER_COUNT * 256;
+
+// The whole milliseconds and the fraction per overflow
+// The fraction is kept in units of 8 us so it fits in a byte
+const MILLIS_INC: u32 = MICROS_PER_OVERFLOW / 1000;
+const FRACT_INC: u8 = ((MICROS_PER_OVERFLOW % 1000) >> 3) as u8;
+const FRACT_MAX: u8 = (1000 >> 3) as u8;
+
+// Timer/Counter register addresses
+const TIFR0: usize = 0x35;
+const TCNT0: usize = 0x46;
+const TIMSK0: usize = 0x6E;
+
+// TIMSK0 and TIFR0 bits
+const TOIE0: u8 = 0;
+const TOV0: u8 = 0;
+
+/// Milliseconds since the tick was started
+static TICK_MILLIS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// The part of a millisecond left over from the overflows, in 8 us
+/// units
+static TICK_FRACT: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// The number of Timer0 overflows since the tick was started
+static TICK_OVERFLOWS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Start the system tick
+///
+/// This sets up Timer0 and enables its overflow interrupt.  It can be
+/// called more than once, the counts aren't reset.  Interrupts have to
+/// be enabled for the tick to advance.
+pub fn init() {
+    update_reg(TCCR0A, 0x03, true);
+    write_reg(TCCR0B, 0x03);
+    update_reg(TIMSK0, 1 << TOIE0, true);
+}
+
+/// Get the number of milliseconds since the tick was started
+pub fn millis() -> u32 {
+    free(|cs| TICK_MILLIS.borrow(cs).get())
+}
+
+/// Get the number of microseconds since the tick was started
+///
+/// The resolution is MICROS_PER_COUNT, 4 us at 16 MHz.
+pub fn micros() -> u32 {
+    free(|cs| {
+        let mut overflows = TICK_OVERFLOWS.borrow(cs).get();
+        let count = read_reg(TCNT0);
+
+        // The timer may have overflowed after interrupts were
+        // disabled.  If it did and the count hasn't reached the top
+        // yet, the overflow hasn't been counted.
+        if (read_reg(TIFR0) & (1 << TOV0)) != 0 && count < 255 {
+            overflows = overflows.wrapping_add(1);
+        }
+
+        (overflows << 8)
+            .wrapping_add(count as u32)
+            .wrapping_mul(MICROS_PER_COUNT)
+    })
+}
+
+/// Get the value for the BASIC TICKS() function
+///
+/// This is the milliseconds since the tick was started.
+pub fn ticks() -> u32 {
+    millis()
+}
+
+/// Get the time elapsed since an earlier time
+///
+/// since and the result are in the same unit, milliseconds or
+/// microseconds.  This works across a wrap of the count.
+pub fn elapsed(now: u32, since: u32) -> u32 {
+    now.wrapping_sub(since)
+}
+
+/// Test if time a is after time b
+///
+/// This works across a wrap of the count as long as the times are
+/// less than half the range apart.
+pub fn is_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Wait for a number of milliseconds
+///
+/// The CPU sleeps in Idle mode between timer interrupts instead of
+/// busy-waiting.  Other interrupts wake it early, it goes back to
+/// sleep until the time is up.
+///
+/// Interrupts must be enabled and the tick started, otherwise this
+/// never returns.
+pub fn delay_ms(ms: u32) {
+    let start = millis();
+
+    while elapsed(millis(), start) < ms {
+        unsafe { basino_sleep_idle() };
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn TIMER0_OVF() {
+    free(|cs| {
+        let mut m = TICK_MILLIS.borrow(cs).get().wrapping_add(MILLIS_INC);
+        let mut f = TICK_FRACT.borrow(cs).get() + FRACT_INC;
+        if f >= FRACT_MAX {
+            f -= FRACT_MAX;
+            m = m.wrapping_add(1);
+        }
+
+        TICK_MILLIS.borrow(cs).set(m);
+        TICK_FRACT.borrow(cs).set(f);
+
+        let overflows = TICK_OVERFLOWS.borrow(cs);
+        overflows.set(overflows.get().wrapping_add(1));
+    });
+
+    events::timer_overflow();
+}
+
+/// A tests module
+/// This doesn't use the standard Rust testing framework.  Instead it's a normal
+/// public module that can be called by other systems.
+pub mod tests {
+    use crate::{
+        testing::{run_suite, TestCase, TestReporter},
+        tests::write_test_result,
+        tick::{delay_ms, elapsed, init, is_after, micros, millis, ticks},
+    };
+
+    /// The test cases that don't need the timer running
+    pub const TEST_CASES: &[TestCase] = &[
+        TestCase::new("elapsed_wraps_works", test_tick_elapsed_wraps_works),
+        TestCase::new("is_after_wraps_works", test_tick_is_after_wraps_works),
+    ];
+
+    /// The test cases that need the timer running and interrupts enabled
+    pub const TIMER_TEST_CASES: &[TestCase] = &[
+        TestCase::new("millis_accuracy", test_tick_millis_accuracy),
+        TestCase::new("micros_accuracy", test_tick_micros_accuracy),
+        TestCase::new("micros_increases", test_tick_micros_increases),
+        TestCase::new("delay_ms_accuracy", test_tick_delay_ms_accuracy),
+        TestCase::new("ticks_works", test_tick_ticks_works),
+    ];
+
+    /// Run all the tests in this module
+    pub fn run_tests(reporter: &mut dyn TestReporter) {
+        run_suite(reporter, "tick", TEST_CASES);
+
+        init();
+        unsafe { avr_device::interrupt::enable() };
+
+        run_suite(reporter, "tick", TIMER_TEST_CASES);
+
+        avr_device::interrupt::disable();
+    }
+
+    /// Test that elapsed works when the count wraps
+    pub fn test_tick_elapsed_wraps_works(reporter: &mut dyn TestReporter) {
+        write_test_result(
+            reporter,
+            elapsed(5, u32::MAX - 4) == 10,
+            "elapsed should work across a wrap",
+        );
+    }
+
+    /// Test that is_after works when the count wraps
+    pub fn test_tick_is_after_wraps_works(reporter: &mut dyn TestReporter) {
+        write_test_result(
+            reporter,
+            is_after(5, u32::MAX - 4),
+            "a time after the wrap should be after",
+        );
+        write_test_result(
+            reporter,
+            !is_after(u32::MAX - 4, 5),
+            "a time before the wrap should not be after",
+        );
+        write_test_result(reporter, !is_after(7, 7), "a time is not after itself");
+    }
+
+    /// Test millis against the busy-wait delay, which counts cycles
+    pub fn test_tick_millis_accuracy(reporter: &mut dyn TestReporter) {
+        let start = millis();
+        arduino_hal::delay_ms(50);
+        let e = elapsed(millis(), start);
+
+        write_test_result(
+            reporter,
+            (49..=51).contains(&e),
+            "millis should count 50 ms in a 50 ms delay",
+        );
+    }
+
+    /// Test micros against the busy-wait delay, which counts cycles
+    pub fn test_tick_micros_accuracy(reporter: &mut dyn TestReporter) {
+        let start = micros();
+        arduino_hal::delay_ms(10);
+        let e = elapsed(micros(), start);
+
+        // Allow for the interrupt handlers and the delay loop overhead
+        write_test_result(
+            reporter,
+            (9_900..=10_200).contains(&e),
+            "micros should count 10000 us in a 10 ms delay",
+        );
+    }
+
+    /// Test that micros never goes backwards, including across timer
+    /// overflows
+    pub fn test_tick_micros_increases(reporter: &mut dyn TestReporter) {
+        let mut last = micros();
+        let mut ok = true;
+
+        for _ in 0..2000 {
+            let now = micros();
+            if is_after(last, now) {
+                ok = false;
+            }
+            last = now;
+        }
+
+        write_test_result(reporter, ok, "micros should never go backwards");
+    }
+
+    /// Test that delay_ms sleeps for the requested time
+    pub fn test_tick_delay_ms_accuracy(reporter: &mut dyn TestReporter) {
+        let start = micros();
+        delay_ms(20);
+        let e = elapsed(micros(), start);
+
+        // The delay can end up to one tick late, it starts partway
+        // through a millisecond
+        write_test_result(
+            reporter,
+            (19_000..=21_100).contains(&e),
+            "delay_ms should wait for the requested time",
+        );
+    }
+
+    /// Test that ticks counts milliseconds
+    pub fn test_tick_ticks_works(reporter: &mut dyn TestReporter) {
+        let start = ticks();
+        delay_ms(5);
+
+        write_test_result(
+            reporter,
+            elapsed(ticks(), start) >= 5,
+            "ticks should advance during a delay",
+        );
+    }
+}